@@ -1,4 +1,4 @@
-use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use criterion::{criterion_group, criterion_main, Criterion};
 use bytevm::prelude::*;
 
 // Your function to benchmark
@@ -41,7 +41,7 @@ fn test_function_call() {
 }
 
 fn bench_fibonacci(c: &mut Criterion) {
-    c.bench_function("function_call", |b| b.iter(|| test_function_call()));
+    c.bench_function("function_call", |b| b.iter(test_function_call));
 }
 
 criterion_group!(benches, bench_fibonacci);
@@ -37,7 +37,7 @@ fn loopy(input: i64) -> i64 {
     
     let mut vm = Vm::default();
     vm.load_program(program.build());
-    vm.run(None, None).unwrap().result.unwrap().into()
+    vm.run(None, None).unwrap().result.unwrap().try_into().unwrap()
 }
 
 fn bench_fibonacci(c: &mut Criterion) {
@@ -0,0 +1,599 @@
+use crate::builder::{BlockEncoder, FunctionBuilder, ProgramBuilder};
+use crate::error::{AssemblyError, AssemblyErrorKind};
+use crate::program::{CallTarget, GlobalTarget, Import, Instruction, Program, SymbolEntry};
+use crate::variant::Variant;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+/// Assembles source in the textual assembly language into a program, using the program builder.
+///
+/// Functions start with `.function <name> <arity>` and end with `.end`. The arity is the number of required
+/// arguments, optionally followed by `..<max>` for optional arguments and `+` for a rest parameter.
+/// Inside a function, `.local` and `.upvalue` declare named variables, `<label>:` marks a jump target and every
+/// other line is an instruction. Functions and globals are referenced by name or by `#<index>`.
+pub(crate) fn assemble(source: &str) -> Result<Program, AssemblyError> {
+
+    let mut assembler = Assembler::default();
+    let mut line_count = 0;
+
+    for (index, line) in source.lines().enumerate() {
+        line_count = index + 1;
+        let tokens = tokenize(line, line_count)?;
+        if !tokens.is_empty() {
+            assembler.line(Cursor { tokens, index: 0, line: line_count, end_column: line.chars().count() + 1 })?;
+        }
+    }
+
+    if let Some(function) = assembler.function {
+        return Err(AssemblyError {
+            kind: AssemblyErrorKind::UnterminatedFunction { name: function.name },
+            line: function.line,
+            column: function.column
+        });
+    }
+
+    // Errors found by the builder point at the end of the source
+    assembler.program.try_build().map_err(|error| AssemblyError {
+        kind: AssemblyErrorKind::Build { error },
+        line: line_count.max(1),
+        column: 1
+    })
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Word(String),
+    String(String),
+    Punctuation(char),
+}
+
+impl Token {
+
+    // Shows the token as it was written for error messages
+    fn text(&self) -> String {
+        match self {
+            Token::Word(word) => word.clone(),
+            Token::String(string) => format!("{:?}", string),
+            Token::Punctuation(character) => character.to_string()
+        }
+    }
+
+}
+
+fn is_word_character(character: char) -> bool {
+    character.is_alphanumeric() || matches!(character, '_' | '.' | '-' | '+' | '#' | '@')
+}
+
+// Splits a line into tokens with their columns, everything after a semicolon is a comment
+fn tokenize(line: &str, line_number: usize) -> Result<Vec<(Token, usize)>, AssemblyError> {
+
+    let characters = line.chars().collect::<Vec<_>>();
+    let mut tokens = Vec::new();
+    let mut index = 0;
+
+    let error = |kind: AssemblyErrorKind, index: usize| AssemblyError {
+        kind,
+        line: line_number,
+        column: index + 1
+    };
+
+    while index < characters.len() {
+        let start = index;
+        match characters[index] {
+            ';' => break,
+            character if character.is_whitespace() => index += 1,
+            '"' => {
+                let mut string = String::new();
+                index += 1;
+                loop {
+                    match characters.get(index) {
+                        None => return Err(error(AssemblyErrorKind::UnterminatedString, start)),
+                        Some('"') => break,
+                        Some('\\') => {
+                            let escaped = match characters.get(index + 1) {
+                                Some('n') => '\n',
+                                Some('t') => '\t',
+                                Some('r') => '\r',
+                                Some('0') => '\0',
+                                Some('"') => '"',
+                                Some('\\') => '\\',
+                                Some(character) => return Err(error(AssemblyErrorKind::InvalidEscape { character: *character }, index)),
+                                None => return Err(error(AssemblyErrorKind::UnterminatedString, start))
+                            };
+                            string.push(escaped);
+                            index += 2;
+                        },
+                        Some(character) => {
+                            string.push(*character);
+                            index += 1;
+                        }
+                    }
+                }
+                index += 1;
+                tokens.push((Token::String(string), start + 1));
+            },
+            '[' | ']' | '{' | '}' | ',' | ':' => {
+                tokens.push((Token::Punctuation(characters[index]), start + 1));
+                index += 1;
+            },
+            character if is_word_character(character) => {
+
+                // Qualified names keep their double colons, a single colon ends the word
+                let mut word = String::new();
+                while index < characters.len() {
+                    if is_word_character(characters[index]) {
+                        word.push(characters[index]);
+                        index += 1;
+                    } else if characters[index] == ':' && characters.get(index + 1) == Some(&':') {
+                        word.push_str("::");
+                        index += 2;
+                    } else {
+                        break;
+                    }
+                }
+                tokens.push((Token::Word(word), start + 1));
+            },
+            character => return Err(error(AssemblyErrorKind::UnexpectedCharacter { character }, index))
+        }
+    }
+
+    Ok(tokens)
+}
+
+// Tokens of a line that are taken one operand at a time
+struct Cursor {
+    tokens: Vec<(Token, usize)>,
+    index: usize,
+    line: usize,
+    end_column: usize,
+}
+
+impl Cursor {
+
+    fn error(&self, kind: AssemblyErrorKind, column: usize) -> AssemblyError {
+        AssemblyError {
+            kind,
+            line: self.line,
+            column
+        }
+    }
+
+    fn next(&mut self, expected: &'static str) -> Result<(Token, usize), AssemblyError> {
+        match self.tokens.get(self.index) {
+            Some(token) => {
+                self.index += 1;
+                Ok(token.clone())
+            },
+            None => Err(self.error(AssemblyErrorKind::MissingOperand { expected }, self.end_column))
+        }
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.index).map(|(token, _)| token)
+    }
+
+    fn word(&mut self, expected: &'static str) -> Result<(String, usize), AssemblyError> {
+        match self.next(expected)? {
+            (Token::Word(word), column) => Ok((word, column)),
+            (token, column) => Err(self.error(AssemblyErrorKind::InvalidOperand { expected, found: token.text() }, column))
+        }
+    }
+
+    fn number(&mut self, expected: &'static str) -> Result<usize, AssemblyError> {
+        let (word, column) = self.word(expected)?;
+        word.parse().map_err(|_| self.error(AssemblyErrorKind::InvalidOperand { expected, found: word }, column))
+    }
+
+    fn punctuation(&mut self, expected: &'static str, allowed: &[char]) -> Result<char, AssemblyError> {
+        match self.next(expected)? {
+            (Token::Punctuation(character), _) if allowed.contains(&character) => Ok(character),
+            (token, column) => Err(self.error(AssemblyErrorKind::InvalidOperand { expected, found: token.text() }, column))
+        }
+    }
+
+    fn end(&self) -> Result<(), AssemblyError> {
+        match self.tokens.get(self.index) {
+            Some((token, column)) => Err(self.error(AssemblyErrorKind::UnexpectedOperand { found: token.text() }, *column)),
+            None => Ok(())
+        }
+    }
+
+    // Function name or #index
+    fn call_target(&mut self) -> Result<CallTarget, AssemblyError> {
+        let (word, column) = self.word("function")?;
+        match word.strip_prefix('#') {
+            Some(index) => match index.parse() {
+                Ok(index) => Ok(CallTarget::Index(index)),
+                Err(_) => Err(self.error(AssemblyErrorKind::InvalidOperand { expected: "function index", found: word }, column))
+            },
+            None => Ok(CallTarget::Name(word))
+        }
+    }
+
+    // Global name or #slot
+    fn global_target(&mut self) -> Result<GlobalTarget, AssemblyError> {
+        let (word, column) = self.word("global")?;
+        match word.strip_prefix('#') {
+            Some(slot) => match slot.parse() {
+                Ok(slot) => Ok(GlobalTarget::Slot(slot)),
+                Err(_) => Err(self.error(AssemblyErrorKind::InvalidOperand { expected: "global slot", found: word }, column))
+            },
+            None => Ok(GlobalTarget::Name(word))
+        }
+    }
+
+    // Constants are null, booleans, numbers, "strings", @symbols, #indices, [arrays] and {dictionaries}
+    fn constant(&mut self) -> Result<Variant, AssemblyError> {
+        match self.next("constant")? {
+            (Token::String(string), _) => Ok(Variant::String(string)),
+            (Token::Word(word), column) => match word_constant(&word) {
+                Some(value) => Ok(value),
+                None => Err(self.error(AssemblyErrorKind::InvalidOperand { expected: "constant", found: word }, column))
+            },
+            (Token::Punctuation('['), _) => {
+                let mut items = Vec::new();
+                if self.peek() == Some(&Token::Punctuation(']')) {
+                    self.index += 1;
+                } else {
+                    loop {
+                        items.push(self.constant()?);
+                        if self.punctuation("',' or ']'", &[',', ']'])? == ']' {
+                            break;
+                        }
+                    }
+                }
+                Ok(Variant::Array(Rc::new(RefCell::new(items))))
+            },
+            (Token::Punctuation('{'), _) => {
+                let mut items = HashMap::new();
+                if self.peek() == Some(&Token::Punctuation('}')) {
+                    self.index += 1;
+                } else {
+                    loop {
+                        let key = self.constant()?;
+                        self.punctuation("':'", &[':'])?;
+                        items.insert(key, self.constant()?);
+                        if self.punctuation("',' or '}'", &[',', '}'])? == '}' {
+                            break;
+                        }
+                    }
+                }
+                Ok(Variant::Dictionary(Rc::new(RefCell::new(items))))
+            },
+            (token, column) => Err(self.error(AssemblyErrorKind::InvalidOperand { expected: "constant", found: token.text() }, column))
+        }
+    }
+
+}
+
+fn word_constant(word: &str) -> Option<Variant> {
+    match word {
+        "null" => return Some(Variant::Null),
+        "true" => return Some(Variant::Boolean(true)),
+        "false" => return Some(Variant::Boolean(false)),
+        _ => {}
+    }
+    if let Some(name) = word.strip_prefix('@') {
+        return (!name.is_empty()).then(|| Variant::SymbolReference(name.to_string()));
+    }
+    if let Some(index) = word.strip_prefix('#') {
+        return index.parse().ok().map(Variant::Index);
+    }
+    if let Ok(value) = word.parse::<i64>() {
+        return Some(Variant::Integer(value));
+    }
+    word.parse::<f64>().ok().map(Variant::Float)
+}
+
+// Function that is being assembled
+struct FunctionState {
+    name: String,
+    line: usize,
+    column: usize,
+    builder: FunctionBuilder,
+    encoder: BlockEncoder,
+    locals: Vec<String>,
+    upvalues: Vec<String>,
+    labels: HashSet<String>,
+    label_references: Vec<(String, usize, usize)>,
+}
+
+#[derive(Default)]
+struct Assembler {
+    program: ProgramBuilder,
+    function_names: HashSet<String>,
+    function: Option<FunctionState>,
+}
+
+impl Assembler {
+
+    fn line(&mut self, mut cursor: Cursor) -> Result<(), AssemblyError> {
+
+        let (first, column) = cursor.next("instruction")?;
+        let Token::Word(word) = first else {
+            return Err(cursor.error(AssemblyErrorKind::UnexpectedOperand { found: first.text() }, column));
+        };
+
+        if word.starts_with('.') {
+            return self.directive(&word, column, &mut cursor);
+        }
+
+        let Some(function) = &mut self.function else {
+            return Err(cursor.error(AssemblyErrorKind::OutsideFunction, column));
+        };
+
+        if cursor.peek() == Some(&Token::Punctuation(':')) {
+            cursor.index += 1;
+            cursor.end()?;
+            if !function.labels.insert(word.clone()) {
+                return Err(cursor.error(AssemblyErrorKind::DuplicateLabel { name: word }, column));
+            }
+            function.encoder.add_label(&word);
+            return Ok(());
+        }
+
+        function.instruction(&word, column, &mut cursor)?;
+        cursor.end()
+    }
+
+    fn directive(&mut self, name: &str, column: usize, cursor: &mut Cursor) -> Result<(), AssemblyError> {
+
+        let unexpected = |cursor: &Cursor| cursor.error(AssemblyErrorKind::UnexpectedDirective { name: name.to_string() }, column);
+
+        match (name, &mut self.function) {
+            (".module", None) => {
+                let (module, _) = cursor.word("module name")?;
+                self.program.module(&module);
+            },
+            (".export", None) => {
+                let (export, _) = cursor.word("function name")?;
+                self.program.export(&export);
+            },
+            (".import", None) => {
+                let (path, path_column) = cursor.word("import path")?;
+                if Import::parse(&path).is_none() {
+                    return Err(cursor.error(AssemblyErrorKind::InvalidOperand { expected: "import path", found: path }, path_column));
+                }
+                self.program.import(&path);
+            },
+            (".global", None) => {
+                let (global, _) = cursor.word("global name")?;
+                let initial_value = match cursor.peek() {
+                    Some(_) => Some(cursor.constant()?),
+                    None => None
+                };
+                self.program.declare_global(&global, initial_value);
+            },
+            (".native", None) => {
+                let (native, name_column) = cursor.word("function name")?;
+                if !self.function_names.insert(native.clone()) {
+                    return Err(cursor.error(AssemblyErrorKind::DuplicateFunction { name: native }, name_column));
+                }
+                let arity = cursor.number("arity")?;
+                self.program.add_symbol(native, SymbolEntry::NativeFunction { arity });
+            },
+            (".function", None) => {
+                let (function_name, name_column) = cursor.word("function name")?;
+                if !self.function_names.insert(function_name.clone()) {
+                    return Err(cursor.error(AssemblyErrorKind::DuplicateFunction { name: function_name }, name_column));
+                }
+                let mut builder = FunctionBuilder::default();
+                builder.name(&function_name);
+                parse_arity(cursor, &mut builder)?;
+                self.function = Some(FunctionState {
+                    name: function_name,
+                    line: cursor.line,
+                    column,
+                    builder,
+                    encoder: BlockEncoder::default(),
+                    locals: Vec::new(),
+                    upvalues: Vec::new(),
+                    labels: HashSet::new(),
+                    label_references: Vec::new()
+                });
+            },
+            (".returns", Some(function)) => {
+                let count = cursor.number("return count")?;
+                function.builder.returns(count);
+            },
+            (".local", Some(function)) => {
+                let (local, _) = cursor.word("local variable name")?;
+                if !function.locals.contains(&local) {
+                    function.encoder.declare_local(&local);
+                    function.locals.push(local);
+                }
+            },
+            (".upvalue", Some(function)) => {
+                let (upvalue, _) = cursor.word("upvalue name")?;
+                if !function.upvalues.contains(&upvalue) {
+                    function.encoder.declare_upvalue(&upvalue);
+                    function.upvalues.push(upvalue);
+                }
+            },
+            (".end", Some(_)) => {
+                cursor.end()?;
+                let Some(mut function) = self.function.take() else {
+                    unreachable!("The function is open");
+                };
+
+                // Labels may be used before they are defined, so they are checked at the end of the function
+                if let Some((label, line, column)) = function.label_references.iter().find(|(label, _, _)| !function.labels.contains(label)) {
+                    return Err(AssemblyError {
+                        kind: AssemblyErrorKind::MissingLabel { name: label.clone() },
+                        line: *line,
+                        column: *column
+                    });
+                }
+
+                self.program.add_function(function.builder.body(&mut function.encoder).build());
+                return Ok(());
+            },
+            (".module" | ".export" | ".import" | ".global" | ".native" | ".function" | ".returns" | ".local" | ".upvalue" | ".end", _) => {
+                return Err(unexpected(cursor));
+            },
+            _ => return Err(cursor.error(AssemblyErrorKind::UnknownDirective { name: name.to_string() }, column))
+        }
+
+        cursor.end()
+    }
+
+}
+
+// Arity is written as <required>, <required>..<max> for optional arguments and with a trailing + for a rest parameter
+fn parse_arity(cursor: &mut Cursor, builder: &mut FunctionBuilder) -> Result<(), AssemblyError> {
+    let (word, column) = cursor.word("arity")?;
+    let invalid = |cursor: &Cursor| cursor.error(AssemblyErrorKind::InvalidOperand { expected: "arity", found: word.clone() }, column);
+
+    let (counts, rest) = match word.strip_suffix('+') {
+        Some(counts) => (counts, true),
+        None => (word.as_str(), false)
+    };
+    let (required, max) = match counts.split_once("..") {
+        Some((required, max)) => (required, Some(max)),
+        None => (counts, None)
+    };
+
+    let required = required.parse::<usize>().map_err(|_| invalid(cursor))?;
+    builder.arity(required);
+    if let Some(max) = max {
+        let max = max.parse::<usize>().map_err(|_| invalid(cursor))?;
+        if max < required {
+            return Err(invalid(cursor));
+        }
+        builder.max_arity(max);
+    }
+    if rest {
+        builder.rest_parameter();
+    }
+    Ok(())
+}
+
+impl FunctionState {
+
+    fn local(&self, cursor: &mut Cursor) -> Result<String, AssemblyError> {
+        let (name, column) = cursor.word("local variable")?;
+        if !self.locals.contains(&name) {
+            return Err(cursor.error(AssemblyErrorKind::UndeclaredLocal { name }, column));
+        }
+        Ok(name)
+    }
+
+    fn upvalue(&self, cursor: &mut Cursor) -> Result<String, AssemblyError> {
+        let (name, column) = cursor.word("upvalue")?;
+        if !self.upvalues.contains(&name) {
+            return Err(cursor.error(AssemblyErrorKind::UndeclaredUpvalue { name }, column));
+        }
+        Ok(name)
+    }
+
+    fn label(&mut self, cursor: &mut Cursor) -> Result<String, AssemblyError> {
+        let (name, column) = cursor.word("label")?;
+        self.label_references.push((name.clone(), cursor.line, column));
+        Ok(name)
+    }
+
+    fn instruction(&mut self, opcode: &str, column: usize, cursor: &mut Cursor) -> Result<(), AssemblyError> {
+
+        let instruction = match opcode {
+
+            // Instructions with named operands are encoded by the block encoder
+            "set_local" => {
+                let name = self.local(cursor)?;
+                self.encoder.set_local(&name);
+                return Ok(());
+            },
+            "get_local" => {
+                let name = self.local(cursor)?;
+                self.encoder.get_local(&name);
+                return Ok(());
+            },
+            "set_upvalue" => {
+                let name = self.upvalue(cursor)?;
+                self.encoder.set_upvalue(&name);
+                return Ok(());
+            },
+            "get_upvalue" => {
+                let name = self.upvalue(cursor)?;
+                self.encoder.get_upvalue(&name);
+                return Ok(());
+            },
+            "jump" => {
+                let label = self.label(cursor)?;
+                self.encoder.jump(&label);
+                return Ok(());
+            },
+            "jump_if_false" => {
+                let label = self.label(cursor)?;
+                self.encoder.jump_if_false(&label);
+                return Ok(());
+            },
+            "try_begin" => {
+                let label = self.label(cursor)?;
+                self.encoder.try_begin(&label);
+                return Ok(());
+            },
+            "make_closure" => {
+                let target = cursor.call_target()?;
+                let mut captures = Vec::new();
+                while cursor.peek().is_some() {
+                    let name = self.local(cursor)?;
+                    captures.push(self.locals.iter().position(|local| *local == name).unwrap_or_default());
+                }
+                Instruction::MakeClosure(target, captures)
+            },
+
+            // Other instructions are appended as they are
+            "set_global" => Instruction::SetGlobal(cursor.global_target()?),
+            "get_global" => Instruction::GetGlobal(cursor.global_target()?),
+            "create_array" => Instruction::CreateArray(cursor.number("array size")?),
+            "get_array_item" => Instruction::GetArrayItem,
+            "set_array_item" => Instruction::SetArrayItem,
+            "get_array_length" => Instruction::GetArrayLength,
+            "create_dictionary" => Instruction::CreateDictionary(cursor.number("dictionary size")?),
+            "get_dictionary_item" => Instruction::GetDictionaryItem,
+            "set_dictionary_item" => Instruction::SetDictionaryItem,
+            "get_dictionary_keys" => Instruction::GetDictionaryKeys,
+            "call" => Instruction::FunctionCall(cursor.call_target()?),
+            "tail_call" => Instruction::TailCall(cursor.call_target()?),
+            "call_with_argument_count" => Instruction::CallWithArgumentCount(cursor.call_target()?, cursor.number("argument count")?),
+            "get_argument_count" => Instruction::GetArgumentCount,
+            "call_indirect" => Instruction::CallIndirect(cursor.number("argument count")?),
+            "return" => Instruction::Return,
+            "return_n" => Instruction::ReturnN(cursor.number("return count")?),
+            "end_function" => Instruction::EndFunction,
+            "push" => Instruction::Push(cursor.constant()?),
+            "pop" => Instruction::Pop,
+            "add" => Instruction::Add,
+            "sub" => Instruction::Sub,
+            "mul" => Instruction::Mul,
+            "div" => Instruction::Div,
+            "mod" => Instruction::Mod,
+            "pow" => Instruction::Pow,
+            "equal" => Instruction::Equal,
+            "less_than" => Instruction::LessThan,
+            "less_equal" => Instruction::LessEqual,
+            "greater_than" => Instruction::GreaterThan,
+            "greater_equal" => Instruction::GreaterEqual,
+            "not_equal" => Instruction::NotEqual,
+            "or" => Instruction::Or,
+            "and" => Instruction::And,
+            "not" => Instruction::Not,
+            "negate" => Instruction::Negate,
+            "make_coroutine" => Instruction::MakeCoroutine(cursor.number("argument count")?),
+            "resume" => Instruction::Resume,
+            "yield" => Instruction::Yield,
+            "is_coroutine_finished" => Instruction::IsCoroutineFinished,
+            "try_end" => Instruction::TryEnd,
+            "throw" => Instruction::Throw,
+            "print" => Instruction::Print,
+            "halt" => Instruction::Halt,
+            "panic" => Instruction::Panic,
+            _ => return Err(cursor.error(AssemblyErrorKind::UnknownOpcode { name: opcode.to_string() }, column))
+        };
+
+        self.encoder.add_instruction(instruction);
+        Ok(())
+    }
+
+}
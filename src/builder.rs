@@ -1,11 +1,18 @@
-use crate::program::{CallTarget, Function, Instruction, SymbolEntry};
+use crate::error::{VmError, VmErrorKind};
+use crate::program::{CallTarget, Function, Global, GlobalTarget, Import, Instruction, SymbolEntry};
 use crate::variant::Variant;
 use std::collections::HashMap;
 use crate::prelude::Program;
+use crate::verifier::{result_count, verify_stack_depth};
 
 #[derive(Clone, Debug, Default)]
 pub struct ProgramBuilder {
     program: Program,
+    verify_stack_depth: bool,
+    optimize_tail_calls: bool,
+
+    // Import paths that could not be parsed, reported when the program is built
+    invalid_imports: Vec<String>,
 }
 
 impl ProgramBuilder {
@@ -29,20 +36,121 @@ impl ProgramBuilder {
         self.program.symbol_table.insert(name, entry);
     }
 
-    pub fn build(mut self) -> Program {
+    /// Declares a global variable and returns its slot.
+    /// The global starts as null unless an initial value is given. Declaring it again replaces the initial value.
+    pub fn declare_global(&mut self, name: &str, initial_value: Option<Variant>) -> usize {
+        let initial_value = initial_value.unwrap_or(Variant::Null);
+        match self.program.globals.iter().position(|global| global.name == name) {
+            Some(slot) => {
+                self.program.globals[slot].initial_value = initial_value;
+                slot
+            },
+            None => {
+                self.program.globals.push(Global {
+                    name: name.to_string(),
+                    initial_value
+                });
+                self.program.globals.len() - 1
+            }
+        }
+    }
 
-        // Resolve function references with function index
-        for function in &mut self.program.functions {
-            for instruction in &mut function.instructions {
-                if let Instruction::FunctionCall(CallTarget::Name(name)) = instruction {
-                    if let Some(SymbolEntry::UserDefinedFunction { index }) = self.program.symbol_table.get(name) {
-                        *instruction = Instruction::FunctionCall(CallTarget::Index(*index));
-                    }
-                }
+    /// Makes the program a module. Its functions are called from other modules and the host as `module::function`.
+    pub fn module(&mut self, name: &str) -> &mut Self {
+        self.program.name = Some(name.to_string());
+        self
+    }
+
+    /// Makes a function of the module callable from other modules and the host.
+    pub fn export(&mut self, name: &str) -> &mut Self {
+        self.program.exports.push(name.to_string());
+        self
+    }
+
+    /// Imports a function exported by another module, such as `math.sqrt`, to call it by its plain name.
+    /// Invalid paths are reported as unresolved symbols when the program is built.
+    pub fn import(&mut self, path: &str) -> &mut Self {
+        match Import::parse(path) {
+            Some(import) => self.program.imports.push(import),
+            None => self.invalid_imports.push(path.to_string())
+        }
+        self
+    }
+
+    /// Enables checking the operand stack depth of every function when the program is built.
+    /// Calls with fewer operands than the callee's arity and jumps that join with different depths are rejected.
+    pub fn verify_stack_depth(&mut self, enabled: bool) -> &mut Self {
+        self.verify_stack_depth = enabled;
+        self
+    }
+
+    /// Enables rewriting calls that are directly followed by a return into tail calls.
+    /// Only calls to functions that return a single value are rewritten, so the result stays the same.
+    pub fn optimize_tail_calls(&mut self, enabled: bool) -> &mut Self {
+        self.optimize_tail_calls = enabled;
+        self
+    }
+
+    /// Builds the program and panics if the stack depth verification fails.
+    pub fn build(self) -> Program {
+        match self.try_build() {
+            Ok(program) => program,
+            Err(error) => panic!("{}", error)
+        }
+    }
+
+    /// Builds the program and returns an error with the location of the first failed check.
+    pub fn try_build(mut self) -> Result<Program, VmError> {
+
+        if let Some(path) = self.invalid_imports.first() {
+            return Err(VmError::new(VmErrorKind::UnresolvedSymbol { name: path.clone() }));
+        }
+
+        for name in &self.program.exports {
+            if !matches!(self.program.symbol_table.get(name), Some(SymbolEntry::UserDefinedFunction { .. })) {
+                return Err(VmError::new(VmErrorKind::UnresolvedSymbol { name: name.clone() }));
+            }
+        }
+
+        // Imported names must not hide functions of the program
+        for import in &self.program.imports {
+            if self.program.symbol_table.contains_key(&import.name) {
+                return Err(VmError::new(VmErrorKind::DuplicateSymbol { name: import.name.clone() }));
+            }
+        }
+
+        // Resolve function references with function index and global names with their slot
+        self.program.resolve_references();
+
+        if self.optimize_tail_calls {
+            rewrite_tail_calls(&mut self.program);
+        }
+
+        if self.verify_stack_depth {
+            verify_stack_depth(&self.program)?;
+        }
+
+        Ok(self.program)
+    }
+}
+
+// Replaces calls followed by a return with tail calls, the return stays in place so jump targets don't move
+fn rewrite_tail_calls(program: &mut Program) {
+    let result_counts = program.functions.iter().map(result_count).collect::<Vec<_>>();
+    for function in &mut program.functions {
+        // A tail call would drop the frame that owns the handlers, so functions with try regions are left alone
+        if function.instructions.iter().any(|instruction| matches!(instruction, Instruction::TryBegin(_))) {
+            continue;
+        }
+        for pc in 1..function.instructions.len() {
+            if function.instructions[pc] != Instruction::Return {
+                continue;
+            }
+            if let Instruction::FunctionCall(CallTarget::Index(index)) = function.instructions[pc - 1]
+                && result_counts.get(index) == Some(&Some(1)) {
+                function.instructions[pc - 1] = Instruction::TailCall(CallTarget::Index(index));
             }
         }
-        
-        self.program
     }
 }
 
@@ -50,6 +158,9 @@ impl ProgramBuilder {
 pub struct FunctionBuilder {
     name: String,
     arity: usize,
+    max_arity: usize,
+    variadic: bool,
+    return_count: Option<usize>,
     local_count: usize,
     body: Vec<Instruction>,
 }
@@ -65,6 +176,31 @@ impl FunctionBuilder {
     /// Sets the number of arguments for the function.
     pub fn arity(&mut self, arity: usize) -> &mut Self {
         self.arity = arity;
+        self.max_arity = arity;
+        self
+    }
+
+    /// Sets the number of required arguments.
+    pub fn min_arity(&mut self, arity: usize) -> &mut Self {
+        self.arity = arity;
+        self
+    }
+
+    /// Sets the number of required and optional arguments. Optional arguments that are not passed are null.
+    pub fn max_arity(&mut self, arity: usize) -> &mut Self {
+        self.max_arity = arity;
+        self
+    }
+
+    /// Collects arguments beyond the maximum arity into an array in the local after the parameters.
+    pub fn rest_parameter(&mut self) -> &mut Self {
+        self.variadic = true;
+        self
+    }
+
+    /// Declares the number of values returned by the function, checked when the stack depth is verified.
+    pub fn returns(&mut self, count: usize) -> &mut Self {
+        self.return_count = Some(count);
         self
     }
 
@@ -77,10 +213,18 @@ impl FunctionBuilder {
 
     /// Builds the function and returns it.
     pub fn build(&mut self) -> Function {
+        let optional_count = self.max_arity.saturating_sub(self.arity);
+
+        // Parameters take local slots even if the body does not name them
+        let parameter_slots = self.arity.saturating_add(optional_count).saturating_add(usize::from(self.variadic));
+
         Function {
             name: self.name.clone(),
-            arity: self.arity.clone(),
-            local_count: self.local_count,
+            arity: self.arity,
+            optional_count,
+            variadic: self.variadic,
+            local_count: self.local_count.max(parameter_slots),
+            return_count: self.return_count,
             instructions: self.body.clone(),
         }
     }
@@ -92,8 +236,10 @@ pub struct BlockEncoder {
     instructions: Vec<Instruction>,
     variable_names: HashMap<String, usize>,
     next_local_slot: usize,
+    upvalue_names: HashMap<String, usize>,
+    next_upvalue_slot: usize,
     labels: HashMap<String, usize>,
-    pending_jumps: HashMap<String, usize>,
+    pending_jumps: Vec<(String, usize)>,
     known_functions: HashMap<String, usize>,
 }
 
@@ -104,6 +250,11 @@ impl BlockEncoder {
         self
     }
 
+    /// Appends an instruction as it is, for operands such as function indices that have no named form.
+    pub fn add_instruction(&mut self, instruction: Instruction) -> &mut Self {
+        self.push(instruction)
+    }
+
     /// Declares a local variable with the given name.
     pub fn declare_local(&mut self, name: &str) -> &mut Self {
         if !self.variable_names.contains_key(name) {
@@ -131,6 +282,53 @@ impl BlockEncoder {
         }
     }
 
+    /// Declares a variable captured by the closure that runs this block.
+    /// Upvalues are numbered in declaration order and must match the captures passed to `make_closure`.
+    pub fn declare_upvalue(&mut self, name: &str) -> &mut Self {
+        if !self.upvalue_names.contains_key(name) {
+            self.upvalue_names.insert(name.to_string(), self.next_upvalue_slot);
+            self.next_upvalue_slot += 1;
+        }
+        self
+    }
+
+    /// Sets a captured variable to a value.
+    pub fn set_upvalue(&mut self, name: &str) -> &mut Self {
+        if let Some(&index) = self.upvalue_names.get(name) {
+            self.push(Instruction::SetUpvalue(index))
+        } else {
+            panic!("Upvalue {} not declared", name);
+        }
+    }
+
+    /// Get value from a captured variable.
+    pub fn get_upvalue(&mut self, name: &str) -> &mut Self {
+        if let Some(&index) = self.upvalue_names.get(name) {
+            self.push(Instruction::GetUpvalue(index))
+        } else {
+            panic!("Upvalue {} not declared", name);
+        }
+    }
+
+    /// Creates a closure of the named function that captures the given local variables.
+    pub fn make_closure(&mut self, function: &str, captures: &[&str]) -> &mut Self {
+        let captures = captures.iter().map(|name| match self.variable_names.get(*name) {
+            Some(&index) => index,
+            None => panic!("Local variable {} not declared", name)
+        }).collect();
+        self.push(Instruction::MakeClosure(CallTarget::Name(function.to_string()), captures))
+    }
+
+    /// Sets a global variable to a value. The name is resolved to its slot when the program is built.
+    pub fn set_global(&mut self, name: &str) -> &mut Self {
+        self.push(Instruction::SetGlobal(GlobalTarget::Name(name.to_string())))
+    }
+
+    /// Get value from a global variable.
+    pub fn get_global(&mut self, name: &str) -> &mut Self {
+        self.push(Instruction::GetGlobal(GlobalTarget::Name(name.to_string())))
+    }
+
     /// Adds a label to the instruction list.
     pub fn add_label(&mut self, label: &str) -> &mut Self {
         self.labels.insert(label.to_string(), self.instructions.len());
@@ -142,7 +340,7 @@ impl BlockEncoder {
         if let Some(&index) = self.labels.get(label) {
             self.push(Instruction::Jump(index))
         } else {
-            self.pending_jumps.insert(label.to_string(), self.instructions.len());
+            self.pending_jumps.push((label.to_string(), self.instructions.len()));
             self.push(Instruction::Jump(0))
         }
     }
@@ -152,7 +350,7 @@ impl BlockEncoder {
         if let Some(&index) = self.labels.get(label) {
             self.push(Instruction::JumpIfFalse(index))
         } else {
-            self.pending_jumps.insert(label.to_string(), self.instructions.len());
+            self.pending_jumps.push((label.to_string(), self.instructions.len()));
             self.push(Instruction::JumpIfFalse(0))
         }
     }
@@ -283,6 +481,26 @@ impl BlockEncoder {
         self.push(Instruction::FunctionCall(CallTarget::Index(index)))
     }
 
+    /// Calls a function by its name in place of the current function and returns its result.
+    pub fn tail_call_function_by_name(&mut self, name: &str) -> &mut Self {
+        self.push(Instruction::TailCall(CallTarget::Name(name.to_string())))
+    }
+
+    /// Calls a function that takes optional or rest parameters with the given number of arguments.
+    pub fn call_function_with_argument_count(&mut self, name: &str, argument_count: usize) -> &mut Self {
+        self.push(Instruction::CallWithArgumentCount(CallTarget::Name(name.to_string()), argument_count))
+    }
+
+    /// Pushes the number of arguments passed to the current function.
+    pub fn get_argument_count(&mut self) -> &mut Self {
+        self.push(Instruction::GetArgumentCount)
+    }
+
+    /// Pops a function reference and calls it with the given number of arguments.
+    pub fn call_indirect(&mut self, argument_count: usize) -> &mut Self {
+        self.push(Instruction::CallIndirect(argument_count))
+    }
+
     pub fn create_array(&mut self, size: usize) -> &mut Self {
         self.push(Instruction::CreateArray(size))
     }
@@ -317,11 +535,21 @@ impl BlockEncoder {
         self.push(Instruction::GetDictionaryKeys)
     }
 
+    /// Discards the top of the stack.
+    pub fn pop(&mut self) -> &mut Self {
+        self.push(Instruction::Pop)
+    }
+
     /// Halts the execution of the function and returns the top of the stack.
     pub fn return_value(&mut self) -> &mut Self {
         self.push(Instruction::Return)
     }
 
+    /// Returns the given number of values from the top of the stack to the caller.
+    pub fn return_values(&mut self, count: usize) -> &mut Self {
+        self.push(Instruction::ReturnN(count))
+    }
+
     /// End function execution.
     pub fn end_function(&mut self) -> &mut Self {
         self.push(Instruction::EndFunction)
@@ -337,6 +565,46 @@ impl BlockEncoder {
         self.push(Instruction::Halt)
     }
 
+    /// Creates a coroutine from the function reference or closure on top of the stack and the given number of arguments below it.
+    pub fn make_coroutine(&mut self, argument_count: usize) -> &mut Self {
+        self.push(Instruction::MakeCoroutine(argument_count))
+    }
+
+    /// Runs the coroutine on top of the stack until it yields or returns and pushes the value it produced.
+    pub fn resume(&mut self) -> &mut Self {
+        self.push(Instruction::Resume)
+    }
+
+    /// Suspends the current coroutine and hands the top of the stack to the resumer.
+    pub fn yield_value(&mut self) -> &mut Self {
+        self.push(Instruction::Yield)
+    }
+
+    /// Pushes true if the coroutine on top of the stack has returned or failed.
+    pub fn is_coroutine_finished(&mut self) -> &mut Self {
+        self.push(Instruction::IsCoroutineFinished)
+    }
+
+    /// Starts a try region. Errors thrown until the matching try_end continue at the handler label with the exception on the stack.
+    pub fn try_begin(&mut self, handler_label: &str) -> &mut Self {
+        if let Some(&index) = self.labels.get(handler_label) {
+            self.push(Instruction::TryBegin(index))
+        } else {
+            self.pending_jumps.push((handler_label.to_string(), self.instructions.len()));
+            self.push(Instruction::TryBegin(0))
+        }
+    }
+
+    /// Ends the innermost try region of the current function.
+    pub fn try_end(&mut self) -> &mut Self {
+        self.push(Instruction::TryEnd)
+    }
+
+    /// Throws the top of the stack as an exception.
+    pub fn throw(&mut self) -> &mut Self {
+        self.push(Instruction::Throw)
+    }
+
     /// Panics the VM with the top of the stack as the error message.
     pub fn panic(&mut self) -> &mut Self {
         self.push(Instruction::Panic)
@@ -348,7 +616,7 @@ impl BlockEncoder {
         // Insert Halt at the end of the block if not already present
         if let Some(last_instruction) = self.instructions.last() {
             match last_instruction {
-                Instruction::Return | Instruction::Halt => {}
+                Instruction::Return | Instruction::ReturnN(_) | Instruction::TailCall(_) | Instruction::Throw | Instruction::Halt => {}
                 _ => {
                     self.push(Instruction::Halt);
                 }
@@ -365,7 +633,10 @@ impl BlockEncoder {
                     Some(Instruction::JumpIfFalse(_)) => {
                         self.instructions[*index] = Instruction::JumpIfFalse(target_index);
                     }
-                    ins => unreachable!("Expected Jump or JumpIfFalse instruction, found {:?}", ins),
+                    Some(Instruction::TryBegin(_)) => {
+                        self.instructions[*index] = Instruction::TryBegin(target_index);
+                    }
+                    ins => unreachable!("Expected Jump, JumpIfFalse or TryBegin instruction, found {:?}", ins),
                 }
             } else {
                 panic!("Label {} not found", label);
@@ -443,5 +714,59 @@ mod test {
         let instructions = encoder.encode();
         assert_eq!(instructions, vec![]);
     }
+
+    #[test]
+    fn test_resolve_global_slots() {
+        let mut builder = ProgramBuilder::default();
+        assert_eq!(builder.declare_global("a", None), 0);
+        assert_eq!(builder.declare_global("b", Some(Variant::Integer(1))), 1);
+        assert_eq!(builder.declare_global("a", Some(Variant::Integer(2))), 0);
+        builder.add_function(FunctionBuilder::default()
+            .name("main")
+            .body(BlockEncoder::default().get_global("b").set_global("a").get_global("c"))
+            .build()
+        );
+
+        let program = builder.build();
+        assert_eq!(program.globals[0].initial_value, Variant::Integer(2));
+        assert_eq!(program.functions[0].instructions, vec![
+            Instruction::GetGlobal(GlobalTarget::Slot(1)),
+            Instruction::SetGlobal(GlobalTarget::Slot(0)),
+            Instruction::GetGlobal(GlobalTarget::Name(String::from("c"))),
+            Instruction::Halt
+        ]);
+    }
+
+    #[test]
+    fn test_rewrite_tail_calls() {
+        let mut builder = ProgramBuilder::default();
+        builder.optimize_tail_calls(true);
+        builder.add_function(FunctionBuilder::default()
+            .name("one")
+            .body(BlockEncoder::default().push_integer(1).return_value())
+            .build()
+        );
+        builder.add_function(FunctionBuilder::default()
+            .name("nothing")
+            .body(BlockEncoder::default().end_function())
+            .build()
+        );
+        builder.add_function(FunctionBuilder::default()
+            .name("main")
+            .body(BlockEncoder::default()
+                .call_function_by_name("nothing")
+                .call_function_by_name("one")
+                .return_value()
+            )
+            .build()
+        );
+
+        let program = builder.build();
+        assert_eq!(program.functions[2].instructions, vec![
+            Instruction::FunctionCall(CallTarget::Index(1)),
+            Instruction::TailCall(CallTarget::Index(0)),
+            Instruction::Return
+        ]);
+    }
     
 }
@@ -0,0 +1,284 @@
+use crate::program::{CallTarget, Function, GlobalTarget, Instruction, Program, SymbolEntry};
+use crate::variant::Variant;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{Display, Formatter, Result, Write};
+
+// Column of the instruction numbers, which are written as comments so that the output can be assembled again
+const NUMBER_COLUMN: usize = 40;
+
+/// Prints the program in the assembly language, so that `Program::from_assembly` reads it back.
+impl Display for Program {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+
+        // Declarations come before the functions and are separated from them by a blank line
+        let mut header = String::new();
+        if let Some(name) = &self.name {
+            let _ = writeln!(header, ".module {}", name);
+        }
+        for export in &self.exports {
+            let _ = writeln!(header, ".export {}", export);
+        }
+        for import in &self.imports {
+            let _ = writeln!(header, ".import {}.{}", import.module, import.name);
+        }
+
+        let mut natives = self.symbol_table.iter()
+            .filter_map(|(name, entry)| match entry {
+                SymbolEntry::NativeFunction { arity } => Some((name, arity)),
+                SymbolEntry::UserDefinedFunction { .. } => None
+            })
+            .collect::<Vec<_>>();
+        natives.sort();
+        for (name, arity) in natives {
+            let _ = writeln!(header, ".native {} {}", name, arity);
+        }
+
+        for global in &self.globals {
+            let _ = match &global.initial_value {
+                Variant::Null => writeln!(header, ".global {}", global.name),
+                value => writeln!(header, ".global {} {}", global.name, Constant(value))
+            };
+        }
+
+        write!(f, "{}", header)?;
+        for (index, function) in self.functions.iter().enumerate() {
+            if index > 0 || !header.is_empty() {
+                writeln!(f)?;
+            }
+            write_function(f, function, Some(index), Some(self))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Prints the function in the assembly language. Functions and globals are shown by index because a function
+/// on its own has no symbol table, print the program to see their names.
+impl Display for Function {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write_function(f, self, None, None)
+    }
+}
+
+fn write_function(f: &mut Formatter<'_>, function: &Function, index: Option<usize>, program: Option<&Program>) -> Result {
+
+    let parameter_count = function.arity.saturating_add(function.optional_count).saturating_add(usize::from(function.variadic));
+    let local_name = |slot: usize| match slot < parameter_count {
+        true => format!("arg_{}", slot),
+        false => format!("local_{}", slot)
+    };
+
+    // Jump targets get labels numbered in the order they appear
+    let labels = function.instructions.iter()
+        .filter_map(|instruction| match instruction {
+            Instruction::Jump(target) | Instruction::JumpIfFalse(target) | Instruction::TryBegin(target) => Some(*target),
+            _ => None
+        })
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .enumerate()
+        .map(|(number, target)| (target, format!("L{}", number)))
+        .collect::<BTreeMap<_, _>>();
+
+    // Upvalues are not declared by functions, so the highest one that is used is taken as their count
+    let upvalue_count = function.instructions.iter()
+        .filter_map(|instruction| match instruction {
+            Instruction::GetUpvalue(slot) | Instruction::SetUpvalue(slot) => Some(slot + 1),
+            _ => None
+        })
+        .max()
+        .unwrap_or_default();
+
+    match index {
+        Some(index) => write!(f, "; function #{}: ", index)?,
+        None => write!(f, "; function: ")?
+    }
+    writeln!(f, "{}, arity {}, {} locals", function.name, function.arity, function.local_count)?;
+
+    write!(f, ".function {} {}", function.name, function.arity)?;
+    if function.optional_count > 0 {
+        write!(f, "..{}", function.arity.saturating_add(function.optional_count))?;
+    }
+    if function.variadic {
+        write!(f, "+")?;
+    }
+    writeln!(f)?;
+
+    if let Some(count) = function.return_count {
+        writeln!(f, "    .returns {}", count)?;
+    }
+    for slot in 0..function.local_count {
+        writeln!(f, "    .local {}", local_name(slot))?;
+    }
+    for slot in 0..upvalue_count {
+        writeln!(f, "    .upvalue upvalue_{}", slot)?;
+    }
+
+    for (pc, instruction) in function.instructions.iter().enumerate() {
+        if let Some(label) = labels.get(&pc) {
+            writeln!(f, "{}:", label)?;
+        }
+
+        let mut line = String::from("    ");
+        let label = |target: &usize| labels[target].as_str();
+        let call_target = |target: &CallTarget| match target {
+            CallTarget::Name(name) => name.clone(),
+            CallTarget::Index(index) => function_name(program, *index)
+        };
+        let global_target = |target: &GlobalTarget| match target {
+            GlobalTarget::Name(name) => name.clone(),
+            GlobalTarget::Slot(slot) => global_name(program, *slot)
+        };
+
+        let _ = match instruction {
+            Instruction::SetLocal(slot) => write!(line, "set_local {}", local_name(*slot)),
+            Instruction::GetLocal(slot) => write!(line, "get_local {}", local_name(*slot)),
+            Instruction::SetGlobal(target) => write!(line, "set_global {}", global_target(target)),
+            Instruction::GetGlobal(target) => write!(line, "get_global {}", global_target(target)),
+            Instruction::CreateArray(size) => write!(line, "create_array {}", size),
+            Instruction::GetArrayItem => write!(line, "get_array_item"),
+            Instruction::SetArrayItem => write!(line, "set_array_item"),
+            Instruction::GetArrayLength => write!(line, "get_array_length"),
+            Instruction::CreateDictionary(size) => write!(line, "create_dictionary {}", size),
+            Instruction::GetDictionaryItem => write!(line, "get_dictionary_item"),
+            Instruction::SetDictionaryItem => write!(line, "set_dictionary_item"),
+            Instruction::GetDictionaryKeys => write!(line, "get_dictionary_keys"),
+            Instruction::FunctionCall(target) => write!(line, "call {}", call_target(target)),
+            Instruction::TailCall(target) => write!(line, "tail_call {}", call_target(target)),
+            Instruction::CallWithArgumentCount(target, count) => write!(line, "call_with_argument_count {} {}", call_target(target), count),
+            Instruction::GetArgumentCount => write!(line, "get_argument_count"),
+            Instruction::CallIndirect(count) => write!(line, "call_indirect {}", count),
+            Instruction::MakeClosure(target, captures) => {
+                let _ = write!(line, "make_closure {}", call_target(target));
+                captures.iter().try_for_each(|slot| write!(line, " {}", local_name(*slot)))
+            },
+            Instruction::GetUpvalue(slot) => write!(line, "get_upvalue upvalue_{}", slot),
+            Instruction::SetUpvalue(slot) => write!(line, "set_upvalue upvalue_{}", slot),
+            Instruction::Return => write!(line, "return"),
+            Instruction::ReturnN(count) => write!(line, "return_n {}", count),
+            Instruction::EndFunction => write!(line, "end_function"),
+            Instruction::Push(value) => write!(line, "push {}", Constant(value)),
+            Instruction::Pop => write!(line, "pop"),
+            Instruction::Add => write!(line, "add"),
+            Instruction::Sub => write!(line, "sub"),
+            Instruction::Mul => write!(line, "mul"),
+            Instruction::Div => write!(line, "div"),
+            Instruction::Mod => write!(line, "mod"),
+            Instruction::Pow => write!(line, "pow"),
+            Instruction::Equal => write!(line, "equal"),
+            Instruction::LessThan => write!(line, "less_than"),
+            Instruction::LessEqual => write!(line, "less_equal"),
+            Instruction::GreaterThan => write!(line, "greater_than"),
+            Instruction::GreaterEqual => write!(line, "greater_equal"),
+            Instruction::NotEqual => write!(line, "not_equal"),
+            Instruction::Or => write!(line, "or"),
+            Instruction::And => write!(line, "and"),
+            Instruction::Not => write!(line, "not"),
+            Instruction::Negate => write!(line, "negate"),
+            Instruction::Jump(target) => write!(line, "jump {}", label(target)),
+            Instruction::JumpIfFalse(target) => write!(line, "jump_if_false {}", label(target)),
+            Instruction::MakeCoroutine(count) => write!(line, "make_coroutine {}", count),
+            Instruction::Resume => write!(line, "resume"),
+            Instruction::Yield => write!(line, "yield"),
+            Instruction::IsCoroutineFinished => write!(line, "is_coroutine_finished"),
+            Instruction::TryBegin(target) => write!(line, "try_begin {}", label(target)),
+            Instruction::TryEnd => write!(line, "try_end"),
+            Instruction::Throw => write!(line, "throw"),
+            Instruction::Print => write!(line, "print"),
+            Instruction::Halt => write!(line, "halt"),
+            Instruction::Panic => write!(line, "panic")
+        };
+
+        writeln!(f, "{:<width$} ; {}", line, pc, width = NUMBER_COLUMN)?;
+    }
+
+    // Targets past the last instruction
+    for (_, label) in labels.range(function.instructions.len()..) {
+        writeln!(f, "{}:", label)?;
+    }
+
+    writeln!(f, ".end")
+}
+
+// Name of a function index, if the symbol table resolves that name back to the same index
+fn function_name(program: Option<&Program>, index: usize) -> String {
+    if let Some(program) = program
+        && let Some(function) = program.functions.get(index)
+        && program.symbol_table.get(&function.name) == Some(&SymbolEntry::UserDefinedFunction { index }) {
+        return function.name.clone();
+    }
+    format!("#{}", index)
+}
+
+// Name of a global slot, if it is the first global with that name
+fn global_name(program: Option<&Program>, slot: usize) -> String {
+    if let Some(program) = program
+        && let Some(global) = program.globals.get(slot)
+        && program.globals.iter().position(|other| other.name == global.name) == Some(slot) {
+        return global.name.clone();
+    }
+    format!("#{}", slot)
+}
+
+// Writes a value as a constant of the assembly language
+struct Constant<'a>(&'a Variant);
+
+impl Display for Constant<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        match self.0 {
+            Variant::Null => write!(f, "null"),
+            Variant::Integer(value) => write!(f, "{}", value),
+
+            // Debug keeps the decimal point so that the value is read back as a float
+            Variant::Float(value) => write!(f, "{:?}", value),
+            Variant::String(value) => {
+                write!(f, "\"")?;
+                for character in value.chars() {
+                    match character {
+                        '"' => write!(f, "\\\"")?,
+                        '\\' => write!(f, "\\\\")?,
+                        '\n' => write!(f, "\\n")?,
+                        '\t' => write!(f, "\\t")?,
+                        '\r' => write!(f, "\\r")?,
+                        '\0' => write!(f, "\\0")?,
+                        character => write!(f, "{}", character)?
+                    }
+                }
+                write!(f, "\"")
+            },
+            Variant::Boolean(value) => write!(f, "{}", value),
+            Variant::SymbolReference(name) => write!(f, "@{}", name),
+            Variant::Index(index) => write!(f, "#{}", index),
+            Variant::Array(items) => {
+                write!(f, "[")?;
+                for (index, item) in items.borrow().iter().enumerate() {
+                    if index > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", Constant(item))?;
+                }
+                write!(f, "]")
+            },
+            Variant::Dictionary(items) => {
+
+                // Entries are sorted so that the output does not depend on the hash order
+                let mut entries = items.borrow().iter()
+                    .map(|(key, value)| (Constant(key).to_string(), Constant(value).to_string()))
+                    .collect::<Vec<_>>();
+                entries.sort();
+                write!(f, "{{")?;
+                for (index, (key, value)) in entries.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}: {}", key, value)?;
+                }
+                write!(f, "}}")
+            },
+            Variant::Upvalue(value) => write!(f, "{}", Constant(&value.borrow())),
+
+            // Values that only exist at runtime have no constant syntax
+            value => write!(f, "<{}>", value.type_name())
+        }
+    }
+}
@@ -0,0 +1,679 @@
+use crate::error::{DecodeError, VmError, VmErrorKind};
+use crate::program::{CallTarget, Function, Global, GlobalTarget, Import, Instruction, Program, SymbolEntry};
+use crate::variant::Variant;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+// Marks the start of an encoded program
+pub(crate) const MAGIC: &[u8; 4] = b"BVM\0";
+
+// Version of the encoding written by to_bytes, bytes with any other version are rejected
+pub(crate) const FORMAT_VERSION: u16 = 1;
+
+// Locals are allocated when a function is called, so larger counts are rejected as corrupt instead of being allocated
+const MAX_LOCAL_COUNT: usize = u16::MAX as usize;
+
+// Tags of the constant table entries
+const CONSTANT_NULL: u8 = 0;
+const CONSTANT_INTEGER: u8 = 1;
+const CONSTANT_FLOAT: u8 = 2;
+const CONSTANT_STRING: u8 = 3;
+const CONSTANT_BOOLEAN: u8 = 4;
+const CONSTANT_SYMBOL: u8 = 5;
+const CONSTANT_INDEX: u8 = 6;
+const CONSTANT_ARRAY: u8 = 7;
+const CONSTANT_DICTIONARY: u8 = 8;
+
+// Tags of names and indices in call targets, global targets and symbol entries
+const TARGET_NAME: u8 = 0;
+const TARGET_INDEX: u8 = 1;
+
+/// Encodes a program with its names collected into a string table and its values into a constant table.
+/// Closures, upvalues and coroutines only exist at runtime and can't be encoded.
+pub(crate) fn encode(program: &Program) -> Result<Vec<u8>, VmError> {
+
+    let mut encoder = Encoder::default();
+    let mut body = Vec::new();
+
+    encoder.write_optional_string(&mut body, program.name.as_deref());
+    write_usize(&mut body, program.exports.len());
+    for name in &program.exports {
+        encoder.write_string(&mut body, name);
+    }
+    write_usize(&mut body, program.imports.len());
+    for import in &program.imports {
+        encoder.write_string(&mut body, &import.module);
+        encoder.write_string(&mut body, &import.name);
+    }
+
+    // Symbols are sorted so that the same program always encodes to the same bytes
+    let mut symbols = program.symbol_table.iter().collect::<Vec<_>>();
+    symbols.sort_by_key(|(name, _)| *name);
+    write_usize(&mut body, symbols.len());
+    for (name, entry) in symbols {
+        encoder.write_string(&mut body, name);
+        match entry {
+            SymbolEntry::NativeFunction { arity } => {
+                body.push(TARGET_NAME);
+                write_usize(&mut body, *arity);
+            },
+            SymbolEntry::UserDefinedFunction { index } => {
+                body.push(TARGET_INDEX);
+                write_usize(&mut body, *index);
+            }
+        }
+    }
+
+    write_usize(&mut body, program.globals.len());
+    for global in &program.globals {
+        encoder.write_string(&mut body, &global.name);
+        let constant = encoder.add_constant(&global.initial_value)?;
+        write_usize(&mut body, constant);
+    }
+
+    write_usize(&mut body, program.functions.len());
+    for function in &program.functions {
+        encoder.write_function(&mut body, function)?;
+    }
+
+    let mut bytes = Vec::with_capacity(body.len() + encoder.strings.len() * 8 + encoder.constants.len() * 8 + 16);
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    write_usize(&mut bytes, encoder.strings.len());
+    for string in &encoder.strings {
+        write_usize(&mut bytes, string.len());
+        bytes.extend_from_slice(string.as_bytes());
+    }
+    write_usize(&mut bytes, encoder.constants.len());
+    for constant in &encoder.constants {
+        bytes.extend_from_slice(constant);
+    }
+    bytes.extend_from_slice(&body);
+
+    Ok(bytes)
+}
+
+/// Decodes a program encoded by `encode`, checking every tag and table index.
+pub(crate) fn decode(bytes: &[u8]) -> Result<Program, DecodeError> {
+
+    let mut decoder = Decoder { bytes, offset: 0, strings: Vec::new(), constants: Vec::new() };
+
+    if decoder.read_bytes(MAGIC.len()).ok() != Some(MAGIC.as_slice()) {
+        return Err(DecodeError::InvalidMagic);
+    }
+    let version = u16::from_le_bytes([decoder.read_u8()?, decoder.read_u8()?]);
+    if version != FORMAT_VERSION {
+        return Err(DecodeError::UnsupportedVersion { version, supported: FORMAT_VERSION });
+    }
+
+    let string_count = decoder.read_count()?;
+    for _ in 0..string_count {
+        let length = decoder.read_usize()?;
+        let offset = decoder.offset;
+        let string = std::str::from_utf8(decoder.read_bytes(length)?).map_err(|_| DecodeError::InvalidUtf8 { offset })?.to_string();
+        decoder.strings.push(string);
+    }
+
+    let constant_count = decoder.read_count()?;
+    for _ in 0..constant_count {
+        let constant = decoder.read_constant()?;
+        decoder.constants.push(constant);
+    }
+
+    let mut program = Program {
+        name: decoder.read_optional_string()?,
+        ..Default::default()
+    };
+
+    for _ in 0..decoder.read_count()? {
+        program.exports.push(decoder.read_string()?);
+    }
+    for _ in 0..decoder.read_count()? {
+        program.imports.push(Import {
+            module: decoder.read_string()?,
+            name: decoder.read_string()?
+        });
+    }
+
+    for _ in 0..decoder.read_count()? {
+        let name = decoder.read_string()?;
+        let entry = match decoder.read_tag()? {
+            (TARGET_NAME, _) => SymbolEntry::NativeFunction { arity: decoder.read_usize()? },
+            (TARGET_INDEX, _) => SymbolEntry::UserDefinedFunction { index: decoder.read_usize()? },
+            (tag, offset) => return Err(DecodeError::InvalidTag { kind: "symbol entry", tag, offset })
+        };
+        program.symbol_table.insert(name, entry);
+    }
+
+    for _ in 0..decoder.read_count()? {
+        program.globals.push(Global {
+            name: decoder.read_string()?,
+            initial_value: decoder.read_constant_reference()?
+        });
+    }
+
+    for _ in 0..decoder.read_count()? {
+        let function = decoder.read_function()?;
+        program.functions.push(function);
+    }
+
+    if decoder.offset != bytes.len() {
+        return Err(DecodeError::TrailingBytes { offset: decoder.offset });
+    }
+
+    Ok(program)
+}
+
+fn write_usize(bytes: &mut Vec<u8>, value: usize) {
+    write_u64(bytes, value as u64);
+}
+
+// Unsigned LEB128, seven bits per byte with the high bit set on all but the last byte
+fn write_u64(bytes: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            bytes.push(byte);
+            return;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+#[derive(Default)]
+struct Encoder {
+    strings: Vec<String>,
+    string_indices: HashMap<String, usize>,
+    constants: Vec<Vec<u8>>,
+    constant_indices: HashMap<Vec<u8>, usize>,
+}
+
+impl Encoder {
+
+    fn write_string(&mut self, bytes: &mut Vec<u8>, string: &str) {
+        let index = match self.string_indices.get(string) {
+            Some(index) => *index,
+            None => {
+                self.strings.push(string.to_string());
+                self.string_indices.insert(string.to_string(), self.strings.len() - 1);
+                self.strings.len() - 1
+            }
+        };
+        write_usize(bytes, index);
+    }
+
+    fn write_optional_string(&mut self, bytes: &mut Vec<u8>, string: Option<&str>) {
+        match string {
+            Some(string) => {
+                bytes.push(1);
+                self.write_string(bytes, string);
+            },
+            None => bytes.push(0)
+        }
+    }
+
+    // Scalar constants are shared, every array and dictionary gets its own entry to keep them distinct values
+    fn add_constant(&mut self, value: &Variant) -> Result<usize, VmError> {
+
+        let mut entry = Vec::new();
+        let shared = match value {
+            Variant::Null => {
+                entry.push(CONSTANT_NULL);
+                true
+            },
+            Variant::Integer(value) => {
+                entry.push(CONSTANT_INTEGER);
+                entry.extend_from_slice(&value.to_le_bytes());
+                true
+            },
+            Variant::Float(value) => {
+                entry.push(CONSTANT_FLOAT);
+                entry.extend_from_slice(&value.to_bits().to_le_bytes());
+                true
+            },
+            Variant::String(value) => {
+                entry.push(CONSTANT_STRING);
+                self.write_string(&mut entry, value);
+                true
+            },
+            Variant::Boolean(value) => {
+                entry.push(CONSTANT_BOOLEAN);
+                entry.push(*value as u8);
+                true
+            },
+            Variant::SymbolReference(name) => {
+                entry.push(CONSTANT_SYMBOL);
+                self.write_string(&mut entry, name);
+                true
+            },
+            Variant::Index(index) => {
+                entry.push(CONSTANT_INDEX);
+                write_usize(&mut entry, *index);
+                true
+            },
+            Variant::Array(array) => {
+                let items = array.borrow().iter().map(|item| self.add_constant(item)).collect::<Result<Vec<_>, _>>()?;
+                entry.push(CONSTANT_ARRAY);
+                write_usize(&mut entry, items.len());
+                for item in items {
+                    write_usize(&mut entry, item);
+                }
+                false
+            },
+            Variant::Dictionary(dictionary) => {
+                let mut items = Vec::new();
+                for (key, value) in dictionary.borrow().iter() {
+                    items.push((self.add_constant(key)?, self.add_constant(value)?));
+                }
+                entry.push(CONSTANT_DICTIONARY);
+                write_usize(&mut entry, items.len());
+                for (key, value) in items {
+                    write_usize(&mut entry, key);
+                    write_usize(&mut entry, value);
+                }
+                false
+            },
+            Variant::Closure(_) | Variant::Upvalue(_) | Variant::Coroutine(_) => {
+                return Err(VmError::new(VmErrorKind::UnsupportedConstant { type_name: value.type_name() }));
+            }
+        };
+
+        if shared && let Some(index) = self.constant_indices.get(&entry) {
+            return Ok(*index);
+        }
+        self.constants.push(entry.clone());
+        if shared {
+            self.constant_indices.insert(entry, self.constants.len() - 1);
+        }
+        Ok(self.constants.len() - 1)
+    }
+
+    fn write_call_target(&mut self, bytes: &mut Vec<u8>, target: &CallTarget) {
+        match target {
+            CallTarget::Name(name) => {
+                bytes.push(TARGET_NAME);
+                self.write_string(bytes, name);
+            },
+            CallTarget::Index(index) => {
+                bytes.push(TARGET_INDEX);
+                write_usize(bytes, *index);
+            }
+        }
+    }
+
+    fn write_global_target(&mut self, bytes: &mut Vec<u8>, target: &GlobalTarget) {
+        match target {
+            GlobalTarget::Name(name) => {
+                bytes.push(TARGET_NAME);
+                self.write_string(bytes, name);
+            },
+            GlobalTarget::Slot(slot) => {
+                bytes.push(TARGET_INDEX);
+                write_usize(bytes, *slot);
+            }
+        }
+    }
+
+    fn write_function(&mut self, bytes: &mut Vec<u8>, function: &Function) -> Result<(), VmError> {
+        self.write_string(bytes, &function.name);
+        write_usize(bytes, function.arity);
+        write_usize(bytes, function.optional_count);
+        bytes.push(function.variadic as u8);
+        write_usize(bytes, function.local_count);
+        match function.return_count {
+            Some(count) => {
+                bytes.push(1);
+                write_usize(bytes, count);
+            },
+            None => bytes.push(0)
+        }
+        write_usize(bytes, function.instructions.len());
+        for instruction in &function.instructions {
+            self.write_instruction(bytes, instruction)?;
+        }
+        Ok(())
+    }
+
+    fn write_instruction(&mut self, bytes: &mut Vec<u8>, instruction: &Instruction) -> Result<(), VmError> {
+        bytes.push(opcode(instruction));
+        match instruction {
+            Instruction::SetLocal(operand)
+            | Instruction::GetLocal(operand)
+            | Instruction::CreateArray(operand)
+            | Instruction::CreateDictionary(operand)
+            | Instruction::CallIndirect(operand)
+            | Instruction::GetUpvalue(operand)
+            | Instruction::SetUpvalue(operand)
+            | Instruction::ReturnN(operand)
+            | Instruction::Jump(operand)
+            | Instruction::JumpIfFalse(operand)
+            | Instruction::MakeCoroutine(operand)
+            | Instruction::TryBegin(operand) => write_usize(bytes, *operand),
+            Instruction::SetGlobal(target) | Instruction::GetGlobal(target) => self.write_global_target(bytes, target),
+            Instruction::FunctionCall(target) | Instruction::TailCall(target) => self.write_call_target(bytes, target),
+            Instruction::CallWithArgumentCount(target, count) => {
+                self.write_call_target(bytes, target);
+                write_usize(bytes, *count);
+            },
+            Instruction::MakeClosure(target, captures) => {
+                self.write_call_target(bytes, target);
+                write_usize(bytes, captures.len());
+                for capture in captures {
+                    write_usize(bytes, *capture);
+                }
+            },
+            Instruction::Push(value) => {
+                let constant = self.add_constant(value)?;
+                write_usize(bytes, constant);
+            },
+            _ => {}
+        }
+        Ok(())
+    }
+
+}
+
+// Opcodes follow the order of the instructions, new instructions are added at the end
+fn opcode(instruction: &Instruction) -> u8 {
+    match instruction {
+        Instruction::SetLocal(_) => 0,
+        Instruction::GetLocal(_) => 1,
+        Instruction::SetGlobal(_) => 2,
+        Instruction::GetGlobal(_) => 3,
+        Instruction::CreateArray(_) => 4,
+        Instruction::GetArrayItem => 5,
+        Instruction::SetArrayItem => 6,
+        Instruction::GetArrayLength => 7,
+        Instruction::CreateDictionary(_) => 8,
+        Instruction::GetDictionaryItem => 9,
+        Instruction::SetDictionaryItem => 10,
+        Instruction::GetDictionaryKeys => 11,
+        Instruction::FunctionCall(_) => 12,
+        Instruction::TailCall(_) => 13,
+        Instruction::CallWithArgumentCount(_, _) => 14,
+        Instruction::GetArgumentCount => 15,
+        Instruction::CallIndirect(_) => 16,
+        Instruction::MakeClosure(_, _) => 17,
+        Instruction::GetUpvalue(_) => 18,
+        Instruction::SetUpvalue(_) => 19,
+        Instruction::Return => 20,
+        Instruction::ReturnN(_) => 21,
+        Instruction::EndFunction => 22,
+        Instruction::Push(_) => 23,
+        Instruction::Pop => 24,
+        Instruction::Add => 25,
+        Instruction::Sub => 26,
+        Instruction::Mul => 27,
+        Instruction::Div => 28,
+        Instruction::Mod => 29,
+        Instruction::Pow => 30,
+        Instruction::Equal => 31,
+        Instruction::LessThan => 32,
+        Instruction::LessEqual => 33,
+        Instruction::GreaterThan => 34,
+        Instruction::GreaterEqual => 35,
+        Instruction::NotEqual => 36,
+        Instruction::Or => 37,
+        Instruction::And => 38,
+        Instruction::Not => 39,
+        Instruction::Negate => 40,
+        Instruction::Jump(_) => 41,
+        Instruction::JumpIfFalse(_) => 42,
+        Instruction::MakeCoroutine(_) => 43,
+        Instruction::Resume => 44,
+        Instruction::Yield => 45,
+        Instruction::IsCoroutineFinished => 46,
+        Instruction::TryBegin(_) => 47,
+        Instruction::TryEnd => 48,
+        Instruction::Throw => 49,
+        Instruction::Print => 50,
+        Instruction::Halt => 51,
+        Instruction::Panic => 52,
+    }
+}
+
+struct Decoder<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+    strings: Vec<String>,
+    constants: Vec<Variant>,
+}
+
+impl Decoder<'_> {
+
+    fn read_bytes(&mut self, length: usize) -> Result<&[u8], DecodeError> {
+        match self.offset.checked_add(length) {
+            Some(end) if end <= self.bytes.len() => {
+                let bytes = &self.bytes[self.offset..end];
+                self.offset = end;
+                Ok(bytes)
+            },
+            _ => Err(DecodeError::UnexpectedEnd { offset: self.bytes.len() })
+        }
+    }
+
+    fn read_u8(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    // Returns the tag and its offset for error messages
+    fn read_tag(&mut self) -> Result<(u8, usize), DecodeError> {
+        let offset = self.offset;
+        Ok((self.read_u8()?, offset))
+    }
+
+    fn read_bool(&mut self, kind: &'static str) -> Result<bool, DecodeError> {
+        match self.read_tag()? {
+            (0, _) => Ok(false),
+            (1, _) => Ok(true),
+            (tag, offset) => Err(DecodeError::InvalidTag { kind, tag, offset })
+        }
+    }
+
+    fn read_u64(&mut self) -> Result<u64, DecodeError> {
+        let offset = self.offset;
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.read_u8()?;
+            let bits = (byte & 0x7f) as u64;
+            if shift == 63 && bits > 1 {
+                return Err(DecodeError::NumberTooLarge { offset });
+            }
+            value |= bits << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(DecodeError::NumberTooLarge { offset })
+    }
+
+    fn read_usize(&mut self) -> Result<usize, DecodeError> {
+        let offset = self.offset;
+        usize::try_from(self.read_u64()?).map_err(|_| DecodeError::NumberTooLarge { offset })
+    }
+
+    // Counts can't exceed the remaining bytes since every item takes at least one byte
+    fn read_count(&mut self) -> Result<usize, DecodeError> {
+        let count = self.read_usize()?;
+        if count > self.bytes.len() - self.offset {
+            return Err(DecodeError::UnexpectedEnd { offset: self.bytes.len() });
+        }
+        Ok(count)
+    }
+
+    fn read_string(&mut self) -> Result<String, DecodeError> {
+        let index = self.read_usize()?;
+        match self.strings.get(index) {
+            Some(string) => Ok(string.clone()),
+            None => Err(DecodeError::InvalidStringIndex { index })
+        }
+    }
+
+    fn read_optional_string(&mut self) -> Result<Option<String>, DecodeError> {
+        match self.read_bool("optional string")? {
+            true => Ok(Some(self.read_string()?)),
+            false => Ok(None)
+        }
+    }
+
+    fn read_constant_reference(&mut self) -> Result<Variant, DecodeError> {
+        let index = self.read_usize()?;
+        match self.constants.get(index) {
+            Some(value) => Ok(value.clone()),
+            None => Err(DecodeError::InvalidConstantIndex { index })
+        }
+    }
+
+    // Arrays and dictionaries refer to constants that come before them in the table
+    fn read_constant(&mut self) -> Result<Variant, DecodeError> {
+        let value = match self.read_tag()? {
+            (CONSTANT_NULL, _) => Variant::Null,
+            (CONSTANT_INTEGER, _) => Variant::Integer(i64::from_le_bytes(self.read_bytes(8)?.try_into().unwrap())),
+            (CONSTANT_FLOAT, _) => Variant::Float(f64::from_bits(u64::from_le_bytes(self.read_bytes(8)?.try_into().unwrap()))),
+            (CONSTANT_STRING, _) => Variant::String(self.read_string()?),
+            (CONSTANT_BOOLEAN, _) => Variant::Boolean(self.read_bool("boolean")?),
+            (CONSTANT_SYMBOL, _) => Variant::SymbolReference(self.read_string()?),
+            (CONSTANT_INDEX, _) => Variant::Index(self.read_usize()?),
+            (CONSTANT_ARRAY, _) => {
+                let count = self.read_count()?;
+                let mut items = Vec::with_capacity(count);
+                for _ in 0..count {
+                    items.push(self.read_constant_reference()?);
+                }
+                Variant::Array(Rc::new(RefCell::new(items)))
+            },
+            (CONSTANT_DICTIONARY, _) => {
+                let count = self.read_count()?;
+                let mut items = HashMap::with_capacity(count);
+                for _ in 0..count {
+                    let key = self.read_constant_reference()?;
+                    let value = self.read_constant_reference()?;
+                    items.insert(key, value);
+                }
+                Variant::Dictionary(Rc::new(RefCell::new(items)))
+            },
+            (tag, offset) => return Err(DecodeError::InvalidTag { kind: "constant", tag, offset })
+        };
+        Ok(value)
+    }
+
+    fn read_call_target(&mut self) -> Result<CallTarget, DecodeError> {
+        match self.read_tag()? {
+            (TARGET_NAME, _) => Ok(CallTarget::Name(self.read_string()?)),
+            (TARGET_INDEX, _) => Ok(CallTarget::Index(self.read_usize()?)),
+            (tag, offset) => Err(DecodeError::InvalidTag { kind: "call target", tag, offset })
+        }
+    }
+
+    fn read_global_target(&mut self) -> Result<GlobalTarget, DecodeError> {
+        match self.read_tag()? {
+            (TARGET_NAME, _) => Ok(GlobalTarget::Name(self.read_string()?)),
+            (TARGET_INDEX, _) => Ok(GlobalTarget::Slot(self.read_usize()?)),
+            (tag, offset) => Err(DecodeError::InvalidTag { kind: "global target", tag, offset })
+        }
+    }
+
+    fn read_function(&mut self) -> Result<Function, DecodeError> {
+        let name = self.read_string()?;
+        let arity = self.read_usize()?;
+        let optional_count = self.read_usize()?;
+        let variadic = self.read_bool("variadic flag")?;
+
+        // Parameters take the first local slots
+        let offset = self.offset;
+        let local_count = self.read_usize()?;
+        let parameter_count = arity.saturating_add(optional_count).saturating_add(usize::from(variadic));
+        if local_count < parameter_count || local_count > MAX_LOCAL_COUNT {
+            return Err(DecodeError::InvalidLocalCount { local_count, offset });
+        }
+        let return_count = match self.read_bool("return count")? {
+            true => Some(self.read_usize()?),
+            false => None
+        };
+        let count = self.read_count()?;
+        let mut instructions = Vec::with_capacity(count);
+        for _ in 0..count {
+            instructions.push(self.read_instruction()?);
+        }
+        Ok(Function {
+            name,
+            arity,
+            optional_count,
+            variadic,
+            local_count,
+            return_count,
+            instructions
+        })
+    }
+
+    fn read_instruction(&mut self) -> Result<Instruction, DecodeError> {
+        let instruction = match self.read_tag()? {
+            (0, _) => Instruction::SetLocal(self.read_usize()?),
+            (1, _) => Instruction::GetLocal(self.read_usize()?),
+            (2, _) => Instruction::SetGlobal(self.read_global_target()?),
+            (3, _) => Instruction::GetGlobal(self.read_global_target()?),
+            (4, _) => Instruction::CreateArray(self.read_usize()?),
+            (5, _) => Instruction::GetArrayItem,
+            (6, _) => Instruction::SetArrayItem,
+            (7, _) => Instruction::GetArrayLength,
+            (8, _) => Instruction::CreateDictionary(self.read_usize()?),
+            (9, _) => Instruction::GetDictionaryItem,
+            (10, _) => Instruction::SetDictionaryItem,
+            (11, _) => Instruction::GetDictionaryKeys,
+            (12, _) => Instruction::FunctionCall(self.read_call_target()?),
+            (13, _) => Instruction::TailCall(self.read_call_target()?),
+            (14, _) => Instruction::CallWithArgumentCount(self.read_call_target()?, self.read_usize()?),
+            (15, _) => Instruction::GetArgumentCount,
+            (16, _) => Instruction::CallIndirect(self.read_usize()?),
+            (17, _) => {
+                let target = self.read_call_target()?;
+                let count = self.read_count()?;
+                let mut captures = Vec::with_capacity(count);
+                for _ in 0..count {
+                    captures.push(self.read_usize()?);
+                }
+                Instruction::MakeClosure(target, captures)
+            },
+            (18, _) => Instruction::GetUpvalue(self.read_usize()?),
+            (19, _) => Instruction::SetUpvalue(self.read_usize()?),
+            (20, _) => Instruction::Return,
+            (21, _) => Instruction::ReturnN(self.read_usize()?),
+            (22, _) => Instruction::EndFunction,
+            (23, _) => Instruction::Push(self.read_constant_reference()?),
+            (24, _) => Instruction::Pop,
+            (25, _) => Instruction::Add,
+            (26, _) => Instruction::Sub,
+            (27, _) => Instruction::Mul,
+            (28, _) => Instruction::Div,
+            (29, _) => Instruction::Mod,
+            (30, _) => Instruction::Pow,
+            (31, _) => Instruction::Equal,
+            (32, _) => Instruction::LessThan,
+            (33, _) => Instruction::LessEqual,
+            (34, _) => Instruction::GreaterThan,
+            (35, _) => Instruction::GreaterEqual,
+            (36, _) => Instruction::NotEqual,
+            (37, _) => Instruction::Or,
+            (38, _) => Instruction::And,
+            (39, _) => Instruction::Not,
+            (40, _) => Instruction::Negate,
+            (41, _) => Instruction::Jump(self.read_usize()?),
+            (42, _) => Instruction::JumpIfFalse(self.read_usize()?),
+            (43, _) => Instruction::MakeCoroutine(self.read_usize()?),
+            (44, _) => Instruction::Resume,
+            (45, _) => Instruction::Yield,
+            (46, _) => Instruction::IsCoroutineFinished,
+            (47, _) => Instruction::TryBegin(self.read_usize()?),
+            (48, _) => Instruction::TryEnd,
+            (49, _) => Instruction::Throw,
+            (50, _) => Instruction::Print,
+            (51, _) => Instruction::Halt,
+            (52, _) => Instruction::Panic,
+            (tag, offset) => return Err(DecodeError::InvalidTag { kind: "instruction", tag, offset })
+        };
+        Ok(instruction)
+    }
+
+}
@@ -0,0 +1,420 @@
+use crate::variant::Variant;
+use std::convert::Infallible;
+use std::fmt::Display;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum VmErrorKind {
+    RuntimeError {
+        message: String,
+    },
+    FunctionNotFound {
+        name: String,
+    },
+    InvalidFunctionIndex {
+        index: usize,
+    },
+    InvalidEntryPoint {
+        name: String,
+    },
+    ArityMismatch {
+        function: String,
+        expected: usize,
+        actual: usize,
+    },
+    NativeFunctionError {
+        name: String,
+        error: Box<VmError>,
+    },
+    TypeError {
+        expected: &'static str,
+        actual: &'static str,
+    },
+    ArgumentTypeError {
+        index: usize,
+        expected: &'static str,
+        actual: &'static str,
+    },
+    OperatorTypeError {
+        operator: &'static str,
+        lhs: &'static str,
+        rhs: &'static str,
+    },
+    UnaryOperatorTypeError {
+        operator: &'static str,
+        operand: &'static str,
+    },
+    IntegerOverflow {
+        operator: &'static str,
+    },
+    DivisionByZero,
+    StackUnderflow,
+    InvalidLocal {
+        index: usize,
+    },
+    InvalidUpvalue {
+        index: usize,
+    },
+    GlobalNotFound {
+        name: String,
+    },
+    InvalidGlobal {
+        slot: usize,
+    },
+    IndexOutOfBounds {
+        index: usize,
+        length: usize,
+    },
+    KeyNotFound {
+        key: Variant,
+    },
+    InvalidProgramCounter,
+    ReturnCountMismatch {
+        function: String,
+        expected: usize,
+        actual: usize,
+    },
+    InconsistentStackDepth {
+        expected: usize,
+        actual: usize,
+    },
+    OutOfFuel,
+    Timeout,
+    Cancelled,
+    CallDepthExceeded {
+        limit: usize,
+    },
+    StackOverflow {
+        limit: usize,
+    },
+    CollectionTooLarge {
+        length: usize,
+        limit: usize,
+    },
+    HeapLimitExceeded {
+        limit: usize,
+    },
+    Panic {
+        value: Variant,
+    },
+    Thrown {
+        value: Variant,
+    },
+    HandlerNotFound,
+    CoroutineRunning,
+    CoroutineFinished,
+    CoroutineError {
+        error: Box<VmError>,
+    },
+    YieldOutsideCoroutine,
+    DuplicateSymbol {
+        name: String,
+    },
+    UnresolvedSymbol {
+        name: String,
+    },
+    UnsupportedConstant {
+        type_name: &'static str,
+    },
+}
+
+/// A function that was active when an error occurred.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StackTraceEntry {
+    pub function: String,
+    pub function_index: usize,
+    pub pc: usize,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct VmError {
+    pub kind: VmErrorKind,
+
+    // Active functions with the failing function first, empty if the error occurred outside of execution
+    pub backtrace: Vec<StackTraceEntry>,
+}
+
+impl VmError {
+
+    pub fn new(kind: VmErrorKind) -> Self {
+        VmError {
+            kind,
+            backtrace: Vec::new(),
+        }
+    }
+
+    /// Returns the function and pc where the error occurred.
+    pub fn location(&self) -> Option<&StackTraceEntry> {
+        self.backtrace.first()
+    }
+
+}
+
+impl VmErrorKind {
+
+    /// Returns true if an exception handler in the bytecode can catch the error.
+    /// Limits, interruptions, panics and malformed programs always stop the execution.
+    pub fn is_catchable(&self) -> bool {
+        match self {
+            VmErrorKind::NativeFunctionError { error, .. } | VmErrorKind::CoroutineError { error } => error.kind.is_catchable(),
+            VmErrorKind::Thrown { .. }
+            | VmErrorKind::RuntimeError { .. }
+            | VmErrorKind::FunctionNotFound { .. }
+            | VmErrorKind::ArityMismatch { .. }
+            | VmErrorKind::TypeError { .. }
+            | VmErrorKind::ArgumentTypeError { .. }
+            | VmErrorKind::OperatorTypeError { .. }
+            | VmErrorKind::UnaryOperatorTypeError { .. }
+            | VmErrorKind::IntegerOverflow { .. }
+            | VmErrorKind::DivisionByZero
+            | VmErrorKind::GlobalNotFound { .. }
+            | VmErrorKind::IndexOutOfBounds { .. }
+            | VmErrorKind::KeyNotFound { .. }
+            | VmErrorKind::CoroutineRunning
+            | VmErrorKind::CoroutineFinished => true,
+            _ => false
+        }
+    }
+
+    /// Returns true if the error comes from a resource limit or an interruption of the run.
+    pub fn is_limit(&self) -> bool {
+        matches!(self,
+            VmErrorKind::OutOfFuel
+            | VmErrorKind::Timeout
+            | VmErrorKind::Cancelled
+            | VmErrorKind::CallDepthExceeded { .. }
+            | VmErrorKind::StackOverflow { .. }
+            | VmErrorKind::CollectionTooLarge { .. }
+            | VmErrorKind::HeapLimitExceeded { .. }
+        )
+    }
+
+    /// Returns the value an exception handler receives, the thrown value or the error message.
+    pub fn exception_value(&self) -> Variant {
+        match self {
+            VmErrorKind::Thrown { value } => value.clone(),
+            VmErrorKind::NativeFunctionError { error, .. } | VmErrorKind::CoroutineError { error } => error.kind.exception_value(),
+            _ => Variant::String(self.to_string())
+        }
+    }
+
+}
+
+impl From<VmErrorKind> for VmError {
+    fn from(kind: VmErrorKind) -> Self {
+        VmError::new(kind)
+    }
+}
+
+impl From<Infallible> for VmError {
+    fn from(value: Infallible) -> Self {
+        match value {}
+    }
+}
+
+impl Display for VmErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VmErrorKind::RuntimeError { message } => write!(f, "{}", message),
+            VmErrorKind::FunctionNotFound { name } => write!(f, "Function not found: {}", name),
+            VmErrorKind::InvalidFunctionIndex { index } => write!(f, "Invalid function index: {}", index),
+            VmErrorKind::InvalidEntryPoint { name } => write!(f, "Cannot execute native function as entry point: {}", name),
+            VmErrorKind::ArityMismatch { function, expected, actual } => write!(f, "Function {} expects {} arguments but got {}", function, expected, actual),
+            VmErrorKind::NativeFunctionError { name, error } => write!(f, "Native function {} failed: {}", name, error.kind),
+            VmErrorKind::TypeError { expected, actual } => write!(f, "Expected {} but got {}", expected, actual),
+            VmErrorKind::ArgumentTypeError { index, expected, actual } => write!(f, "Argument {} expected {} but got {}", index, expected, actual),
+            VmErrorKind::OperatorTypeError { operator, lhs, rhs } => write!(f, "Invalid operands for {}: {} and {}", operator, lhs, rhs),
+            VmErrorKind::UnaryOperatorTypeError { operator, operand } => write!(f, "Invalid operand for {}: {}", operator, operand),
+            VmErrorKind::IntegerOverflow { operator } => write!(f, "Integer overflow in {}", operator),
+            VmErrorKind::DivisionByZero => write!(f, "Division by zero"),
+            VmErrorKind::StackUnderflow => write!(f, "Operand stack underflow"),
+            VmErrorKind::InvalidLocal { index } => write!(f, "Invalid local variable: {}", index),
+            VmErrorKind::InvalidUpvalue { index } => write!(f, "Invalid upvalue: {}", index),
+            VmErrorKind::GlobalNotFound { name } => write!(f, "Global variable not found: {}", name),
+            VmErrorKind::InvalidGlobal { slot } => write!(f, "Invalid global slot: {}", slot),
+            VmErrorKind::IndexOutOfBounds { index, length } => write!(f, "Array index out of bounds: {} >= {}", index, length),
+            VmErrorKind::KeyNotFound { key } => write!(f, "Dictionary key not found: {}", key),
+            VmErrorKind::InvalidProgramCounter => write!(f, "Program counter out of bounds"),
+            VmErrorKind::ReturnCountMismatch { function, expected, actual } => write!(f, "Function {} declares {} return values but returns {}", function, expected, actual),
+            VmErrorKind::InconsistentStackDepth { expected, actual } => write!(f, "Inconsistent stack depth: expected {} operands but got {}", expected, actual),
+            VmErrorKind::OutOfFuel => write!(f, "Instruction budget exhausted"),
+            VmErrorKind::Timeout => write!(f, "Execution deadline exceeded"),
+            VmErrorKind::Cancelled => write!(f, "Execution cancelled"),
+            VmErrorKind::CallDepthExceeded { limit } => write!(f, "Maximum call depth of {} exceeded", limit),
+            VmErrorKind::StackOverflow { limit } => write!(f, "Maximum stack size of {} exceeded", limit),
+            VmErrorKind::CollectionTooLarge { length, limit } => write!(f, "Collection length {} exceeds the limit of {}", length, limit),
+            VmErrorKind::HeapLimitExceeded { limit } => write!(f, "Heap limit of {} bytes exceeded", limit),
+            VmErrorKind::Panic { value } => write!(f, "Panic: {}", value),
+            VmErrorKind::Thrown { value } => write!(f, "Uncaught exception: {}", value),
+            VmErrorKind::HandlerNotFound => write!(f, "No exception handler to remove"),
+            VmErrorKind::CoroutineRunning => write!(f, "Coroutine is already running"),
+            VmErrorKind::CoroutineFinished => write!(f, "Cannot resume a finished coroutine"),
+            VmErrorKind::CoroutineError { error } => write!(f, "Coroutine failed: {}", error.kind),
+            VmErrorKind::YieldOutsideCoroutine => write!(f, "Cannot yield outside of a coroutine"),
+            VmErrorKind::DuplicateSymbol { name } => write!(f, "Symbol is defined more than once: {}", name),
+            VmErrorKind::UnresolvedSymbol { name } => write!(f, "Unresolved symbol: {}", name),
+            VmErrorKind::UnsupportedConstant { type_name } => write!(f, "Cannot encode a {} constant", type_name),
+        }
+    }
+}
+
+impl Display for VmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.kind)?;
+        for entry in &self.backtrace {
+            write!(f, "\n    at {} (pc {})", entry.function, entry.pc)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for VmError {}
+
+/// Reasons why bytes could not be decoded into a program.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DecodeError {
+    InvalidMagic,
+    UnsupportedVersion {
+        version: u16,
+        supported: u16,
+    },
+    UnexpectedEnd {
+        offset: usize,
+    },
+    InvalidTag {
+        kind: &'static str,
+        tag: u8,
+        offset: usize,
+    },
+    InvalidUtf8 {
+        offset: usize,
+    },
+    NumberTooLarge {
+        offset: usize,
+    },
+    InvalidStringIndex {
+        index: usize,
+    },
+    InvalidConstantIndex {
+        index: usize,
+    },
+    InvalidLocalCount {
+        local_count: usize,
+        offset: usize,
+    },
+    TrailingBytes {
+        offset: usize,
+    },
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeError::InvalidMagic => write!(f, "Not an encoded program"),
+            DecodeError::UnsupportedVersion { version, supported } => write!(f, "Unsupported format version {}, expected {}", version, supported),
+            DecodeError::UnexpectedEnd { offset } => write!(f, "Unexpected end of data at offset {}", offset),
+            DecodeError::InvalidTag { kind, tag, offset } => write!(f, "Invalid {} tag {} at offset {}", kind, tag, offset),
+            DecodeError::InvalidUtf8 { offset } => write!(f, "Invalid UTF-8 string at offset {}", offset),
+            DecodeError::NumberTooLarge { offset } => write!(f, "Number too large at offset {}", offset),
+            DecodeError::InvalidStringIndex { index } => write!(f, "Invalid string table index: {}", index),
+            DecodeError::InvalidConstantIndex { index } => write!(f, "Invalid constant table index: {}", index),
+            DecodeError::InvalidLocalCount { local_count, offset } => write!(f, "Invalid local count {} at offset {}", local_count, offset),
+            DecodeError::TrailingBytes { offset } => write!(f, "Unexpected data after the program at offset {}", offset),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// An error in assembly source, with the line and column where it was found.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AssemblyError {
+    pub kind: AssemblyErrorKind,
+
+    // Line and column of the error, starting at 1
+    pub line: usize,
+    pub column: usize,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum AssemblyErrorKind {
+    UnexpectedCharacter {
+        character: char,
+    },
+    UnterminatedString,
+    InvalidEscape {
+        character: char,
+    },
+    UnknownDirective {
+        name: String,
+    },
+    UnexpectedDirective {
+        name: String,
+    },
+    UnknownOpcode {
+        name: String,
+    },
+    UndeclaredLocal {
+        name: String,
+    },
+    UndeclaredUpvalue {
+        name: String,
+    },
+    MissingLabel {
+        name: String,
+    },
+    DuplicateLabel {
+        name: String,
+    },
+    DuplicateFunction {
+        name: String,
+    },
+    InvalidOperand {
+        expected: &'static str,
+        found: String,
+    },
+    MissingOperand {
+        expected: &'static str,
+    },
+    UnexpectedOperand {
+        found: String,
+    },
+    OutsideFunction,
+    UnterminatedFunction {
+        name: String,
+    },
+    Build {
+        error: VmError,
+    },
+}
+
+impl Display for AssemblyErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AssemblyErrorKind::UnexpectedCharacter { character } => write!(f, "Unexpected character: {:?}", character),
+            AssemblyErrorKind::UnterminatedString => write!(f, "Unterminated string"),
+            AssemblyErrorKind::InvalidEscape { character } => write!(f, "Invalid escape sequence: \\{}", character),
+            AssemblyErrorKind::UnknownDirective { name } => write!(f, "Unknown directive: {}", name),
+            AssemblyErrorKind::UnexpectedDirective { name } => write!(f, "Directive not allowed here: {}", name),
+            AssemblyErrorKind::UnknownOpcode { name } => write!(f, "Unknown opcode: {}", name),
+            AssemblyErrorKind::UndeclaredLocal { name } => write!(f, "Undeclared local variable: {}", name),
+            AssemblyErrorKind::UndeclaredUpvalue { name } => write!(f, "Undeclared upvalue: {}", name),
+            AssemblyErrorKind::MissingLabel { name } => write!(f, "Label not found: {}", name),
+            AssemblyErrorKind::DuplicateLabel { name } => write!(f, "Label is defined more than once: {}", name),
+            AssemblyErrorKind::DuplicateFunction { name } => write!(f, "Function is defined more than once: {}", name),
+            AssemblyErrorKind::InvalidOperand { expected, found } => write!(f, "Expected {} but found {}", expected, found),
+            AssemblyErrorKind::MissingOperand { expected } => write!(f, "Missing {}", expected),
+            AssemblyErrorKind::UnexpectedOperand { found } => write!(f, "Unexpected operand: {}", found),
+            AssemblyErrorKind::OutsideFunction => write!(f, "Instruction outside of a function"),
+            AssemblyErrorKind::UnterminatedFunction { name } => write!(f, "Function {} has no .end", name),
+            AssemblyErrorKind::Build { error } => write!(f, "{}", error),
+        }
+    }
+}
+
+impl Display for AssemblyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.kind)
+    }
+}
+
+impl std::error::Error for AssemblyError {}
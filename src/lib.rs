@@ -1,15 +1,48 @@
+// Variant keys are hashed by value; interior mutability of arrays and dictionaries is accepted.
+#![allow(clippy::mutable_key_type)]
+
+mod error;
 mod variant;
 mod runtime;
 mod program;
 mod builder;
+mod native;
+mod verifier;
+mod linker;
+mod encoding;
+mod assembler;
+mod disassembler;
 
 pub mod prelude {
     pub use crate::builder::BlockEncoder;
     pub use crate::builder::ProgramBuilder;
     pub use crate::builder::FunctionBuilder;
+    pub use crate::error::AssemblyError;
+    pub use crate::error::AssemblyErrorKind;
+    pub use crate::error::DecodeError;
+    pub use crate::error::StackTraceEntry;
+    pub use crate::error::VmError;
+    pub use crate::error::VmErrorKind;
+    pub use crate::linker::Linker;
+    pub use crate::native::IntoNativeFunction;
+    pub use crate::native::NativeReturn;
+    pub use crate::program::CallTarget;
+    pub use crate::program::Function;
+    pub use crate::program::Global;
+    pub use crate::program::Import;
+    pub use crate::program::GlobalTarget;
+    pub use crate::program::Instruction;
     pub use crate::program::Program;
+    pub use crate::program::SymbolEntry;
+    pub use crate::runtime::Execution;
+    pub use crate::runtime::NativeContext;
+    pub use crate::runtime::RunOptions;
+    pub use crate::runtime::CancellationHandle;
     pub use crate::runtime::Vm;
-    pub use crate::runtime::VmError;
     pub use crate::runtime::VmExecutionResult;
+    pub use crate::runtime::VmLimits;
+    pub use crate::variant::Closure;
+    pub use crate::variant::Coroutine;
+    pub use crate::variant::CoroutineStatus;
     pub use crate::variant::Variant;
 }
\ No newline at end of file
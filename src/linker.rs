@@ -0,0 +1,83 @@
+use crate::error::{StackTraceEntry, VmError, VmErrorKind};
+use crate::program::{CallTarget, GlobalTarget, Program, SymbolEntry};
+
+/// Merges several programs into one, placing the functions and globals of each program after the previous ones.
+/// Every symbol may only be defined once and every function or global used by name must be defined by one of the programs.
+#[derive(Clone, Debug, Default)]
+pub struct Linker {
+    program: Program,
+}
+
+impl Linker {
+
+    /// Adds the functions, globals and native function declarations of a program.
+    pub fn add_program(&mut self, program: Program) -> Result<&mut Self, VmError> {
+
+        let mut program = program;
+        program.qualify_symbols();
+
+        for (name, entry) in &program.symbol_table {
+            self.check_symbol(name, entry)?;
+        }
+        if let Some(global) = program.globals.iter().find(|global| self.program.globals.iter().any(|existing| existing.name == global.name)) {
+            return Err(VmError::new(VmErrorKind::DuplicateSymbol { name: global.name.clone() }));
+        }
+
+        program.relocate(self.program.functions.len(), self.program.globals.len());
+
+        self.program.symbol_table.extend(program.symbol_table);
+        self.program.functions.extend(program.functions);
+        self.program.globals.extend(program.globals);
+        Ok(self)
+    }
+
+    /// Declares a native function that the programs call and that is registered with the VM before running.
+    pub fn declare_native_function(&mut self, name: &str, arity: usize) -> Result<&mut Self, VmError> {
+        let entry = SymbolEntry::NativeFunction { arity };
+        self.check_symbol(name, &entry)?;
+        self.program.symbol_table.insert(name.to_string(), entry);
+        Ok(self)
+    }
+
+    /// Resolves the references between the programs and returns the merged program.
+    pub fn link(self) -> Result<Program, VmError> {
+
+        let mut program = self.program;
+        program.resolve_references();
+
+        // Names that are left are neither functions nor globals of any program
+        for (function_index, function) in program.functions.iter_mut().enumerate() {
+            for (pc, instruction) in function.instructions.iter_mut().enumerate() {
+                let mut unresolved = match instruction.call_target_mut() {
+                    Some(CallTarget::Name(name)) if !program.symbol_table.contains_key(name) => Some(name.clone()),
+                    _ => None
+                };
+                if let Some(GlobalTarget::Name(name)) = instruction.global_target_mut() {
+                    unresolved = Some(name.clone());
+                }
+                if let Some(name) = unresolved {
+                    return Err(VmError {
+                        kind: VmErrorKind::UnresolvedSymbol { name },
+                        backtrace: vec![StackTraceEntry {
+                            function: function.name.clone(),
+                            function_index,
+                            pc
+                        }]
+                    });
+                }
+            }
+        }
+
+        Ok(program)
+    }
+
+    // Native functions may be declared by several programs as long as they agree on the arity
+    fn check_symbol(&self, name: &str, entry: &SymbolEntry) -> Result<(), VmError> {
+        match (self.program.symbol_table.get(name), entry) {
+            (None, _) => Ok(()),
+            (Some(SymbolEntry::NativeFunction { arity: existing }), SymbolEntry::NativeFunction { arity }) if existing == arity => Ok(()),
+            _ => Err(VmError::new(VmErrorKind::DuplicateSymbol { name: name.to_string() }))
+        }
+    }
+
+}
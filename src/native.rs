@@ -0,0 +1,123 @@
+use crate::error::{VmError, VmErrorKind};
+use crate::runtime::NativeContext;
+use crate::variant::Variant;
+
+/// Converts the return value of a typed native function into the value pushed onto the stack.
+pub trait NativeReturn {
+    fn into_native_result(self) -> Result<Option<Variant>, VmError>;
+}
+
+impl NativeReturn for () {
+    fn into_native_result(self) -> Result<Option<Variant>, VmError> {
+        Ok(None)
+    }
+}
+
+impl NativeReturn for Variant {
+    fn into_native_result(self) -> Result<Option<Variant>, VmError> {
+        Ok(Some(self))
+    }
+}
+
+impl<T: NativeReturn> NativeReturn for Option<T> {
+    fn into_native_result(self) -> Result<Option<Variant>, VmError> {
+        match self {
+            Some(value) => value.into_native_result(),
+            None => Ok(Some(Variant::Null))
+        }
+    }
+}
+
+impl<T: NativeReturn> NativeReturn for Result<T, VmError> {
+    fn into_native_result(self) -> Result<Option<Variant>, VmError> {
+        self.and_then(NativeReturn::into_native_result)
+    }
+}
+
+macro_rules! impl_native_return {
+    ($($ty:ty),*) => {
+        $(
+            impl NativeReturn for $ty {
+                fn into_native_result(self) -> Result<Option<Variant>, VmError> {
+                    Ok(Some(Variant::from(self)))
+                }
+            }
+        )*
+    };
+}
+
+impl_native_return!(i64, f64, String, &str, bool);
+
+// Sizes such as lengths are returned as integers so that bytecode can do arithmetic with them
+impl NativeReturn for usize {
+    fn into_native_result(self) -> Result<Option<Variant>, VmError> {
+        match i64::try_from(self) {
+            Ok(value) => Ok(Some(Variant::Integer(value))),
+            Err(_) => Err(VmErrorKind::IntegerOverflow { operator: "usize conversion" }.into())
+        }
+    }
+}
+
+/// Converts a Rust function or closure with typed arguments into a native function.
+/// Arguments are converted with `TryFrom<Variant>` and the arity is taken from the signature.
+pub trait IntoNativeFunction<Args> {
+
+    /// Number of arguments taken by the function.
+    fn arity(&self) -> usize;
+
+    /// Wraps the function so that it can be called with the raw arguments from the stack.
+    fn into_native_function(self) -> impl Fn(&mut NativeContext, Vec<Variant>) -> Result<Option<Variant>, VmError> + 'static;
+}
+
+fn convert_argument<T>(index: usize, value: Variant) -> Result<T, VmError>
+where
+    T: TryFrom<Variant>,
+    T::Error: Into<VmError>
+{
+    T::try_from(value).map_err(|error| {
+        let error = error.into();
+        match error.kind {
+            VmErrorKind::TypeError { expected, actual } => VmErrorKind::ArgumentTypeError { index, expected, actual }.into(),
+            _ => error
+        }
+    })
+}
+
+macro_rules! impl_into_native_function {
+    ($count:expr $(, $arg:ident)*) => {
+        impl<F, R $(, $arg)*> IntoNativeFunction<($($arg,)*)> for F
+        where
+            F: Fn($($arg),*) -> R + 'static,
+            R: NativeReturn,
+            $($arg: TryFrom<Variant>, <$arg as TryFrom<Variant>>::Error: Into<VmError>,)*
+        {
+            fn arity(&self) -> usize {
+                $count
+            }
+
+            #[allow(non_snake_case, unused_mut, unused_variables)]
+            fn into_native_function(self) -> impl Fn(&mut NativeContext, Vec<Variant>) -> Result<Option<Variant>, VmError> + 'static {
+                move |_, args| {
+                    let mut args = args.into_iter().enumerate();
+                    $(
+                        let $arg = match args.next() {
+                            Some((index, value)) => convert_argument::<$arg>(index, value)?,
+                            None => return Err(VmErrorKind::RuntimeError {
+                                message: format!("Expected {} arguments", $count)
+                            }.into())
+                        };
+                    )*
+                    self($($arg),*).into_native_result()
+                }
+            }
+        }
+    };
+}
+
+impl_into_native_function!(0);
+impl_into_native_function!(1, A1);
+impl_into_native_function!(2, A1, A2);
+impl_into_native_function!(3, A1, A2, A3);
+impl_into_native_function!(4, A1, A2, A3, A4);
+impl_into_native_function!(5, A1, A2, A3, A4, A5);
+impl_into_native_function!(6, A1, A2, A3, A4, A5, A6);
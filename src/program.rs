@@ -1,6 +1,8 @@
 use crate::variant::Variant;
 use std::collections::HashMap;
 use crate::builder::ProgramBuilder;
+use crate::{assembler, encoding};
+use crate::error::{AssemblyError, DecodeError, VmError};
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum Instruction {
@@ -8,6 +10,8 @@ pub enum Instruction {
     // Variables
     SetLocal(usize),
     GetLocal(usize),
+    SetGlobal(GlobalTarget),
+    GetGlobal(GlobalTarget),
 
     // Arrays
     CreateArray(usize),
@@ -23,7 +27,23 @@ pub enum Instruction {
 
     // Functions
     FunctionCall(CallTarget),
+
+    // Calls a function in place of the current one, reusing its frame
+    TailCall(CallTarget),
+
+    // Calls a function with the given number of arguments, for functions with optional or rest parameters
+    CallWithArgumentCount(CallTarget, usize),
+    GetArgumentCount,
+
+    // Pops a symbol reference or closure and calls it with the given number of arguments
+    CallIndirect(usize),
+
+    // Closures
+    MakeClosure(CallTarget, Vec<usize>),
+    GetUpvalue(usize),
+    SetUpvalue(usize),
     Return,
+    ReturnN(usize),
     EndFunction,
 
     // Stack operations
@@ -58,6 +78,17 @@ pub enum Instruction {
     Jump(usize),
     JumpIfFalse(usize),
 
+    // Coroutines
+    MakeCoroutine(usize),
+    Resume,
+    Yield,
+    IsCoroutineFinished,
+
+    // Exceptions
+    TryBegin(usize),
+    TryEnd,
+    Throw,
+
     // Output
     Print,
 
@@ -72,6 +103,42 @@ pub enum CallTarget {
     Index(usize)
 }
 
+impl From<&str> for CallTarget {
+    fn from(name: &str) -> Self {
+        CallTarget::Name(name.to_string())
+    }
+}
+
+impl From<String> for CallTarget {
+    fn from(name: String) -> Self {
+        CallTarget::Name(name)
+    }
+}
+
+impl From<usize> for CallTarget {
+    fn from(index: usize) -> Self {
+        CallTarget::Index(index)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum GlobalTarget {
+    Name(String),
+    Slot(usize)
+}
+
+impl From<&str> for GlobalTarget {
+    fn from(name: &str) -> Self {
+        GlobalTarget::Name(name.to_string())
+    }
+}
+
+impl From<usize> for GlobalTarget {
+    fn from(slot: usize) -> Self {
+        GlobalTarget::Slot(slot)
+    }
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub enum SymbolEntry {
     NativeFunction {
@@ -88,27 +155,253 @@ pub struct Function {
     // Name of the function
     pub name: String,
 
-    // Number of arguments
+    // Number of required arguments
     pub arity: usize,
 
+    // Number of optional arguments after the required ones, missing ones are null
+    pub optional_count: usize,
+
+    // Whether extra arguments are collected into an array in the slot after the parameters
+    pub variadic: bool,
+
     // Number of local variables
     pub local_count: usize,
 
+    // Number of values returned by every return of the function, if declared
+    pub return_count: Option<usize>,
+
     // List of instructions
     pub instructions: Vec<Instruction>
 
 }
 
+impl Function {
+
+    /// Returns true if the function can be called with the given number of arguments.
+    pub fn accepts_argument_count(&self, count: usize) -> bool {
+        count >= self.arity && (self.variadic || count <= self.arity.saturating_add(self.optional_count))
+    }
+
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Global {
+
+    // Name of the global variable
+    pub name: String,
+
+    // Value of the global when the program is loaded
+    pub initial_value: Variant
+
+}
+
+/// A function of another module that is called by its plain name in the importing module.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Import {
+
+    // Name of the module that exports the function
+    pub module: String,
+
+    // Name of the function in the exporting module
+    pub name: String
+
+}
+
+impl Import {
+
+    /// Parses an import path such as `math.sqrt`. The last segment names the function and the rest the module.
+    pub fn parse(path: &str) -> Option<Import> {
+        match path.rsplit_once('.') {
+            Some((module, name)) if !module.is_empty() && !name.is_empty() => Some(Import {
+                module: module.to_string(),
+                name: name.to_string()
+            }),
+            _ => None
+        }
+    }
+
+    /// Returns the name the imported function is registered with, such as `math::sqrt`.
+    pub fn qualified_name(&self) -> String {
+        qualified_name(&self.module, &self.name)
+    }
+
+}
+
+pub(crate) fn qualified_name(module: &str, name: &str) -> String {
+    format!("{}::{}", module, name)
+}
+
+// Name of a private function that only the module's own references resolve to, it can't be written as an assembler word
+pub(crate) fn private_name(module: &str, name: &str) -> String {
+    format!("{}::<{}>", module, name)
+}
+
+pub(crate) fn is_private_name(name: &str) -> bool {
+    name.contains("::<")
+}
+
+// Private functions are only reachable from the functions of their module, whose names carry the module as prefix
+pub(crate) fn is_reachable_from(name: &str, caller: &str) -> bool {
+    match name.split_once("::<") {
+        Some((module, _)) => caller.strip_prefix(module).is_some_and(|rest| rest.starts_with("::")),
+        None => true
+    }
+}
+
 #[derive(Clone, Default, Debug, PartialEq)]
 pub struct Program {
     pub symbol_table: HashMap<String, SymbolEntry>,
-    pub functions: Vec<Function>
+    pub functions: Vec<Function>,
+
+    // Global variables, indexed by slot
+    pub globals: Vec<Global>,
+
+    // Name of the module, its exported functions are registered as `module::function`
+    pub name: Option<String>,
+
+    // Functions of a named module that other modules and the host may call, the others are private
+    pub exports: Vec<String>,
+
+    // Functions of other modules that are called by their plain name
+    pub imports: Vec<Import>
+}
+
+impl Instruction {
+
+    /// Returns the function referenced by a call or closure instruction.
+    pub(crate) fn call_target_mut(&mut self) -> Option<&mut CallTarget> {
+        match self {
+            Instruction::FunctionCall(target)
+            | Instruction::CallWithArgumentCount(target, _)
+            | Instruction::TailCall(target)
+            | Instruction::MakeClosure(target, _) => Some(target),
+            _ => None
+        }
+    }
+
+    /// Returns the global variable referenced by a global instruction.
+    pub(crate) fn global_target_mut(&mut self) -> Option<&mut GlobalTarget> {
+        match self {
+            Instruction::GetGlobal(target) | Instruction::SetGlobal(target) => Some(target),
+            _ => None
+        }
+    }
+
 }
 
 impl Program {
 
+    /// Version of the binary encoding written by `to_bytes`.
+    pub const FORMAT_VERSION: u16 = encoding::FORMAT_VERSION;
+
     pub fn builder() -> ProgramBuilder {
         ProgramBuilder::default()
     }
-    
+
+    /// Encodes the program into the binary format, with a header, a string table and a constant table.
+    /// Fails if a constant only exists at runtime, such as a closure.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, VmError> {
+        encoding::encode(self)
+    }
+
+    /// Decodes a program from bytes written by `to_bytes`.
+    /// Bytes written with a different format version are rejected.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Program, DecodeError> {
+        encoding::decode(bytes)
+    }
+
+    /// Assembles a program from the textual assembly language, see the `.bvasm` files in the tests for examples.
+    /// Errors point at the line and column of the source where they were found.
+    /// Programs print themselves in this language, so the output of `to_string` can be assembled again.
+    pub fn from_assembly(source: &str) -> Result<Program, AssemblyError> {
+        assembler::assemble(source)
+    }
+
+    /// Replaces function names with their index and global names with their slot where the program defines them.
+    pub(crate) fn resolve_references(&mut self) {
+        for function in &mut self.functions {
+            for instruction in &mut function.instructions {
+                if let Some(target) = instruction.call_target_mut()
+                    && let CallTarget::Name(name) = target
+                    && let Some(SymbolEntry::UserDefinedFunction { index }) = self.symbol_table.get(name) {
+                    *target = CallTarget::Index(*index);
+                }
+                if let Some(target) = instruction.global_target_mut()
+                    && let GlobalTarget::Name(name) = target
+                    && let Some(slot) = self.globals.iter().position(|global| &global.name == name) {
+                    *target = GlobalTarget::Slot(slot);
+                }
+            }
+        }
+    }
+
+    /// Rewrites the symbols of the program so that it can be placed next to other modules.
+    /// Imported names are replaced with their qualified names. Exported functions and the globals of a named module are
+    /// registered with their qualified names, private functions with a hidden name that the module's references are rewritten to.
+    pub(crate) fn qualify_symbols(&mut self) {
+
+        let mut renames = self.imports.drain(..)
+            .map(|import| (import.name.clone(), import.qualified_name()))
+            .collect::<HashMap<_, _>>();
+
+        if let Some(module) = self.name.take() {
+            let exports = std::mem::take(&mut self.exports);
+            self.symbol_table = std::mem::take(&mut self.symbol_table).into_iter()
+                .map(|(name, entry)| match entry {
+                    SymbolEntry::UserDefinedFunction { .. } => {
+                        let qualified = match exports.contains(&name) {
+                            true => qualified_name(&module, &name),
+                            false => private_name(&module, &name)
+                        };
+                        renames.insert(name, qualified.clone());
+                        (qualified, entry)
+                    },
+                    SymbolEntry::NativeFunction { .. } => (name, entry)
+                })
+                .collect();
+            for function in &mut self.functions {
+                function.name = qualified_name(&module, &function.name);
+            }
+            for global in &mut self.globals {
+                global.name = qualified_name(&module, &global.name);
+            }
+        }
+
+        // Calls and function references by name that were not resolved to an index
+        for function in &mut self.functions {
+            for instruction in &mut function.instructions {
+                let name = match instruction {
+                    Instruction::Push(Variant::SymbolReference(name)) => name,
+                    _ => match instruction.call_target_mut() {
+                        Some(CallTarget::Name(name)) => name,
+                        _ => continue
+                    }
+                };
+                if let Some(qualified) = renames.get(name) {
+                    *name = qualified.clone();
+                }
+            }
+        }
+    }
+
+    /// Shifts function indices and global slots so that the program can be placed after others.
+    /// Indices that would overflow saturate to a value that the runtime rejects as invalid.
+    pub(crate) fn relocate(&mut self, function_offset: usize, global_offset: usize) {
+        for entry in self.symbol_table.values_mut() {
+            if let SymbolEntry::UserDefinedFunction { index } = entry {
+                *index = index.saturating_add(function_offset);
+            }
+        }
+        for function in &mut self.functions {
+            for instruction in &mut function.instructions {
+                if let Some(CallTarget::Index(index)) = instruction.call_target_mut() {
+                    *index = index.saturating_add(function_offset);
+                }
+                if let Some(GlobalTarget::Slot(slot)) = instruction.global_target_mut() {
+                    *slot = slot.saturating_add(global_offset);
+                }
+            }
+        }
+    }
+
 }
\ No newline at end of file
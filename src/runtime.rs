@@ -1,525 +1,1534 @@
-use crate::program::{Function, SymbolEntry, Instruction, Program, CallTarget};
-use crate::variant::Variant;
+use crate::error::{StackTraceEntry, VmError, VmErrorKind};
+use crate::native::IntoNativeFunction;
+use crate::program::{Function, SymbolEntry, Instruction, Program, CallTarget, GlobalTarget, is_private_name, is_reachable_from};
+use crate::variant::{Closure, Coroutine, CoroutineStatus, Variant};
 use log::{debug, trace};
 use std::cell::RefCell;
 use std::collections::HashMap;
 use std::rc::Rc;
-use std::time::Duration;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 
 macro_rules! runtime_error {
-    ($($arg:tt)*) => {
-        Err(VmError::RuntimeError {
-            message: format!($($arg)*)
-        })
-    };
-}
-
-macro_rules! stack_pop {
-    ($stack:expr) => {
-        $stack.pop().expect("Operand stack should not be empty")
+    ($kind:expr) => {
+        Err(VmError::new($kind))
     };
 }
 
 #[derive(Clone, Default, Debug, PartialEq)]
 pub struct VmExecutionResult {
+
+    // First value returned by the entry point
     pub result: Option<Variant>,
+
+    // All values returned by the entry point, more than one if it returned with `ReturnN`
+    pub results: Vec<Variant>,
+
     pub run_time: Duration,
+
+    // Execution state if the run was suspended before completing
+    pub suspended: Option<Execution>,
 }
 
-#[derive(Clone, Debug, PartialEq)]
-pub enum VmError {
-    RuntimeError {
-        message: String,
-    },
-    RuntimeWarning {
-        message: String,
+impl VmExecutionResult {
+
+    /// Returns true if the run ran out of fuel and can be resumed.
+    pub fn is_suspended(&self) -> bool {
+        self.suspended.is_some()
+    }
+
+}
+
+/// Options that control a single run of the VM.
+#[derive(Clone, Default, Debug, PartialEq)]
+pub struct RunOptions {
+
+    // Maximum number of instructions to execute before suspending
+    pub fuel: Option<u64>,
+
+    // Maximum wall-clock time of the run before it stops with a timeout
+    pub deadline: Option<Duration>,
+
+    // Handle that stops the run when cancelled from another thread
+    pub cancellation: Option<CancellationHandle>,
+
+}
+
+/// Shared flag that requests a running VM to stop.
+/// Clones share the same flag and can be sent to other threads.
+#[derive(Clone, Default, Debug)]
+pub struct CancellationHandle {
+    cancelled: Arc<AtomicBool>
+}
+
+impl CancellationHandle {
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Requests the run to stop at the next interruption check.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    /// Returns true if cancellation has been requested.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+}
+
+impl PartialEq for CancellationHandle {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.cancelled, &other.cancelled)
     }
 }
 
+/// Resource limits enforced by the VM while executing bytecode.
+/// Limits that are not set are unbounded.
+#[derive(Clone, Default, Debug, PartialEq)]
+pub struct VmLimits {
+
+    // Maximum number of active function calls, including calls made from native functions
+    pub max_call_depth: Option<usize>,
+
+    // Maximum number of values on the stack of an execution, including local variables
+    pub max_stack_size: Option<usize>,
+
+    // Maximum number of items in an array or entries in a dictionary
+    pub max_collection_length: Option<usize>,
+
+    // Approximate number of bytes that strings, arrays and dictionaries may allocate during a run
+    pub max_heap_bytes: Option<usize>,
+
+}
+
+// Number of instructions executed between checks of the deadline and the cancellation handle
+const INTERRUPT_CHECK_INTERVAL: u64 = 1024;
+
+// Number of dispatch loops that native callbacks and coroutines may nest
+// Each one takes tens of kilobytes of native stack in debug builds
+const MAX_NESTED_DISPATCH: usize = 16;
+
 #[derive(Clone, Default, Debug, PartialEq)]
 struct StackFrame {
     function_index: usize,
     pc: usize,
     stack_base_pointer: usize,
+    closure: Option<Rc<Closure>>,
+    argument_count: usize,
 }
 
+// Exception handler registered by a try region
 #[derive(Clone, Default, Debug, PartialEq)]
+struct ExceptionHandler {
+
+    // Number of frames below the function that registered the handler
+    frame_depth: usize,
+
+    // Stack length at the start of the try region
+    stack_height: usize,
+
+    // Address of the handler in the registering function
+    pc: usize,
+}
+
+/// The state of a running function that can be suspended and resumed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Execution {
+    frames: Vec<StackFrame>,
+    stack: Vec<Variant>,
+    handlers: Vec<ExceptionHandler>,
+    function_index: usize,
+    pc: usize,
+    stack_base_pointer: usize,
+    closure: Option<Rc<Closure>>,
+    argument_count: usize,
+
+    // Coroutines may yield, other executions run until they return
+    coroutine: bool,
+}
+
+impl Execution {
+
+    // Starts a call of the function with its arguments already bound on the stack
+    fn new(function_index: usize, frames: Vec<StackFrame>, stack: Vec<Variant>, closure: Option<Rc<Closure>>, argument_count: usize, coroutine: bool) -> Execution {
+        Execution {
+            frames,
+            stack,
+            handlers: Vec::new(),
+            function_index,
+            pc: 0,
+            stack_base_pointer: 0,
+            closure,
+            argument_count,
+            coroutine
+        }
+    }
+
+}
+
+// Function resolved from a symbol name at a call site
+enum Callee {
+    Native {
+        name: String,
+        arity: usize
+    },
+    Function(usize),
+    Closure(Rc<Closure>),
+}
+
+enum Completion {
+    Returned(Vec<Variant>),
+    Suspended,
+    Yielded(Variant),
+}
+
+/// Places the arguments of a call starting at the base pointer into the function's parameter slots.
+/// Missing optional parameters are null and extra arguments are collected into the rest parameter.
+fn bind_arguments(function: &Function, stack: &mut Vec<Variant>, stack_base_pointer: usize) {
+    let frame_end = stack_base_pointer.saturating_add(function.local_count);
+    if function.variadic {
+
+        // Parameters past the function's locals are dropped with the rest of the arguments
+        let rest_slot = stack_base_pointer.saturating_add(function.arity).saturating_add(function.optional_count).min(frame_end);
+        let rest = if stack.len() > rest_slot {
+            stack.split_off(rest_slot)
+        } else {
+            stack.resize(rest_slot, Variant::Null);
+            Vec::new()
+        };
+        stack.push(Variant::Array(Rc::new(RefCell::new(rest))));
+    }
+    stack.resize(frame_end, Variant::Null);
+}
+
+/// Makes room for the locals of a function called at the base pointer.
+/// Fails if the stack would grow past the limit or its memory can't be allocated.
+fn reserve_frame(function: &Function, stack: &mut Vec<Variant>, stack_base_pointer: usize, max_stack_size: usize) -> Result<(), VmErrorKind> {
+    match stack_base_pointer.checked_add(function.local_count) {
+        Some(frame_end) if frame_end <= max_stack_size && stack.try_reserve(frame_end.saturating_sub(stack.len())).is_ok() => Ok(()),
+        _ => Err(VmErrorKind::StackOverflow { limit: max_stack_size })
+    }
+}
+
+/// Removes the exception handlers of the functions at or above the frame depth when they return or are replaced.
+fn release_handlers(handlers: &mut Vec<ExceptionHandler>, frame_depth: usize) {
+    while handlers.last().is_some_and(|handler| handler.frame_depth >= frame_depth) {
+        handlers.pop();
+    }
+}
+
+fn arity_mismatch(function: &Function, actual: usize) -> VmErrorKind {
+    let expected = if actual < function.arity {
+        function.arity
+    } else {
+        function.arity.saturating_add(function.optional_count)
+    };
+    VmErrorKind::ArityMismatch {
+        function: function.name.clone(),
+        expected,
+        actual
+    }
+}
+
+type NativeFunction = Rc<dyn Fn(&mut NativeContext, Vec<Variant>) -> Result<Option<Variant>, VmError>>;
+
+/// Handle to the running VM that is passed to native functions.
+pub struct NativeContext<'a> {
+    vm: &'a mut Vm
+}
+
+impl NativeContext<'_> {
+
+    /// Calls a user defined function by name or function index from inside a native function.
+    /// Nested calls share the remaining fuel of the run. A native function's frame can't be suspended,
+    /// so running out of fuel here fails with `OutOfFuel` and stops the whole run.
+    pub fn call(&mut self, target: impl Into<CallTarget>, arguments: Vec<Variant>) -> Result<Option<Variant>, VmError> {
+        let function_index = self.vm.resolve_call_target(target.into(), &arguments)?;
+        if let Some(limit) = self.vm.limits.max_call_depth && self.vm.call_depth >= limit {
+            return runtime_error!(VmErrorKind::CallDepthExceeded { limit });
+        }
+        if self.vm.nested_dispatch >= MAX_NESTED_DISPATCH {
+            return runtime_error!(VmErrorKind::CallDepthExceeded { limit: MAX_NESTED_DISPATCH });
+        }
+        self.vm.nested_dispatch += 1;
+        let result = self.vm.execute(function_index, arguments);
+        self.vm.nested_dispatch -= 1;
+        result
+    }
+
+    /// Resolves a user defined function name to its function index.
+    pub fn resolve_function(&self, name: &str) -> Option<usize> {
+        self.vm.resolve_function(name)
+    }
+
+}
+
+#[derive(Clone, Default)]
 pub struct Vm {
     functions: Vec<Function>,
     symbols: HashMap<String, SymbolEntry>,
-    native_functions: HashMap<String, fn(Vec<Variant>) -> Option<Variant>>
+    native_functions: HashMap<String, NativeFunction>,
+    globals: Vec<Variant>,
+    global_names: HashMap<String, usize>,
+    frames: Vec<StackFrame>,
+    stack: Vec<Variant>,
+    fuel: u64,
+    deadline: Option<Instant>,
+    cancellation: Option<CancellationHandle>,
+    limits: VmLimits,
+
+    // Active calls of the executions that are waiting on a native function
+    call_depth: usize,
+
+    // Dispatch loops running below the current one for native callbacks and coroutines
+    nested_dispatch: usize,
+
+    // Approximate bytes allocated by the current run
+    heap_bytes: usize
+}
+
+impl std::fmt::Debug for Vm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Vm")
+            .field("functions", &self.functions)
+            .field("symbols", &self.symbols)
+            .field("native_functions", &self.native_functions.keys().collect::<Vec<_>>())
+            .field("globals", &self.global_names)
+            .finish()
+    }
+}
+
+// Native functions can't be compared, so they are equal when both VMs share the same closures
+impl PartialEq for Vm {
+    fn eq(&self, other: &Self) -> bool {
+        self.functions == other.functions
+            && self.symbols == other.symbols
+            && self.globals == other.globals
+            && self.global_names == other.global_names
+            && self.limits == other.limits
+            && self.native_functions.len() == other.native_functions.len()
+            && self.native_functions.iter().all(|(name, function)| {
+                other.native_functions.get(name).is_some_and(|other| Rc::ptr_eq(function, other))
+            })
+    }
 }
 
 impl Vm {
 
-    pub fn register_native_function(&mut self, name: String, arity: usize, function: fn(Vec<Variant>) -> Option<Variant>) {
-        self.native_functions.insert(name.clone(), function);
+    /// Sets the resource limits used by subsequent runs.
+    pub fn set_limits(&mut self, limits: VmLimits) {
+        self.limits = limits;
+    }
+
+    pub fn limits(&self) -> &VmLimits {
+        &self.limits
+    }
+
+    /// Registers a native function. The function may capture host state, call back
+    /// into bytecode through the context and returns an error to abort execution.
+    /// Natives are `Fn` rather than `FnMut` since they may be called again by the bytecode they call,
+    /// so mutable state goes in a `Cell` or `RefCell`. Clones of the VM share the registered closures.
+    pub fn register_native_function<F>(&mut self, name: String, arity: usize, function: F)
+    where
+        F: Fn(&mut NativeContext, Vec<Variant>) -> Result<Option<Variant>, VmError> + 'static
+    {
+        self.native_functions.insert(name.clone(), Rc::new(function));
         self.symbols.insert(name.clone(), SymbolEntry::NativeFunction {
             arity
         });
     }
     
+    /// Registers a Rust function with typed arguments as a native function.
+    /// The arity is taken from the signature and arguments are converted with `TryFrom<Variant>`.
+    pub fn register_fn<Args>(&mut self, name: &str, function: impl IntoNativeFunction<Args>) {
+        let arity = function.arity();
+        self.register_native_function(name.to_string(), arity, function.into_native_function());
+    }
+
+    /// Adds the functions, symbols and globals of a program after the ones already loaded.
+    /// Exported functions of a named module are registered as `module::function`, its private functions are only reachable from the module.
+    /// Symbols defined again replace the earlier definitions, use a Linker to detect conflicts.
     pub fn load_program(&mut self, program: Program) {
 
         debug!("Loaded program");
         trace!("Globals: {:?}", program.symbol_table);
         trace!("Functions: {:?}", program.functions);
 
+        // Function indices and global slots of the program start after the ones already loaded
+        let mut program = program;
+        program.qualify_symbols();
+        program.relocate(self.functions.len(), self.globals.len());
+
         self.functions.extend(program.functions);
-        self.symbols.extend(program.symbol_table.into_iter());
+        self.symbols.extend(program.symbol_table);
+
+        for global in program.globals {
+            self.global_names.insert(global.name, self.globals.len());
+            self.globals.push(global.initial_value);
+        }
+    }
+
+    /// Returns the current value of a global variable.
+    pub fn get_global(&self, name: &str) -> Option<&Variant> {
+        self.global_names.get(name).and_then(|slot| self.globals.get(*slot))
+    }
+
+    /// Sets the value of a global variable declared by a loaded program.
+    pub fn set_global(&mut self, name: &str, value: impl Into<Variant>) -> Result<(), VmError> {
+        let slot = self.resolve_global(&GlobalTarget::Name(name.to_string()))?;
+        self.globals[slot] = value.into();
+        Ok(())
+    }
+
+    /// Resolves a function name used by the function at the caller index, private functions of other modules are not found.
+    fn resolve_callee(&self, name: &str, caller: usize) -> Result<Callee, VmErrorKind> {
+        if !is_reachable_from(name, &self.functions[caller].name) {
+            return Err(VmErrorKind::FunctionNotFound { name: name.to_string() });
+        }
+        match self.symbols.get(name) {
+            Some(SymbolEntry::NativeFunction { arity }) if self.native_functions.contains_key(name) => Ok(Callee::Native {
+                name: name.to_string(),
+                arity: *arity
+            }),
+            Some(SymbolEntry::UserDefinedFunction { index }) => Ok(Callee::Function(*index)),
+            _ => Err(VmErrorKind::FunctionNotFound { name: name.to_string() })
+        }
+    }
+
+    fn resolve_target_callee(&self, target: &CallTarget, caller: usize) -> Result<Callee, VmErrorKind> {
+        match target {
+            CallTarget::Index(index) => Ok(Callee::Function(*index)),
+            CallTarget::Name(name) => self.resolve_callee(name, caller)
+        }
+    }
+
+    fn resolve_global(&self, target: &GlobalTarget) -> Result<usize, VmErrorKind> {
+        match target {
+            GlobalTarget::Slot(slot) if *slot < self.globals.len() => Ok(*slot),
+            GlobalTarget::Slot(slot) => Err(VmErrorKind::InvalidGlobal { slot: *slot }),
+            GlobalTarget::Name(name) => match self.global_names.get(name) {
+                Some(slot) => Ok(*slot),
+                None => Err(VmErrorKind::GlobalNotFound { name: name.clone() })
+            }
+        }
+    }
+
+    /// Resolves a user defined function name to its function index.
+    /// The index can be passed to `call` to skip the symbol lookup on every call. Private functions of modules are not resolved.
+    pub fn resolve_function(&self, name: &str) -> Option<usize> {
+        match self.symbols.get(name) {
+            Some(SymbolEntry::UserDefinedFunction { index }) if *index < self.functions.len() && !is_private_name(name) => Some(*index),
+            _ => None
+        }
     }
 
     /// Executes the program with the given entry point and parameters.
     /// If no entry point is provided, it defaults to "main".
-    pub fn run(&mut self, entry_point: Option<String>, _parameters: Option<Vec<Variant>>) -> Result<VmExecutionResult, VmError> {
-        
-        let timer = std::time::Instant::now();
+    /// Parameters are placed into the entry function's argument slots and must match its arity.
+    /// Without parameters the arity is not checked and the arguments start as null.
+    pub fn run(&mut self, entry_point: Option<String>, parameters: Option<Vec<Variant>>) -> Result<VmExecutionResult, VmError> {
+        self.run_with_options(entry_point, parameters, RunOptions::default())
+    }
 
-        // frames
-        let frames = &mut Vec::with_capacity(32);
+    /// Executes the program like `run` with the given options.
+    /// If the fuel runs out the result is suspended and can be passed to `resume`,
+    /// unless it runs out inside a native function which stops the run with `OutOfFuel`.
+    pub fn run_with_options(&mut self, entry_point: Option<String>, parameters: Option<Vec<Variant>>, options: RunOptions) -> Result<VmExecutionResult, VmError> {
 
-        // stack
-        let mut stack = Vec::with_capacity(1024);
-        
         // use entry point or default to main
         let entry_point = entry_point.unwrap_or_else(|| String::from("main"));
 
         // Get the function to execute
-        let mut function_index = match self.symbols.get(entry_point.as_str()) {
-            Some(SymbolEntry::UserDefinedFunction { index, .. }) => {
+        let function_index = match self.symbols.get(entry_point.as_str()) {
+            Some(SymbolEntry::UserDefinedFunction { index, .. }) if !is_private_name(&entry_point) => {
                 match self.functions.get(*index) {
                     Some(_) => *index,
-                    None => return runtime_error!("Function not found: {}", entry_point)
+                    None => return runtime_error!(VmErrorKind::FunctionNotFound { name: entry_point })
                 }
             },
             Some(SymbolEntry::NativeFunction { .. }) => {
-                return runtime_error!("Cannot execute native function as entry point: {}", entry_point);
+                return runtime_error!(VmErrorKind::InvalidEntryPoint { name: entry_point });
+            },
+            _ => return runtime_error!(VmErrorKind::FunctionNotFound { name: entry_point })
+        };
+
+        // Check the parameters against the function's arity
+        let parameters = match parameters {
+            Some(parameters) => {
+                let function = &self.functions[function_index];
+                if !function.accepts_argument_count(parameters.len()) {
+                    return runtime_error!(arity_mismatch(function, parameters.len()));
+                }
+                parameters
+            },
+            None => Vec::new()
+        };
+
+        self.heap_bytes = 0;
+        let execution = self.prepare(function_index, parameters)?;
+        self.resume(execution, options)
+    }
+
+    /// Continues a suspended execution with the given options.
+    pub fn resume(&mut self, execution: Execution, options: RunOptions) -> Result<VmExecutionResult, VmError> {
+
+        // The execution may come from another Vm, its functions must exist in this one
+        let function_indices = std::iter::once(execution.function_index).chain(execution.frames.iter().map(|frame| frame.function_index));
+        for index in function_indices {
+            if index >= self.functions.len() {
+                return runtime_error!(VmErrorKind::InvalidFunctionIndex { index });
+            }
+        }
+
+        let timer = Instant::now();
+        self.fuel = options.fuel.unwrap_or(u64::MAX);
+        self.deadline = options.deadline.map(|deadline| timer + deadline);
+        self.cancellation = options.cancellation;
+
+        let mut execution = execution;
+        match self.dispatch(&mut execution, true)? {
+            Completion::Returned(results) => {
+                self.recycle(execution);
+                Ok(VmExecutionResult {
+                    result: results.first().cloned(),
+                    results,
+                    run_time: timer.elapsed(),
+                    suspended: None
+                })
+            },
+            Completion::Suspended => Ok(VmExecutionResult {
+                run_time: timer.elapsed(),
+                suspended: Some(execution),
+                ..Default::default()
+            }),
+            Completion::Yielded(_) => unreachable!("Only coroutines yield")
+        }
+
+    }
+
+    /// Calls a user defined function by name or function index with the given arguments.
+    /// The operand stack and frames are reused between calls. Only the first of several returned values is kept.
+    pub fn call(&mut self, target: impl Into<CallTarget>, arguments: Vec<Variant>) -> Result<Option<Variant>, VmError> {
+        let function_index = self.resolve_call_target(target.into(), &arguments)?;
+        self.fuel = u64::MAX;
+        self.deadline = None;
+        self.cancellation = None;
+        self.heap_bytes = 0;
+        self.execute(function_index, arguments)
+    }
+
+    fn resolve_call_target(&self, target: CallTarget, arguments: &[Variant]) -> Result<usize, VmError> {
+
+        let function_index = match target {
+            CallTarget::Index(index) if index < self.functions.len() => index,
+            CallTarget::Index(index) => return runtime_error!(VmErrorKind::InvalidFunctionIndex { index }),
+            CallTarget::Name(name) => match self.resolve_function(name.as_str()) {
+                Some(index) => index,
+                None => return runtime_error!(VmErrorKind::FunctionNotFound { name })
+            }
+        };
+
+        let function = &self.functions[function_index];
+        if !function.accepts_argument_count(arguments.len()) {
+            return runtime_error!(arity_mismatch(function, arguments.len()));
+        }
+
+        Ok(function_index)
+    }
+
+    /// Runs the function at the given index until it returns from its outermost frame.
+    fn execute(&mut self, function_index: usize, arguments: Vec<Variant>) -> Result<Option<Variant>, VmError> {
+
+        let mut execution = self.prepare(function_index, arguments)?;
+
+        match self.dispatch(&mut execution, false)? {
+            Completion::Returned(results) => {
+                self.recycle(execution);
+                Ok(results.into_iter().next())
             },
-            _ => return runtime_error!("Entry point not found: {}", entry_point)
+            Completion::Suspended => unreachable!("Nested executions do not suspend"),
+            Completion::Yielded(_) => unreachable!("Only coroutines yield")
+        }
+    }
+
+    /// Creates the execution state for calling a function, reusing the buffers of the previous execution.
+    /// Nested calls from native functions take empty buffers and get their own.
+    fn prepare(&mut self, function_index: usize, arguments: Vec<Variant>) -> Result<Execution, VmError> {
+
+        let mut frames = std::mem::take(&mut self.frames);
+        frames.clear();
+        let mut stack = std::mem::take(&mut self.stack);
+        stack.clear();
+
+        let function = &self.functions[function_index];
+        if let Err(error) = reserve_frame(function, &mut stack, 0, self.limits.max_stack_size.unwrap_or(usize::MAX)) {
+            return runtime_error!(error);
+        }
+
+        // Place the arguments into the function's argument slots and initialize the local variables
+        let argument_count = arguments.len();
+        stack.extend(arguments);
+        bind_arguments(function, &mut stack, 0);
+
+        Ok(Execution::new(function_index, frames, stack, None, argument_count, false))
+    }
+
+    /// Returns the reason to stop the run if the deadline has passed or cancellation was requested.
+    fn interruption(&self) -> Option<VmErrorKind> {
+        if let Some(cancellation) = &self.cancellation && cancellation.is_cancelled() {
+            return Some(VmErrorKind::Cancelled);
+        }
+        if let Some(deadline) = self.deadline && Instant::now() >= deadline {
+            return Some(VmErrorKind::Timeout);
+        }
+        None
+    }
+
+    /// Checks a newly created string, array or dictionary against the collection length and heap limits.
+    fn track_allocation(&mut self, value: &Variant) -> Result<(), VmErrorKind> {
+
+        let (length, bytes) = match value {
+            Variant::String(string) => (None, string.len()),
+            Variant::Array(array) => {
+                let length = array.borrow().len();
+                (Some(length), length.saturating_mul(std::mem::size_of::<Variant>()))
+            },
+            Variant::Dictionary(table) => {
+                let length = table.borrow().len();
+                (Some(length), length.saturating_mul(2 * std::mem::size_of::<Variant>()))
+            },
+            _ => return Ok(())
         };
 
-        // Initialize the function's local variables
-        stack.resize(self.functions[function_index].local_count, Variant::Null);
+        if let Some(length) = length && let Some(limit) = self.limits.max_collection_length && length > limit {
+            return Err(VmErrorKind::CollectionTooLarge { length, limit });
+        }
+
+        self.heap_bytes = self.heap_bytes.saturating_add(bytes);
+        if let Some(limit) = self.limits.max_heap_bytes && self.heap_bytes > limit {
+            return Err(VmErrorKind::HeapLimitExceeded { limit });
+        }
+
+        Ok(())
+    }
+
+    /// Keeps the buffers of a finished execution for the next one.
+    fn recycle(&mut self, execution: Execution) {
+        self.frames = execution.frames;
+        self.stack = execution.stack;
+    }
+
+    fn dispatch(&mut self, execution: &mut Execution, suspendable: bool) -> Result<Completion, VmError> {
+
+        let frames = &mut execution.frames;
+        let stack = &mut execution.stack;
+        let handlers = &mut execution.handlers;
+        let mut function_index = execution.function_index;
+        let mut pc = execution.pc;
+        let mut stack_base_pointer = execution.stack_base_pointer;
+
+        // Closure of the current function, holding the variables it captured
+        let mut closure = execution.closure.take();
+
+        // Number of arguments passed to the current function
+        let mut argument_count = execution.argument_count;
+
+        // Fuel is taken from the run's budget in slices, interruptions are checked between slices
+        let mut fuel = 0;
+
+        let max_stack_size = self.limits.max_stack_size.unwrap_or(usize::MAX);
+        let max_call_depth = self.limits.max_call_depth.unwrap_or(usize::MAX);
 
-        // Initialize the stack frame
-        let mut pc = 0;
-        let mut stack_base_pointer = 0;
-        
         debug!("Starting execution of function: {}", self.functions[function_index].name);
-        let mut result = None;
+        let mut results = Vec::new();
+
+        let error = 'dispatch: {
+
+            'execution: loop  {
+
+                // Errors resume at the nearest exception handler that catches them
+                // Otherwise they break out of the dispatch loop so that the location can be attached
+                macro_rules! fail {
+                    ($error:expr) => {{
+                        let error = $error;
+                        if error.is_catchable() && let Some(handler) = handlers.pop() {
+
+                            // Unwind to the function that registered the handler
+                            let frame = frames.drain(handler.frame_depth..).next();
+                            if let Some(frame) = frame {
+                                stack_base_pointer = frame.stack_base_pointer;
+                                function_index = frame.function_index;
+                                closure = frame.closure;
+                                argument_count = frame.argument_count;
+                            }
+
+                            stack.truncate(handler.stack_height);
+                            stack.push(error.exception_value());
+                            pc = handler.pc;
+                            continue 'execution;
+                        }
+                        break 'dispatch error
+                    }};
+                }
 
-        loop  {
+                macro_rules! check {
+                    ($result:expr) => {
+                        match $result {
+                            Ok(value) => value,
+                            Err(error) => fail!(error)
+                        }
+                    };
+                }
+
+                // Pushes a value that grows the stack, checked against the stack size limit
+                macro_rules! stack_push {
+                    ($stack:expr, $value:expr) => {{
+                        if $stack.len() >= max_stack_size {
+                            fail!(VmErrorKind::StackOverflow { limit: max_stack_size });
+                        }
+                        $stack.push($value)
+                    }};
+                }
+
+                macro_rules! stack_pop {
+                    ($stack:expr) => {
+                        match $stack.pop() {
+                            Some(value) => value,
+                            None => fail!(VmErrorKind::StackUnderflow)
+                        }
+                    };
+                }
+
+                // Moves the top values of the stack to the calling function or finishes the execution
+                macro_rules! return_from_function {
+                    ($count:expr, $local_count:expr) => {{
+                        let count = $count;
+                        match stack_base_pointer.checked_add($local_count).and_then(|operands_start| operands_start.checked_add(count)) {
+                            Some(needed) if needed <= stack.len() => {},
+                            _ => fail!(VmErrorKind::StackUnderflow)
+                        }
+                        release_handlers(handlers, frames.len());
+
+                        match frames.pop() {
+                            Some(parent_frame) => {
+
+                                // Move the values down to where the arguments of the returning function started
+                                stack.drain(stack_base_pointer..stack.len() - count);
+
+                                pc = parent_frame.pc;
+                                stack_base_pointer = parent_frame.stack_base_pointer;
+                                function_index = parent_frame.function_index;
+                                closure = parent_frame.closure;
+                                argument_count = parent_frame.argument_count;
+                                continue;
+                            },
+                            None => {
+                                results = stack.split_off(stack.len() - count);
+                                break;
+                            }
+                        }
+                    }};
+                }
             
-            // trace!("========================================");
-            // trace!("Frame[{}]: Stack: {:?}", frames.len(), stack);
-            // trace!("Frame[{}]: Base pointer: {}", frames.len(), stack_base_pointer);
-            // trace!("Frame[{}]: Local Count: {}", frames.len(), self.functions[function_index].local_count);
-            // trace!("Frame[{}]: Locals: {:?}", frames.len(), &stack[stack_base_pointer .. stack_base_pointer + self.functions[function_index].local_count]);
-            // trace!("Frame[{}]: Operands: {:?}", frames.len(), &stack[stack_base_pointer + self.functions[function_index].local_count..]);
-
-            let Some(instruction) = self.functions[function_index].instructions.get(pc) else {
-                // debug!("Frame[{}]: Instructions {:?}", frames.len(), self.functions[function_index].instructions);
-                return runtime_error!("Program counter out of bounds: {} >= {}", pc, self.functions[function_index].instructions.len());
-            };
+                // trace!("========================================");
+                // trace!("Frame[{}]: Stack: {:?}", frames.len(), stack);
+                // trace!("Frame[{}]: Base pointer: {}", frames.len(), stack_base_pointer);
+                // trace!("Frame[{}]: Local Count: {}", frames.len(), self.functions[function_index].local_count);
+                // trace!("Frame[{}]: Locals: {:?}", frames.len(), &stack[stack_base_pointer .. stack_base_pointer + self.functions[function_index].local_count]);
+                // trace!("Frame[{}]: Operands: {:?}", frames.len(), &stack[stack_base_pointer + self.functions[function_index].local_count..]);
+
+                if fuel == 0 {
+
+                    // Suspend or stop when the fuel runs out
+                    if self.fuel == 0 {
+                        if !suspendable {
+                            fail!(VmErrorKind::OutOfFuel);
+                        }
+                        execution.function_index = function_index;
+                        execution.pc = pc;
+                        execution.stack_base_pointer = stack_base_pointer;
+                        execution.closure = closure;
+                        execution.argument_count = argument_count;
+                        return Ok(Completion::Suspended);
+                    }
+
+                    if let Some(interruption) = self.interruption() {
+                        fail!(interruption);
+                    }
+
+                    fuel = self.fuel.min(INTERRUPT_CHECK_INTERVAL);
+                    self.fuel -= fuel;
+                }
+                fuel -= 1;
+
+                let Some(instruction) = self.functions[function_index].instructions.get(pc) else {
+                    // debug!("Frame[{}]: Instructions {:?}", frames.len(), self.functions[function_index].instructions);
+                    fail!(VmErrorKind::InvalidProgramCounter);
+                };
             
-            // trace!("Frame[{}]: Executing instruction[{}]: {:?}", frames.len(), pc, instruction);
+                // trace!("Frame[{}]: Executing instruction[{}]: {:?}", frames.len(), pc, instruction);
             
-            match instruction {
-
-                // Operands
-
-                Instruction::Push(value) => {
-                    stack.push(value.clone());
-                    pc += 1;
-                },
-
-                // Local variables
-
-                Instruction::SetLocal(index) => {
-                    let value = stack_pop!(stack);
-                    let variable_index = stack_base_pointer + *index;
-                    let stack_len = stack.len();
-                    stack.get_mut(variable_index)
-                        .expect(format!("Local variable index out of bounds: {} >= {}", variable_index, stack_len).as_str())
-                        .clone_from(&value);
-                    pc += 1;
-                },
-
-                Instruction::GetLocal(index) => {
-                    let value = stack[stack_base_pointer + *index].clone();
-                    stack.push(value);
-                    pc += 1;
-                },
-
-                // Jump instructions
-
-                Instruction::Jump(address) => {
-                    pc = *address;
-                },
-
-                Instruction::JumpIfFalse(address) => {
-                    let condition = stack_pop!(stack);
-                    if condition.is_false() {
+                match instruction {
+
+                    // Operands
+
+                    Instruction::Push(value) => {
+                        stack_push!(stack, value.clone());
+                        pc += 1;
+                    },
+
+                    // Local variables
+
+                    Instruction::SetLocal(index) => {
+                        let value = stack_pop!(stack);
+                        match stack.get_mut(stack_base_pointer.saturating_add(*index)) {
+                            // Captured locals are shared with closures and written through
+                            Some(Variant::Upvalue(upvalue)) => *upvalue.borrow_mut() = value,
+                            Some(variable) => *variable = value,
+                            None => fail!(VmErrorKind::InvalidLocal { index: *index })
+                        }
+                        pc += 1;
+                    },
+
+                    Instruction::GetLocal(index) => {
+                        let value = match stack.get(stack_base_pointer.saturating_add(*index)) {
+                            Some(Variant::Upvalue(upvalue)) => upvalue.borrow().clone(),
+                            Some(value) => value.clone(),
+                            None => fail!(VmErrorKind::InvalidLocal { index: *index })
+                        };
+                        stack_push!(stack, value);
+                        pc += 1;
+                    },
+
+                    // Closures
+
+                    Instruction::MakeClosure(target, captures) => {
+                        let closure_function_index = match target {
+                            CallTarget::Index(index) => *index,
+                            CallTarget::Name(name) => match self.resolve_callee(name, function_index) {
+                                Ok(Callee::Function(index)) => index,
+                                _ => fail!(VmErrorKind::FunctionNotFound { name: name.clone() })
+                            }
+                        };
+                        if closure_function_index >= self.functions.len() {
+                            fail!(VmErrorKind::InvalidFunctionIndex { index: closure_function_index });
+                        }
+
+                        // Move captured locals into shared storage so that the frame and the closure see the same variable
+                        let mut upvalues = Vec::with_capacity(captures.len());
+                        for index in captures {
+                            let upvalue = match stack.get_mut(stack_base_pointer.saturating_add(*index)) {
+                                Some(Variant::Upvalue(upvalue)) => upvalue.clone(),
+                                Some(variable) => {
+                                    let upvalue = Rc::new(RefCell::new(std::mem::replace(variable, Variant::Null)));
+                                    *variable = Variant::Upvalue(upvalue.clone());
+                                    upvalue
+                                },
+                                None => fail!(VmErrorKind::InvalidLocal { index: *index })
+                            };
+                            upvalues.push(upvalue);
+                        }
+
+                        stack_push!(stack, Variant::Closure(Rc::new(Closure {
+                            function_index: closure_function_index,
+                            upvalues
+                        })));
+                        pc += 1;
+                    },
+
+                    Instruction::GetUpvalue(index) => {
+                        let Some(upvalue) = closure.as_ref().and_then(|closure| closure.upvalues.get(*index)) else {
+                            fail!(VmErrorKind::InvalidUpvalue { index: *index });
+                        };
+                        let value = upvalue.borrow().clone();
+                        stack_push!(stack, value);
+                        pc += 1;
+                    },
+
+                    Instruction::SetUpvalue(index) => {
+                        let value = stack_pop!(stack);
+                        let Some(upvalue) = closure.as_ref().and_then(|closure| closure.upvalues.get(*index)) else {
+                            fail!(VmErrorKind::InvalidUpvalue { index: *index });
+                        };
+                        *upvalue.borrow_mut() = value;
+                        pc += 1;
+                    },
+
+                    // Global variables
+
+                    Instruction::SetGlobal(target) => {
+                        let slot = check!(self.resolve_global(target));
+                        self.globals[slot] = stack_pop!(stack);
+                        pc += 1;
+                    },
+
+                    Instruction::GetGlobal(target) => {
+                        let slot = check!(self.resolve_global(target));
+                        stack_push!(stack, self.globals[slot].clone());
+                        pc += 1;
+                    },
+
+                    // Jump instructions
+
+                    Instruction::Jump(address) => {
                         pc = *address;
-                    } else {
+                    },
+
+                    Instruction::JumpIfFalse(address) => {
+                        let condition = stack_pop!(stack);
+                        if condition.is_false() {
+                            pc = *address;
+                        } else {
+                            pc += 1;
+                        }
+                    },
+
+                    // Binary Operations
+
+                    Instruction::Add => {
+                        let b = stack_pop!(stack);
+                        let a = stack_pop!(stack);
+                        let value = check!(a + b);
+                        check!(self.track_allocation(&value));
+                        stack.push(value);
                         pc += 1;
-                    }
-                },
-
-                // Binary Operations
-
-                Instruction::Add => {
-                    let b = stack_pop!(stack);
-                    let a = stack_pop!(stack);
-                    stack.push(a + b);
-                    pc += 1;
-                },
-
-                Instruction::Sub => {
-                    let b = stack_pop!(stack);
-                    let a = stack_pop!(stack);
-                    stack.push(a - b);
-                    pc += 1;
-                },
-
-                Instruction::Mul => {
-                    let b = stack_pop!(stack);
-                    let a = stack_pop!(stack);
-                    stack.push(a * b);
-                    pc += 1;
-                },
-
-                Instruction::Div => {
-                    let b = stack_pop!(stack);
-                    let a = stack_pop!(stack);
-                    stack.push(a / b);
-                    pc += 1;
-                },
-
-                Instruction::Mod => {
-                    let b = stack_pop!(stack);
-                    let a = stack_pop!(stack);
-                    stack.push(a % b);
-                    pc += 1;
-                },
-
-                Instruction::Pow => {
-                    let b = stack_pop!(stack);
-                    let a = stack_pop!(stack);
-                    stack.push(a.pow(&b));
-                    pc += 1;
-                },
-
-                // Unary Operations
-
-                Instruction::Equal => {
-                    let b = stack_pop!(stack);
-                    let a = stack_pop!(stack);
-                    stack.push(Variant::Boolean(a == b));
-                    pc += 1;
-                },
-
-                Instruction::GreaterThan => {
-                    let b = stack_pop!(stack);
-                    let a = stack_pop!(stack);
-                    stack.push(Variant::Boolean(a > b));
-                    pc += 1;
-                }
+                    },
+
+                    Instruction::Sub => {
+                        let b = stack_pop!(stack);
+                        let a = stack_pop!(stack);
+                        stack.push(check!(a - b));
+                        pc += 1;
+                    },
+
+                    Instruction::Mul => {
+                        let b = stack_pop!(stack);
+                        let a = stack_pop!(stack);
+                        stack.push(check!(a * b));
+                        pc += 1;
+                    },
 
-                Instruction::LessThan => {
-                    let b = stack_pop!(stack);
-                    let a = stack_pop!(stack);
-                    stack.push(Variant::Boolean(a < b));
-                    pc += 1;
-                },
-
-                Instruction::LessEqual => {
-                    let b = stack_pop!(stack);
-                    let a = stack_pop!(stack);
-                    stack.push(Variant::Boolean(a <= b));
-                    pc += 1;
-                },
-
-                Instruction::GreaterEqual => {
-                    let b = stack_pop!(stack);
-                    let a = stack_pop!(stack);
-                    stack.push(Variant::Boolean(a >= b));
-                    pc += 1;
-                },
-
-                Instruction::NotEqual => {
-                    let b = stack_pop!(stack);
-                    let a = stack_pop!(stack);
-                    stack.push(Variant::Boolean(a != b));
-                    pc += 1;
-                },
-
-                Instruction::Or => {
-                    let b = stack_pop!(stack).into();
-                    let a = stack_pop!(stack).into();
-                    stack.push(Variant::Boolean(a || b));
-                    pc += 1;
-                },
-
-                Instruction::And => {
-                    let b = stack_pop!(stack).into();
-                    let a = stack_pop!(stack).into();
-                    stack.push(Variant::Boolean(a && b));
-                    pc += 1;
-                },
-
-                Instruction::Not => {
-                    let a = stack_pop!(stack);
-                    stack.push(!a);
-                    pc += 1;
-                },
-
-                Instruction::Negate => {
-                    let a = stack_pop!(stack);
-                    stack.push(-a);
-                    pc += 1;
-                },
-
-                // Arrays
-
-                Instruction::CreateArray(size) => {
-                    let mut array = Vec::with_capacity(*size);
-                    for _ in 0..*size {
-                        array.push(stack_pop!(stack));
+                    Instruction::Div => {
+                        let b = stack_pop!(stack);
+                        let a = stack_pop!(stack);
+                        stack.push(check!(a / b));
+                        pc += 1;
+                    },
+
+                    Instruction::Mod => {
+                        let b = stack_pop!(stack);
+                        let a = stack_pop!(stack);
+                        stack.push(check!(a % b));
+                        pc += 1;
+                    },
+
+                    Instruction::Pow => {
+                        let b = stack_pop!(stack);
+                        let a = stack_pop!(stack);
+                        stack.push(check!(a.pow(&b)));
+                        pc += 1;
+                    },
+
+                    // Unary Operations
+
+                    Instruction::Equal => {
+                        let b = stack_pop!(stack);
+                        let a = stack_pop!(stack);
+                        stack.push(Variant::Boolean(a == b));
+                        pc += 1;
+                    },
+
+                    Instruction::GreaterThan => {
+                        let b = stack_pop!(stack);
+                        let a = stack_pop!(stack);
+                        stack.push(Variant::Boolean(a > b));
+                        pc += 1;
                     }
-                    array.reverse();
-                    stack.push(Variant::Array(Rc::new(RefCell::new(array))));
-                    pc += 1;
-                },
 
-                Instruction::GetArrayItem => {
+                    Instruction::LessThan => {
+                        let b = stack_pop!(stack);
+                        let a = stack_pop!(stack);
+                        stack.push(Variant::Boolean(a < b));
+                        pc += 1;
+                    },
 
-                    let index = match stack_pop!(stack) {
-                        Variant::Index(index) => index,
-                        v => return runtime_error!("Expected an index but got {:?}", v)
-                    };
+                    Instruction::LessEqual => {
+                        let b = stack_pop!(stack);
+                        let a = stack_pop!(stack);
+                        stack.push(Variant::Boolean(a <= b));
+                        pc += 1;
+                    },
 
-                    let array = stack_pop!(stack);
-                    let value = match array {
-                        Variant::Array(array) => {
-                            let array = array.borrow();
-                            let index: usize = index;
-                            match array.get(index) {
-                                Some(value) => value.clone(),
-                                None => return runtime_error!("Array index out of bounds: {} >= {}", index, array.len())
-                            }
-                        },
-                        _ => return runtime_error!("Expected an array but got {:?}", array)
-                    };
-                    stack.push(value);
-                    pc += 1;
-                }
+                    Instruction::GreaterEqual => {
+                        let b = stack_pop!(stack);
+                        let a = stack_pop!(stack);
+                        stack.push(Variant::Boolean(a >= b));
+                        pc += 1;
+                    },
 
-                Instruction::SetArrayItem => {
+                    Instruction::NotEqual => {
+                        let b = stack_pop!(stack);
+                        let a = stack_pop!(stack);
+                        stack.push(Variant::Boolean(a != b));
+                        pc += 1;
+                    },
 
-                    let value = stack_pop!(stack);
+                    Instruction::Or => {
+                        let b = !stack_pop!(stack).is_false();
+                        let a = !stack_pop!(stack).is_false();
+                        stack.push(Variant::Boolean(a || b));
+                        pc += 1;
+                    },
 
-                    let index = match stack_pop!(stack) {
-                        Variant::Index(index) => index,
-                        v => return runtime_error!("Expected an index but got {:?}", v)
-                    };
+                    Instruction::And => {
+                        let b = !stack_pop!(stack).is_false();
+                        let a = !stack_pop!(stack).is_false();
+                        stack.push(Variant::Boolean(a && b));
+                        pc += 1;
+                    },
+
+                    Instruction::Not => {
+                        let a = stack_pop!(stack);
+                        stack.push(check!(!a));
+                        pc += 1;
+                    },
+
+                    Instruction::Negate => {
+                        let a = stack_pop!(stack);
+                        stack.push(check!(-a));
+                        pc += 1;
+                    },
+
+                    // Arrays
 
-                    let varray = stack_pop!(stack);
-                    match varray {
-                        Variant::Array(ref array) => {
-                            let mut array = array.borrow_mut();
-                            let index: usize = index;
-                            array[index] = value;
-                            stack.push(varray.clone());
-                        },
-                        _ => return runtime_error!("Expected an array but got {:?}", varray)
+                    Instruction::CreateArray(size) => {
+                        if *size > stack.len() {
+                            fail!(VmErrorKind::StackUnderflow);
+                        }
+                        if let Some(limit) = self.limits.max_collection_length && *size > limit {
+                            fail!(VmErrorKind::CollectionTooLarge { length: *size, limit });
+                        }
+                        let array = Variant::Array(Rc::new(RefCell::new(stack.split_off(stack.len() - *size))));
+                        check!(self.track_allocation(&array));
+                        stack_push!(stack, array);
+                        pc += 1;
+                    },
+
+                    Instruction::GetArrayItem => {
+
+                        let index = match stack_pop!(stack) {
+                            Variant::Index(index) => index,
+                            v => fail!(VmErrorKind::TypeError { expected: "index", actual: v.type_name() })
+                        };
+
+                        let array = stack_pop!(stack);
+                        let value = match array {
+                            Variant::Array(array) => {
+                                let array = array.borrow();
+                                let index: usize = index;
+                                match array.get(index) {
+                                    Some(value) => value.clone(),
+                                    None => fail!(VmErrorKind::IndexOutOfBounds { index, length: array.len() })
+                                }
+                            },
+                            _ => fail!(VmErrorKind::TypeError { expected: "array", actual: array.type_name() })
+                        };
+                        stack.push(value);
+                        pc += 1;
                     }
-                    pc += 1;
-                },
-
-                Instruction::GetArrayLength => {
-                    let array = stack_pop!(stack);
-                    let length = match array {
-                        Variant::Array(array) => {
-                            let array = array.borrow();
-                            array.len()
-                        },
-                        _ => return runtime_error!("Expected an array but got {:?}", array)
-                    };
-                    stack.push(Variant::Integer(length as i64));
-                    pc += 1;
-                },
 
-                // Dictionaries
+                    Instruction::SetArrayItem => {
 
-                Instruction::CreateDictionary(size) => {
-                    let mut table = HashMap::new();
-                    for _ in 0..*size {
                         let value = stack_pop!(stack);
+
+                        let index = match stack_pop!(stack) {
+                            Variant::Index(index) => index,
+                            v => fail!(VmErrorKind::TypeError { expected: "index", actual: v.type_name() })
+                        };
+
+                        let varray = stack_pop!(stack);
+                        match varray {
+                            Variant::Array(ref array) => {
+                                let mut array = array.borrow_mut();
+                                let length = array.len();
+                                match array.get_mut(index) {
+                                    Some(item) => *item = value,
+                                    None => fail!(VmErrorKind::IndexOutOfBounds { index, length })
+                                }
+                                stack.push(varray.clone());
+                            },
+                            _ => fail!(VmErrorKind::TypeError { expected: "array", actual: varray.type_name() })
+                        }
+                        pc += 1;
+                    },
+
+                    Instruction::GetArrayLength => {
+                        let array = stack_pop!(stack);
+                        let length = match array {
+                            Variant::Array(array) => {
+                                let array = array.borrow();
+                                array.len()
+                            },
+                            _ => fail!(VmErrorKind::TypeError { expected: "array", actual: array.type_name() })
+                        };
+                        stack.push(Variant::Integer(length as i64));
+                        pc += 1;
+                    },
+
+                    // Dictionaries
+
+                    Instruction::CreateDictionary(size) => {
+                        if size.saturating_mul(2) > stack.len() {
+                            fail!(VmErrorKind::StackUnderflow);
+                        }
+                        if let Some(limit) = self.limits.max_collection_length && *size > limit {
+                            fail!(VmErrorKind::CollectionTooLarge { length: *size, limit });
+                        }
+                        let mut table = HashMap::with_capacity(*size);
+                        for _ in 0..*size {
+                            let value = stack_pop!(stack);
+                            let key = stack_pop!(stack);
+                            table.insert(key, value);
+                        }
+                        let table = Variant::Dictionary(Rc::new(RefCell::new(table)));
+                        check!(self.track_allocation(&table));
+                        stack_push!(stack, table);
+                        pc += 1;
+                    },
+
+                    Instruction::GetDictionaryItem => {
                         let key = stack_pop!(stack);
-                        table.insert(key, value);
+                        let table = stack_pop!(stack);
+                        let value = match table {
+                            Variant::Dictionary(table) => {
+                                let table = table.borrow();
+                                match table.get(&key) {
+                                    Some(value) => value.clone(),
+                                    None => fail!(VmErrorKind::KeyNotFound { key })
+                                }
+                            },
+                            _ => fail!(VmErrorKind::TypeError { expected: "dictionary", actual: table.type_name() })
+                        };
+                        stack.push(value);
+                        pc += 1;
                     }
-                    stack.push(Variant::Dictionary(Rc::new(RefCell::new(table))));
-                    pc += 1;
-                },
-
-                Instruction::GetDictionaryItem => {
-                    let key = stack_pop!(stack);
-                    let table = stack_pop!(stack);
-                    let value = match table {
-                        Variant::Dictionary(table) => {
-                            let table = table.borrow();
-                            match table.get(&key) {
-                                Some(value) => value.clone(),
-                                None => return runtime_error!("Dictionary key not found: {:?}", key)
+
+                    Instruction::SetDictionaryItem => {
+                        let value = stack_pop!(stack);
+                        let key = stack_pop!(stack);
+                        let table = stack_pop!(stack);
+                        match table {
+                            Variant::Dictionary(table) => {
+                                // Hashing the dictionary as its own key would borrow it while it is being modified
+                                if let Variant::Dictionary(key) = &key && Rc::ptr_eq(key, &table) {
+                                    fail!(VmErrorKind::RuntimeError { message: String::from("Dictionary cannot be used as its own key") });
+                                }
+                                let mut table = table.borrow_mut();
+                                if !table.contains_key(&key) {
+                                    if let Some(limit) = self.limits.max_collection_length && table.len() >= limit {
+                                        fail!(VmErrorKind::CollectionTooLarge { length: table.len() + 1, limit });
+                                    }
+                                    self.heap_bytes = self.heap_bytes.saturating_add(2 * std::mem::size_of::<Variant>());
+                                    if let Some(limit) = self.limits.max_heap_bytes && self.heap_bytes > limit {
+                                        fail!(VmErrorKind::HeapLimitExceeded { limit });
+                                    }
+                                }
+                                table.insert(key, value);
+                            },
+                            _ => fail!(VmErrorKind::TypeError { expected: "dictionary", actual: table.type_name() })
+                        }
+                        pc += 1;
+                    },
+
+                    Instruction::GetDictionaryKeys => {
+                        let table = stack_pop!(stack);
+                        let keys = match table {
+                            Variant::Dictionary(table) => {
+                                let table = table.borrow();
+                                table.keys().cloned().collect::<Vec<Variant>>()
+                            },
+                            _ => fail!(VmErrorKind::TypeError { expected: "dictionary", actual: table.type_name() })
+                        };
+                        let keys = Variant::Array(Rc::new(RefCell::new(keys)));
+                        check!(self.track_allocation(&keys));
+                        stack.push(keys);
+                        pc += 1;
+                    },
+
+                    Instruction::Pop => {
+                        stack_pop!(stack);
+                        pc += 1;
+                    },
+
+                    // Function calls
+
+                    Instruction::FunctionCall(_) | Instruction::CallWithArgumentCount(_, _) | Instruction::CallIndirect(_) | Instruction::TailCall(_) => {
+
+                        // Tail calls replace the current function instead of returning to it
+                        let tail_call = matches!(instruction, Instruction::TailCall(_));
+
+                        // Plain calls pass as many arguments as the callee's arity
+                        let (callee, call_argument_count) = match instruction {
+                            Instruction::FunctionCall(target) | Instruction::TailCall(target) => (check!(self.resolve_target_callee(target, function_index)), None),
+                            Instruction::CallWithArgumentCount(target, count) => (check!(self.resolve_target_callee(target, function_index)), Some(*count)),
+                            Instruction::CallIndirect(count) => {
+                                let count = *count;
+                                let callee = match stack_pop!(stack) {
+                                    Variant::SymbolReference(name) => check!(self.resolve_callee(&name, function_index)),
+                                    Variant::Closure(closure) => Callee::Closure(closure),
+                                    value => fail!(VmErrorKind::TypeError { expected: "function", actual: value.type_name() })
+                                };
+                                (callee, Some(count))
+                            },
+                            _ => unreachable!("Only call instructions reach this arm")
+                        };
+
+                        // Arguments must be operands of the caller and not its local variables
+                        let operand_count = stack.len().saturating_sub(stack_base_pointer.saturating_add(self.functions[function_index].local_count));
+
+                        let (next_function_index, next_closure) = match callee {
+                            Callee::Function(index) => (index, None),
+                            Callee::Closure(closure) => (closure.function_index, Some(closure)),
+                            Callee::Native { name, arity } => {
+                                let func = match self.native_functions.get(name.as_str()) {
+                                    Some(func) => func.clone(),
+                                    None => fail!(VmErrorKind::FunctionNotFound { name })
+                                };
+
+                                let count = call_argument_count.unwrap_or(arity);
+                                if count != arity {
+                                    fail!(VmErrorKind::ArityMismatch { function: name, expected: arity, actual: count });
+                                }
+                                if arity > operand_count {
+                                    fail!(VmErrorKind::ArityMismatch { function: name, expected: arity, actual: operand_count });
+                                }
+
+                                // Share the remaining fuel with nested calls made by the native function
+                                let args = stack.split_off(stack.len() - arity);
+                                self.fuel += fuel;
+                                fuel = 0;
+                                let call_depth = frames.len() + 1;
+                                self.call_depth += call_depth;
+                                let native_result = func(&mut NativeContext { vm: self }, args);
+                                self.call_depth -= call_depth;
+
+                                let result_count = match native_result {
+                                    Ok(Some(value)) => {
+                                        stack_push!(stack, value);
+                                        1
+                                    },
+                                    Ok(None) => 0,
+
+                                    // Limits and interruptions in nested calls stop the whole run and are not reported as a failed native function
+                                    // Fuel can't suspend across the native frame, so running out of it stops the run as well
+                                    Err(error) if error.kind.is_limit() => fail!(error.kind),
+                                    Err(error) => fail!(VmErrorKind::NativeFunctionError {
+                                        name,
+                                        error: Box::new(error)
+                                    })
+                                };
+
+                                // Native functions have no frame to reuse, return their result instead
+                                if tail_call {
+                                    return_from_function!(result_count, self.functions[function_index].local_count);
+                                }
+                                pc += 1;
+                                continue;
                             }
-                        },
-                        _ => return runtime_error!("Expected an dictionary but got {:?}", table)
-                    };
-                    stack.push(value);
-                    pc += 1;
-                }
+                        };
 
-                Instruction::SetDictionaryItem => {
-                    let value = stack_pop!(stack);
-                    let key = stack_pop!(stack);
-                    let table = stack_pop!(stack);
-                    match table {
-                        Variant::Dictionary(table) => {
-                            let mut table = table.borrow_mut();
-                            table.insert(key, value);
-                        },
-                        _ => return runtime_error!("Expected an dictionary but got {:?}", table)
-                    }
-                    pc += 1;
-                },
-
-                Instruction::GetDictionaryKeys => {
-                    let table = stack_pop!(stack);
-                    let keys = match table {
-                        Variant::Dictionary(table) => {
-                            let table = table.borrow();
-                            table.keys().cloned().collect::<Vec<Variant>>()
-                        },
-                        _ => return runtime_error!("Expected an dictionary but got {:?}", table)
-                    };
-                    stack.push(Variant::Array(Rc::new(RefCell::new(keys))));
-                    pc += 1;
-                },
+                        let Some(next_function) = self.functions.get(next_function_index) else {
+                            fail!(VmErrorKind::InvalidFunctionIndex { index: next_function_index });
+                        };
 
-                Instruction::Pop => {
-                    stack_pop!(stack);
-                    pc += 1;
-                },
+                        let count = call_argument_count.unwrap_or(next_function.arity);
+                        if !next_function.accepts_argument_count(count) {
+                            fail!(arity_mismatch(next_function, count));
+                        }
+                        if count > operand_count {
+                            fail!(arity_mismatch(next_function, operand_count));
+                        }
 
-                // Function calls
+                        if tail_call {
 
-                Instruction::FunctionCall(target) => {
+                            check!(reserve_frame(next_function, stack, stack_base_pointer, max_stack_size));
 
-                    let next_function_index = match target {
-                        CallTarget::Index(index) => *index,
-                        CallTarget::Name(name) if self.native_functions.contains_key(name) => {
-                            let arity = match self.symbols.get(name.as_str()) {
-                                Some(SymbolEntry::NativeFunction { arity }) => *arity,
-                                _ => return runtime_error!("Native function not found: {}", name)
-                            };
-                            let func = match self.native_functions.get(name.as_str()) {
-                                Some(func) => func,
-                                None => return runtime_error!("Native function not found: {}", name)
-                            };
-                            
-                            let args = stack.drain(stack.len() - arity..).collect::<Vec<_>>();
-                            if let Some(value) = func(args) {
-                                stack.push(value);
-                            }
-                            pc += 1;
+                            // Move the arguments over the current function's locals and reuse its frame
+                            release_handlers(handlers, frames.len());
+                            let arguments_start = stack.len() - count;
+                            stack.drain(stack_base_pointer..arguments_start);
+                            bind_arguments(next_function, stack, stack_base_pointer);
+
+                            pc = 0;
+                            function_index = next_function_index;
+                            closure = next_closure;
+                            argument_count = count;
                             continue;
                         }
-                        CallTarget::Name(name) => {
-                            // User defined function
-                            match self.symbols.get(name.as_str()) {
-                                Some(SymbolEntry::UserDefinedFunction { index, .. }) => *index,
-                                _ => return runtime_error!("Function not found: {}", name)
-                            }
-                        },
-                    };
 
-                    // Remember the current function frame
-                    frames.push(StackFrame {
-                        function_index,
-                        pc: pc + 1,
-                        stack_base_pointer
-                    });
+                        // The current function and the called function are active in addition to the frames
+                        if self.call_depth + frames.len() + 2 > max_call_depth {
+                            fail!(VmErrorKind::CallDepthExceeded { limit: max_call_depth });
+                        }
 
-                    // Create a new stack frame for the function call
-                    pc = 0;
+                        check!(reserve_frame(next_function, stack, stack.len() - count, max_stack_size));
 
-                    // Set the stack base pointer to the current stack length and include the function's arity
-                    stack_base_pointer = stack.len() - self.functions[next_function_index].arity;
+                        // Remember the current function frame
+                        frames.push(StackFrame {
+                            function_index,
+                            pc: pc + 1,
+                            stack_base_pointer,
+                            closure: std::mem::replace(&mut closure, next_closure),
+                            argument_count
+                        });
 
-                    // Extend the stack with the arguments
-                    stack.resize(stack_base_pointer + self.functions[next_function_index].local_count, Variant::Null);
+                        // Create a new stack frame for the function call
+                        pc = 0;
 
-                    // Update the current function to the next function
-                    function_index = next_function_index;
+                        // Set the stack base pointer to the first argument
+                        stack_base_pointer = stack.len() - count;
+                        argument_count = count;
 
-                },
+                        // Fill in the parameters and local variables
+                        bind_arguments(next_function, stack, stack_base_pointer);
 
-                Instruction::Return => {
-                    let Some(returning_value) = stack.pop() else {
-                        return runtime_error!("Return instruction without value");
-                    };
+                        // Update the current function to the next function
+                        function_index = next_function_index;
 
-                    if let Some(parent_frame) = frames.pop() {
+                    },
 
-                        stack.resize(stack_base_pointer, Variant::Null);
+                    Instruction::GetArgumentCount => {
+                        stack_push!(stack, Variant::Integer(argument_count as i64));
+                        pc += 1;
+                    },
 
-                        pc = parent_frame.pc;
-                        stack_base_pointer = parent_frame.stack_base_pointer;
+                    Instruction::Return => {
+                        return_from_function!(1, self.functions[function_index].local_count);
+                    }
 
-                        stack.push(returning_value);
-                        function_index = parent_frame.function_index;
-                    } else {
-                        result = Some(returning_value);
-                        break;
+                    Instruction::ReturnN(count) => {
+                        return_from_function!(*count, self.functions[function_index].local_count);
                     }
-                }
 
-                Instruction::EndFunction => {
-                    if let Some(parent_frame) = frames.pop() {
-                        debug!("Returning from function {}", self.functions[function_index].name);
-                        stack.resize(stack_base_pointer, Variant::Null);
-                        pc = parent_frame.pc;
-                        stack_base_pointer = parent_frame.stack_base_pointer;
-                        function_index = parent_frame.function_index;
-                    } else {
-                        break;
+                    Instruction::EndFunction => {
+                        return_from_function!(0, self.functions[function_index].local_count);
                     }
-                }
 
-                // Output
-                Instruction::Print => {
-                    let value = stack_pop!(stack);
-                    println!("{}", value);
-                    pc += 1;
-                },
+                    // Coroutines
+
+                    Instruction::MakeCoroutine(count) => {
+                        let count = *count;
+                        let (next_function_index, next_closure) = match stack_pop!(stack) {
+                            Variant::SymbolReference(name) => match check!(self.resolve_callee(&name, function_index)) {
+                                Callee::Function(index) => (index, None),
+                                _ => fail!(VmErrorKind::TypeError { expected: "user defined function", actual: "native function" })
+                            },
+                            Variant::Closure(closure) => (closure.function_index, Some(closure)),
+                            value => fail!(VmErrorKind::TypeError { expected: "function", actual: value.type_name() })
+                        };
+
+                        let Some(next_function) = self.functions.get(next_function_index) else {
+                            fail!(VmErrorKind::InvalidFunctionIndex { index: next_function_index });
+                        };
+                        if !next_function.accepts_argument_count(count) {
+                            fail!(arity_mismatch(next_function, count));
+                        }
+                        let operand_count = stack.len().saturating_sub(stack_base_pointer.saturating_add(self.functions[function_index].local_count));
+                        if count > operand_count {
+                            fail!(arity_mismatch(next_function, operand_count));
+                        }
 
-                Instruction::Halt => {
-                    break;
-                },
+                        // The coroutine starts with its arguments on its own stack
+                        let mut coroutine_stack = Vec::new();
+                        check!(reserve_frame(next_function, &mut coroutine_stack, 0, max_stack_size));
+                        coroutine_stack.extend(stack.drain(stack.len() - count..));
+                        bind_arguments(next_function, &mut coroutine_stack, 0);
+
+                        let coroutine = Variant::Coroutine(Rc::new(RefCell::new(Coroutine {
+                            status: CoroutineStatus::Suspended,
+                            execution: Some(Execution::new(next_function_index, Vec::new(), coroutine_stack, next_closure, count, true))
+                        })));
+                        stack_push!(stack, coroutine);
+                        pc += 1;
+                    },
 
-                Instruction::Panic => {
-                    let value = stack_pop!(stack);
-                    return runtime_error!("Panic: {}", value);
-                },
+                    Instruction::Resume => {
+                        let coroutine = match stack_pop!(stack) {
+                            Variant::Coroutine(coroutine) => coroutine,
+                            value => fail!(VmErrorKind::TypeError { expected: "coroutine", actual: value.type_name() })
+                        };
 
-            }
+                        // The coroutine's function is active in addition to the current function and the frames
+                        if self.call_depth + frames.len() + 2 > max_call_depth {
+                            fail!(VmErrorKind::CallDepthExceeded { limit: max_call_depth });
+                        }
+                        if self.nested_dispatch >= MAX_NESTED_DISPATCH {
+                            fail!(VmErrorKind::CallDepthExceeded { limit: MAX_NESTED_DISPATCH });
+                        }
+
+                        // Take the state out so that the coroutine can inspect its own status while running
+                        let mut coroutine_execution = {
+                            let mut coroutine = coroutine.borrow_mut();
+                            match coroutine.status {
+                                CoroutineStatus::Suspended => {},
+                                CoroutineStatus::Running => fail!(VmErrorKind::CoroutineRunning),
+                                CoroutineStatus::Finished => fail!(VmErrorKind::CoroutineFinished)
+                            }
+                            let Some(execution) = coroutine.execution.take() else {
+                                fail!(VmErrorKind::CoroutineFinished);
+                            };
+                            coroutine.status = CoroutineStatus::Running;
+                            execution
+                        };
+
+                        // The coroutine runs on the remaining fuel and counts towards the call depth
+                        self.fuel += fuel;
+                        fuel = 0;
+                        let call_depth = frames.len() + 1;
+                        self.call_depth += call_depth;
+                        self.nested_dispatch += 1;
+                        let completion = self.dispatch(&mut coroutine_execution, suspendable);
+                        self.nested_dispatch -= 1;
+                        self.call_depth -= call_depth;
+
+                        let mut state = coroutine.borrow_mut();
+                        let value = match completion {
+                            Ok(Completion::Yielded(value)) => {
+                                state.status = CoroutineStatus::Suspended;
+                                state.execution = Some(coroutine_execution);
+                                value
+                            },
+                            Ok(Completion::Returned(results)) => {
+                                state.status = CoroutineStatus::Finished;
+                                results.into_iter().next().unwrap_or(Variant::Null)
+                            },
+                            Ok(Completion::Suspended) => {
+
+                                // The fuel ran out inside the coroutine, suspend the run so that the resume is repeated
+                                state.status = CoroutineStatus::Suspended;
+                                state.execution = Some(coroutine_execution);
+                                drop(state);
+                                stack.push(Variant::Coroutine(coroutine));
+                                execution.function_index = function_index;
+                                execution.pc = pc;
+                                execution.stack_base_pointer = stack_base_pointer;
+                                execution.closure = closure;
+                                execution.argument_count = argument_count;
+                                return Ok(Completion::Suspended);
+                            },
+                            Err(error) => {
+                                state.status = CoroutineStatus::Finished;
+
+                                // Limits and interruptions stop the whole run and are not reported as a failed coroutine
+                                if error.kind.is_limit() {
+                                    fail!(error.kind);
+                                }
+                                fail!(VmErrorKind::CoroutineError { error: Box::new(error) })
+                            }
+                        };
+                        drop(state);
+
+                        stack_push!(stack, value);
+                        pc += 1;
+                    },
+
+                    Instruction::Yield => {
+                        if !execution.coroutine {
+                            fail!(VmErrorKind::YieldOutsideCoroutine);
+                        }
+                        let value = stack_pop!(stack);
+
+                        // Keep the state to continue after the yield on the next resume
+                        self.fuel += fuel;
+                        execution.function_index = function_index;
+                        execution.pc = pc + 1;
+                        execution.stack_base_pointer = stack_base_pointer;
+                        execution.closure = closure;
+                        execution.argument_count = argument_count;
+                        return Ok(Completion::Yielded(value));
+                    },
+
+                    Instruction::IsCoroutineFinished => {
+                        let finished = match stack_pop!(stack) {
+                            Variant::Coroutine(coroutine) => coroutine.borrow().status == CoroutineStatus::Finished,
+                            value => fail!(VmErrorKind::TypeError { expected: "coroutine", actual: value.type_name() })
+                        };
+                        stack_push!(stack, Variant::Boolean(finished));
+                        pc += 1;
+                    },
 
+                    // Exceptions
+
+                    Instruction::TryBegin(address) => {
+                        handlers.push(ExceptionHandler {
+                            frame_depth: frames.len(),
+                            stack_height: stack.len(),
+                            pc: *address
+                        });
+                        pc += 1;
+                    },
+
+                    Instruction::TryEnd => {
+                        if handlers.last().is_none_or(|handler| handler.frame_depth != frames.len()) {
+                            fail!(VmErrorKind::HandlerNotFound);
+                        }
+                        handlers.pop();
+                        pc += 1;
+                    },
+
+                    Instruction::Throw => {
+                        let value = stack_pop!(stack);
+                        fail!(VmErrorKind::Thrown { value });
+                    },
+
+                    // Output
+                    Instruction::Print => {
+                        let value = stack_pop!(stack);
+                        println!("{}", value);
+                        pc += 1;
+                    },
+
+                    Instruction::Halt => {
+                        break;
+                    },
+
+                    Instruction::Panic => {
+                        let value = stack_pop!(stack);
+                        fail!(VmErrorKind::Panic { value });
+                    },
+
+                }
+
+            };
+
+            self.fuel += fuel;
+            return Ok(Completion::Returned(results));
         };
 
-        Ok(VmExecutionResult {
-            result,
-            run_time: timer.elapsed()
+        self.fuel += fuel;
+
+        // Build the backtrace from the failing function and the active frames
+        let mut backtrace = Vec::with_capacity(frames.len() + 1);
+        backtrace.push(StackTraceEntry {
+            function: self.functions[function_index].name.clone(),
+            function_index,
+            pc
+        });
+        for frame in frames.iter().rev() {
+            backtrace.push(StackTraceEntry {
+                function: self.functions[frame.function_index].name.clone(),
+                function_index: frame.function_index,
+                pc: frame.pc - 1
+            });
+        }
+
+        Err(VmError {
+            kind: error,
+            backtrace
         })
 
     }
@@ -4,6 +4,8 @@ use std::fmt::Display;
 use std::hash::{Hash, Hasher};
 use std::ops::{Add, Div, Mul, Neg, Not, Rem, Sub};
 use std::rc::Rc;
+use crate::error::VmErrorKind;
+use crate::runtime::Execution;
 
 #[derive(Debug, Clone)]
 pub enum Variant {
@@ -35,15 +37,91 @@ pub enum Variant {
     // Index is an index into an array
     Index(usize),
 
+    // Closure is a function with its captured variables
+    Closure(Rc<Closure>),
+
+    // Upvalue is the shared storage of a local variable captured by a closure
+    Upvalue(Rc<RefCell<Variant>>),
+
+    // Coroutine is a function call with its own stack that can yield and be resumed
+    Coroutine(Rc<RefCell<Coroutine>>),
+
+}
+
+/// A function together with the variables it captured when it was created.
+#[derive(Debug, PartialEq)]
+pub struct Closure {
+
+    // Index of the function to call
+    pub function_index: usize,
+
+    // Captured variables, shared with the creating frame and other closures
+    pub upvalues: Vec<Rc<RefCell<Variant>>>,
+
+}
+
+/// Whether a coroutine can be resumed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CoroutineStatus {
+
+    // Created or yielded and waiting to be resumed
+    Suspended,
+
+    // Currently executing, possibly resuming another coroutine
+    Running,
+
+    // Returned or failed, resuming it is an error
+    Finished,
+
+}
+
+/// A function call that runs on its own stack and frames, switched to by Resume until it yields or returns.
+#[derive(Debug)]
+pub struct Coroutine {
+    pub(crate) status: CoroutineStatus,
+
+    // State of the suspended call, taken out while it runs and dropped when it finishes
+    pub(crate) execution: Option<Execution>,
+}
+
+impl Coroutine {
+
+    pub fn status(&self) -> CoroutineStatus {
+        self.status
+    }
+
 }
 
 impl Variant {
 
-    pub fn pow(&self, rhs: &Variant) -> Variant {
+    /// Returns the name of the variant's type for use in error messages.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Variant::Null => "null",
+            Variant::Integer(_) => "integer",
+            Variant::Float(_) => "float",
+            Variant::String(_) => "string",
+            Variant::Boolean(_) => "boolean",
+            Variant::SymbolReference(_) => "symbol",
+            Variant::Array(_) => "array",
+            Variant::Dictionary(_) => "dictionary",
+            Variant::Index(_) => "index",
+            Variant::Closure(_) => "closure",
+            Variant::Coroutine(_) => "coroutine",
+            Variant::Upvalue(upvalue) => upvalue.borrow().type_name(),
+        }
+    }
+
+    pub fn pow(&self, rhs: &Variant) -> Result<Variant, VmErrorKind> {
         match (self, rhs) {
-            (Variant::Integer(lhs), Variant::Integer(rhs)) => Variant::Integer(lhs.pow(*rhs as u32)),
-            (Variant::Float(lhs), Variant::Float(rhs)) => Variant::Float(lhs.powf(*rhs)),
-            _ => panic!("Invalid operands for exponentiation")
+            (Variant::Integer(lhs), Variant::Integer(rhs)) => {
+                let Ok(exponent) = u32::try_from(*rhs) else {
+                    return Err(VmErrorKind::RuntimeError { message: format!("Invalid exponent for integer exponentiation: {}", rhs) });
+                };
+                checked_integer_result("**", lhs.checked_pow(exponent))
+            },
+            (Variant::Float(lhs), Variant::Float(rhs)) => Ok(Variant::Float(lhs.powf(*rhs))),
+            (lhs, rhs) => Err(operator_type_error("**", lhs, rhs))
         }
     }
     
@@ -92,59 +170,105 @@ impl Display for Variant {
                 write!(f, "}}")
             }
             Variant::SymbolReference(s) => write!(f, "GlobalReference({})", s),
+            Variant::Closure(c) => write!(f, "Closure({})", c.function_index),
+            Variant::Coroutine(c) => write!(f, "Coroutine({:?})", c.borrow().status),
+            Variant::Upvalue(u) => write!(f, "{}", u.borrow()),
         }
     }
 }
 
-impl Into<i64> for Variant {
-    fn into(self) -> i64 {
-        match self {
-            Variant::Integer(i) => i,
-            v => panic!("Cannot convert from {:?} to i64", v)
+impl TryFrom<Variant> for i64 {
+    type Error = VmErrorKind;
+
+    fn try_from(value: Variant) -> Result<Self, Self::Error> {
+        match value {
+            Variant::Integer(i) => Ok(i),
+            v => Err(VmErrorKind::TypeError { expected: "integer", actual: v.type_name() })
         }
     }
 }
 
-impl Into<f64> for Variant {
-    fn into(self) -> f64 {
-        match self {
-            Variant::Float(f) => f,
-            v => panic!("Cannot convert from {:?} to f64", v)
+impl TryFrom<Variant> for f64 {
+    type Error = VmErrorKind;
+
+    fn try_from(value: Variant) -> Result<Self, Self::Error> {
+        match value {
+            Variant::Float(f) => Ok(f),
+            Variant::Integer(i) => Ok(i as f64),
+            v => Err(VmErrorKind::TypeError { expected: "float", actual: v.type_name() })
         }
     }
 }
 
-impl Into<usize> for Variant {
-    fn into(self) -> usize {
-        match self {
-            Variant::Integer(i) => i as usize,
-            v => panic!("Cannot convert from {:?} to usize", v)
+impl TryFrom<Variant> for usize {
+    type Error = VmErrorKind;
+
+    fn try_from(value: Variant) -> Result<Self, Self::Error> {
+        match value {
+            Variant::Index(i) => Ok(i),
+            Variant::Integer(i) if i >= 0 => Ok(i as usize),
+            v => Err(VmErrorKind::TypeError { expected: "index", actual: v.type_name() })
         }
     }
 }
 
-impl Into<String> for Variant {
-    fn into(self) -> String {
-        match self {
-            Variant::String(s) => s,
-            _ => panic!("Cannot convert to String")
+impl TryFrom<Variant> for String {
+    type Error = VmErrorKind;
+
+    fn try_from(value: Variant) -> Result<Self, Self::Error> {
+        match value {
+            Variant::String(s) => Ok(s),
+            v => Err(VmErrorKind::TypeError { expected: "string", actual: v.type_name() })
         }
     }
 }
 
-impl Into<bool> for Variant {
-    fn into(self) -> bool {
-        match self {
-            Variant::Null => false,
-            Variant::Boolean(b) => b,
-            Variant::Integer(i) => i != 0,
-            Variant::Float(f) => f != 0.0,
-            Variant::String(s) => !s.is_empty(),
-            _ => panic!("Cannot convert to bool")
+impl TryFrom<Variant> for bool {
+    type Error = VmErrorKind;
+
+    fn try_from(value: Variant) -> Result<Self, Self::Error> {
+        match value {
+            Variant::Boolean(b) => Ok(b),
+            v => Err(VmErrorKind::TypeError { expected: "boolean", actual: v.type_name() })
         }
     }
 }
 
+impl From<i64> for Variant {
+    fn from(value: i64) -> Self {
+        Variant::Integer(value)
+    }
+}
+
+impl From<f64> for Variant {
+    fn from(value: f64) -> Self {
+        Variant::Float(value)
+    }
+}
+
+impl From<usize> for Variant {
+    fn from(value: usize) -> Self {
+        Variant::Index(value)
+    }
+}
+
+impl From<String> for Variant {
+    fn from(value: String) -> Self {
+        Variant::String(value)
+    }
+}
+
+impl From<&str> for Variant {
+    fn from(value: &str) -> Self {
+        Variant::String(value.to_string())
+    }
+}
+
+impl From<bool> for Variant {
+    fn from(value: bool) -> Self {
+        Variant::Boolean(value)
+    }
+}
 
 impl PartialEq for Variant {
     fn eq(&self, other: &Self) -> bool {
@@ -154,6 +278,8 @@ impl PartialEq for Variant {
             (Variant::Float(lhs), Variant::Float(rhs)) => lhs == rhs,
             (Variant::String(lhs), Variant::String(rhs)) => lhs == rhs,
             (Variant::Boolean(lhs), Variant::Boolean(rhs)) => lhs == rhs,
+            (Variant::SymbolReference(lhs), Variant::SymbolReference(rhs)) => lhs == rhs,
+            (Variant::Index(lhs), Variant::Index(rhs)) => lhs == rhs,
             (Variant::Array(lhs), Variant::Array(rhs)) => {
                 let lhs = lhs.borrow();
                 let rhs = rhs.borrow();
@@ -184,16 +310,16 @@ impl PartialEq for Variant {
                 }
                 true
             },
+            (Variant::Closure(lhs), Variant::Closure(rhs)) => Rc::ptr_eq(lhs, rhs),
+            (Variant::Coroutine(lhs), Variant::Coroutine(rhs)) => Rc::ptr_eq(lhs, rhs),
+            (Variant::Upvalue(lhs), rhs) => *lhs.borrow() == *rhs,
+            (lhs, Variant::Upvalue(rhs)) => *lhs == *rhs.borrow(),
             _ => false
         }
     }
 }
 
-impl Eq for Variant {
-    fn assert_receiver_is_total_eq(&self) {
-        // no-op
-    }
-}
+impl Eq for Variant {}
 
 impl Hash for Variant {
     fn hash<H: Hasher>(&self, state: &mut H) {
@@ -220,25 +346,43 @@ impl Hash for Variant {
                 }
             }
             Variant::SymbolReference(s) => s.hash(state),
+            Variant::Closure(c) => Rc::as_ptr(c).hash(state),
+            Variant::Coroutine(c) => Rc::as_ptr(c).hash(state),
+            Variant::Upvalue(u) => u.borrow().hash(state),
         }
     }
 }
 
+fn operator_type_error(operator: &'static str, lhs: &Variant, rhs: &Variant) -> VmErrorKind {
+    VmErrorKind::OperatorTypeError {
+        operator,
+        lhs: lhs.type_name(),
+        rhs: rhs.type_name()
+    }
+}
+
+fn checked_integer_result(operator: &'static str, value: Option<i64>) -> Result<Variant, VmErrorKind> {
+    match value {
+        Some(value) => Ok(Variant::Integer(value)),
+        None => Err(VmErrorKind::IntegerOverflow { operator })
+    }
+}
+
 // Add Operator trait to Variant
 impl Add for Variant {
-    type Output = Variant;
+    type Output = Result<Variant, VmErrorKind>;
 
     fn add(self, rhs: Self) -> Self::Output {
         match (self, rhs) {
-            (Variant::Integer(lhs), Variant::Integer(rhs)) => Variant::Integer(lhs + rhs),
-            (Variant::Float(lhs), Variant::Float(rhs)) => Variant::Float(lhs + rhs),
-            (Variant::String(lhs), rhs) => Variant::String(lhs + &rhs.to_string()),
-            (Variant::Boolean(lhs), Variant::Boolean(rhs)) => Variant::Boolean(lhs && rhs),
+            (Variant::Integer(lhs), Variant::Integer(rhs)) => checked_integer_result("+", lhs.checked_add(rhs)),
+            (Variant::Float(lhs), Variant::Float(rhs)) => Ok(Variant::Float(lhs + rhs)),
+            (Variant::String(lhs), rhs) => Ok(Variant::String(lhs + &rhs.to_string())),
+            (Variant::Boolean(lhs), Variant::Boolean(rhs)) => Ok(Variant::Boolean(lhs && rhs)),
             (Variant::Array(lhs), Variant::Array(rhs)) => {
                 let mut lhs = lhs.borrow().clone();
                 let rhs = rhs.borrow();
                 lhs.extend(rhs.iter().cloned());
-                Variant::Array(Rc::new(RefCell::new(lhs)))
+                Ok(Variant::Array(Rc::new(RefCell::new(lhs))))
             },
             (Variant::Dictionary(lhs), Variant::Dictionary(rhs)) => {
                 let mut lhs = lhs.borrow().clone();
@@ -246,84 +390,86 @@ impl Add for Variant {
                 for (k, v) in rhs.iter() {
                     lhs.insert(k.clone(), v.clone());
                 }
-                Variant::Dictionary(Rc::new(RefCell::new(lhs)))
+                Ok(Variant::Dictionary(Rc::new(RefCell::new(lhs))))
             },
-            _ => panic!("Invalid operands for addition")
+            (lhs, rhs) => Err(operator_type_error("+", &lhs, &rhs))
         }
     }
 }
 
 impl Sub for Variant {
-    type Output = Variant;
+    type Output = Result<Variant, VmErrorKind>;
 
     fn sub(self, rhs: Self) -> Self::Output {
         match (self, rhs) {
-            (Variant::Integer(lhs), Variant::Integer(rhs)) => Variant::Integer(lhs - rhs),
-            (Variant::Float(lhs), Variant::Float(rhs)) => Variant::Float(lhs - rhs),
-            (a, b) => panic!("Invalid operands for subtraction: {} - {}", a, b)
+            (Variant::Integer(lhs), Variant::Integer(rhs)) => checked_integer_result("-", lhs.checked_sub(rhs)),
+            (Variant::Float(lhs), Variant::Float(rhs)) => Ok(Variant::Float(lhs - rhs)),
+            (lhs, rhs) => Err(operator_type_error("-", &lhs, &rhs))
         }
     }
 }
 
 impl Div for Variant {
-    type Output = Variant;
+    type Output = Result<Variant, VmErrorKind>;
 
     fn div(self, rhs: Self) -> Self::Output {
         match (self, rhs) {
-            (Variant::Integer(lhs), Variant::Integer(rhs)) => Variant::Integer(lhs / rhs),
-            (Variant::Float(lhs), Variant::Float(rhs)) => Variant::Float(lhs / rhs),
-            _ => panic!("Invalid operands for division")
+            (Variant::Integer(_), Variant::Integer(0)) => Err(VmErrorKind::DivisionByZero),
+            (Variant::Integer(lhs), Variant::Integer(rhs)) => checked_integer_result("/", lhs.checked_div(rhs)),
+            (Variant::Float(lhs), Variant::Float(rhs)) => Ok(Variant::Float(lhs / rhs)),
+            (lhs, rhs) => Err(operator_type_error("/", &lhs, &rhs))
         }
     }
 }
 
 impl Mul for Variant {
-    type Output = Variant;
+    type Output = Result<Variant, VmErrorKind>;
 
     fn mul(self, rhs: Self) -> Self::Output {
         match (self, rhs) {
-            (Variant::Integer(lhs), Variant::Integer(rhs)) => Variant::Integer(lhs * rhs),
-            (Variant::Float(lhs), Variant::Float(rhs)) => Variant::Float(lhs * rhs),
-            _ => panic!("Invalid operands for multiplication")
+            (Variant::Integer(lhs), Variant::Integer(rhs)) => checked_integer_result("*", lhs.checked_mul(rhs)),
+            (Variant::Float(lhs), Variant::Float(rhs)) => Ok(Variant::Float(lhs * rhs)),
+            (lhs, rhs) => Err(operator_type_error("*", &lhs, &rhs))
         }
     }
 }
 
 impl Rem for Variant {
-    type Output = Variant;
+    type Output = Result<Variant, VmErrorKind>;
 
     fn rem(self, rhs: Self) -> Self::Output {
         match (self, rhs) {
-            (Variant::Integer(lhs), Variant::Integer(rhs)) => Variant::Integer(lhs % rhs),
-            (Variant::Float(lhs), Variant::Float(rhs)) => Variant::Float(lhs % rhs),
-            _ => panic!("Invalid operands for modulus")
+            (Variant::Integer(_), Variant::Integer(0)) => Err(VmErrorKind::DivisionByZero),
+            (Variant::Integer(lhs), Variant::Integer(rhs)) => checked_integer_result("%", lhs.checked_rem(rhs)),
+            (Variant::Float(lhs), Variant::Float(rhs)) => Ok(Variant::Float(lhs % rhs)),
+            (lhs, rhs) => Err(operator_type_error("%", &lhs, &rhs))
         }
     }
 }
 
 impl Neg for Variant {
-    type Output = Variant;
+    type Output = Result<Variant, VmErrorKind>;
 
     fn neg(self) -> Self::Output {
         match self {
-            Variant::Integer(i) => Variant::Integer(-i),
-            Variant::Float(f) => Variant::Float(-f),
-            Variant::Boolean(b) => Variant::Boolean(!b),
-            _ => panic!("Invalid operand for negation")
+            Variant::Integer(i) => checked_integer_result("-", i.checked_neg()),
+            Variant::Float(f) => Ok(Variant::Float(-f)),
+            Variant::Boolean(b) => Ok(Variant::Boolean(!b)),
+            v => Err(VmErrorKind::UnaryOperatorTypeError { operator: "-", operand: v.type_name() })
         }
     }
 }
 
 impl Not for Variant {
-    type Output = Variant;
+    type Output = Result<Variant, VmErrorKind>;
 
     fn not(self) -> Self::Output {
         match self {
-            Variant::Boolean(b) => Variant::Boolean(!b),
-            Variant::Integer(i) => Variant::Boolean(i == 0),
-            Variant::Float(f) => Variant::Boolean(f == 0.0),
-            Variant::String(s) => Variant::Boolean(s.is_empty()),
-            _ => panic!("Invalid operand for not operation")
+            Variant::Boolean(b) => Ok(Variant::Boolean(!b)),
+            Variant::Integer(i) => Ok(Variant::Boolean(i == 0)),
+            Variant::Float(f) => Ok(Variant::Boolean(f == 0.0)),
+            Variant::String(s) => Ok(Variant::Boolean(s.is_empty())),
+            v => Err(VmErrorKind::UnaryOperatorTypeError { operator: "!", operand: v.type_name() })
         }
     }
 }
@@ -344,42 +490,61 @@ mod tests {
 
     #[test]
     fn test_integer_less_than_or_equal() {
-        assert_eq!(Variant::Integer(1) <= Variant::Integer(1), true);
+        assert!(Variant::Integer(1) <= Variant::Integer(1));
     }
 
     #[test]
     fn test_float_less_than_or_equal() {
-        assert_eq!(Variant::Float(1.0) <= Variant::Float(1.0), true);
+        assert!(Variant::Float(1.0) <= Variant::Float(1.0));
     }
 
     #[test]
     fn test_integer_greater_than() {
-        assert_eq!(Variant::Integer(2) > Variant::Integer(1), true);
+        assert!(Variant::Integer(2) > Variant::Integer(1));
     }
 
     #[test]
     fn test_float_greater_than() {
-        assert_eq!(Variant::Float(2.0) > Variant::Float(1.0), true);
+        assert!(Variant::Float(2.0) > Variant::Float(1.0));
     }
 
     #[test]
     fn test_integer_greater_than_or_equal() {
-        assert_eq!(Variant::Integer(2) >= Variant::Integer(2), true);
+        assert!(Variant::Integer(2) >= Variant::Integer(2));
     }
 
     #[test]
     fn test_float_greater_than_or_equal() {
-        assert_eq!(Variant::Float(2.0) >= Variant::Float(2.0), true);
+        assert!(Variant::Float(2.0) >= Variant::Float(2.0));
     }
 
     #[test]
     fn test_boolean_negation() {
-        assert_eq!(!Variant::Boolean(false), Variant::Boolean(true));
+        assert_eq!(!Variant::Boolean(false), Ok(Variant::Boolean(true)));
+    }
+
+    #[test]
+    fn test_try_from_type_mismatch() {
+        assert_eq!(i64::try_from(Variant::Integer(3)), Ok(3));
+        assert_eq!(i64::try_from(Variant::String(String::from("3"))), Err(VmErrorKind::TypeError { expected: "integer", actual: "string" }));
     }
 
     #[test]
     fn test_boolean_not() {
-        assert_eq!(Variant::Boolean(false).not(), Variant::Boolean(true));
+        assert_eq!(Variant::Boolean(false).not(), Ok(Variant::Boolean(true)));
+    }
+
+    #[test]
+    fn test_invalid_operands() {
+        assert_eq!(Variant::Integer(1) - Variant::String(String::from("a")), Err(VmErrorKind::OperatorTypeError { operator: "-", lhs: "integer", rhs: "string" }));
+        assert_eq!(-Variant::Null, Err(VmErrorKind::UnaryOperatorTypeError { operator: "-", operand: "null" }));
+    }
+
+    #[test]
+    fn test_integer_division_by_zero() {
+        assert_eq!(Variant::Integer(1) / Variant::Integer(0), Err(VmErrorKind::DivisionByZero));
+        assert_eq!(Variant::Integer(1) % Variant::Integer(0), Err(VmErrorKind::DivisionByZero));
+        assert_eq!(Variant::Integer(i64::MIN) / Variant::Integer(-1), Err(VmErrorKind::IntegerOverflow { operator: "/" }));
     }
 
 }
\ No newline at end of file
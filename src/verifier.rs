@@ -0,0 +1,206 @@
+use crate::error::{StackTraceEntry, VmError, VmErrorKind};
+use crate::program::{CallTarget, Function, Instruction, Program, SymbolEntry};
+
+// Number of operands above the local variables, unknown after calls with a varying number of results
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Depth {
+    Known(usize),
+    Unknown
+}
+
+// Signature of a call target that can be resolved without running the program
+struct Callee {
+    name: String,
+    arity: usize,
+    max_arity: Option<usize>,
+    results: Option<usize>
+}
+
+/// Checks the operand stack depth of every reachable instruction in the program.
+/// Calls must find at least as many operands as the callee's arity and jumps must agree on the depth where paths join.
+pub(crate) fn verify_stack_depth(program: &Program) -> Result<(), VmError> {
+    let result_counts = program.functions.iter().map(result_count).collect::<Vec<_>>();
+    for (function_index, function) in program.functions.iter().enumerate() {
+        verify_function(program, &result_counts, function_index, function)?;
+    }
+    Ok(())
+}
+
+/// Returns the number of values a function leaves on the caller's stack if it is the same on every path.
+pub(crate) fn result_count(function: &Function) -> Option<usize> {
+    if function.return_count.is_some() {
+        return function.return_count;
+    }
+    let mut counts = function.instructions.iter().filter_map(returned_count);
+    let first = counts.next().unwrap_or(0);
+    counts.all(|count| count == first).then_some(first)
+}
+
+fn returned_count(instruction: &Instruction) -> Option<usize> {
+    match instruction {
+        Instruction::Return => Some(1),
+        Instruction::ReturnN(count) => Some(*count),
+        Instruction::EndFunction => Some(0),
+        _ => None
+    }
+}
+
+fn resolve_callee(program: &Program, result_counts: &[Option<usize>], target: &CallTarget) -> Result<Option<Callee>, VmErrorKind> {
+    let index = match target {
+        CallTarget::Index(index) => *index,
+        CallTarget::Name(name) => match program.symbol_table.get(name) {
+            Some(SymbolEntry::UserDefinedFunction { index }) => *index,
+            Some(SymbolEntry::NativeFunction { arity }) => return Ok(Some(Callee {
+                name: name.clone(),
+                arity: *arity,
+                max_arity: Some(*arity),
+                results: None
+            })),
+            // Native functions are usually registered with the VM and not known to the program
+            None => return Ok(None)
+        }
+    };
+    match program.functions.get(index) {
+        Some(function) => Ok(Some(Callee {
+            name: function.name.clone(),
+            arity: function.arity,
+            max_arity: (!function.variadic).then_some(function.arity.saturating_add(function.optional_count)),
+            results: result_counts[index]
+        })),
+        None => Err(VmErrorKind::InvalidFunctionIndex { index })
+    }
+}
+
+fn verify_function(program: &Program, result_counts: &[Option<usize>], function_index: usize, function: &Function) -> Result<(), VmError> {
+
+    let error = |kind: VmErrorKind, pc: usize| VmError {
+        kind,
+        backtrace: vec![StackTraceEntry {
+            function: function.name.clone(),
+            function_index,
+            pc
+        }]
+    };
+
+    if function.instructions.is_empty() {
+        return Err(error(VmErrorKind::InvalidProgramCounter, 0));
+    }
+
+    let mut depths: Vec<Option<Depth>> = vec![None; function.instructions.len()];
+    let mut pending = vec![(0, Depth::Known(0))];
+
+    while let Some((pc, depth)) = pending.pop() {
+
+        // Paths that join must agree on the depth, unless it is unknown on one of them
+        match (depths[pc], depth) {
+            (None, _) => depths[pc] = Some(depth),
+            (Some(Depth::Unknown), _) => continue,
+            (Some(Depth::Known(expected)), Depth::Known(actual)) if expected == actual => continue,
+            (Some(Depth::Known(expected)), Depth::Known(actual)) => {
+                return Err(error(VmErrorKind::InconsistentStackDepth { expected, actual }, pc));
+            },
+            (Some(Depth::Known(_)), Depth::Unknown) => depths[pc] = Some(Depth::Unknown)
+        }
+
+        let instruction = &function.instructions[pc];
+
+        if let Some(expected) = function.return_count && let Some(actual) = returned_count(instruction) && actual != expected {
+            return Err(error(VmErrorKind::ReturnCountMismatch {
+                function: function.name.clone(),
+                expected,
+                actual
+            }, pc));
+        }
+
+        // Number of operands taken and pushed by the instruction, pushes are unknown for calls with varying results
+        let (pops, pushes) = match instruction {
+            Instruction::SetLocal(_) | Instruction::SetGlobal(_) | Instruction::SetUpvalue(_) => (1, Some(0)),
+            Instruction::GetLocal(_) | Instruction::GetGlobal(_) | Instruction::GetUpvalue(_) => (0, Some(1)),
+            Instruction::MakeClosure(_, _) | Instruction::Push(_) => (0, Some(1)),
+            Instruction::CreateArray(size) => (*size, Some(1)),
+            Instruction::GetArrayItem => (2, Some(1)),
+            Instruction::SetArrayItem => (3, Some(1)),
+            Instruction::GetArrayLength => (1, Some(1)),
+            Instruction::CreateDictionary(size) => (size.saturating_mul(2), Some(1)),
+            Instruction::GetDictionaryItem => (2, Some(1)),
+            Instruction::SetDictionaryItem => (3, Some(0)),
+            Instruction::GetDictionaryKeys => (1, Some(1)),
+            Instruction::FunctionCall(target) | Instruction::CallWithArgumentCount(target, _) | Instruction::TailCall(target) => {
+                let count = match instruction {
+                    Instruction::CallWithArgumentCount(_, count) => Some(*count),
+                    _ => None
+                };
+                match resolve_callee(program, result_counts, target).map_err(|kind| error(kind, pc))? {
+                    Some(callee) => {
+                        let count = count.unwrap_or(callee.arity);
+                        if count < callee.arity || callee.max_arity.is_some_and(|max_arity| count > max_arity) {
+                            let expected = if count < callee.arity { callee.arity } else { callee.max_arity.unwrap_or(callee.arity) };
+                            return Err(error(VmErrorKind::ArityMismatch {
+                                function: callee.name,
+                                expected,
+                                actual: count
+                            }, pc));
+                        }
+                        if let Depth::Known(actual) = depth && actual < count {
+                            return Err(error(VmErrorKind::ArityMismatch {
+                                function: callee.name,
+                                expected: count,
+                                actual
+                            }, pc));
+                        }
+                        (count, callee.results)
+                    },
+                    None => (count.unwrap_or(0), None)
+                }
+            },
+            Instruction::GetArgumentCount => (0, Some(1)),
+            Instruction::CallIndirect(argument_count) => (argument_count.saturating_add(1), None),
+            Instruction::ReturnN(count) => (*count, Some(0)),
+            Instruction::Return | Instruction::Pop | Instruction::Print | Instruction::Panic => (1, Some(0)),
+            Instruction::EndFunction | Instruction::Halt => (0, Some(0)),
+            Instruction::Add | Instruction::Sub | Instruction::Mul | Instruction::Div | Instruction::Mod | Instruction::Pow => (2, Some(1)),
+            Instruction::Equal | Instruction::LessThan | Instruction::LessEqual => (2, Some(1)),
+            Instruction::GreaterThan | Instruction::GreaterEqual | Instruction::NotEqual => (2, Some(1)),
+            Instruction::Or | Instruction::And => (2, Some(1)),
+            Instruction::Not | Instruction::Negate => (1, Some(1)),
+            Instruction::Jump(_) => (0, Some(0)),
+            Instruction::JumpIfFalse(_) => (1, Some(0)),
+            Instruction::MakeCoroutine(argument_count) => (argument_count.saturating_add(1), Some(1)),
+            Instruction::Resume | Instruction::IsCoroutineFinished => (1, Some(1)),
+            Instruction::Yield => (1, Some(0)),
+            Instruction::TryBegin(_) | Instruction::TryEnd => (0, Some(0)),
+            Instruction::Throw => (1, Some(0)),
+        };
+
+        let next_depth = match (depth, pushes) {
+            (Depth::Known(depth), _) if depth < pops => return Err(error(VmErrorKind::StackUnderflow, pc)),
+            (Depth::Known(depth), Some(pushes)) => Depth::Known((depth - pops).saturating_add(pushes)),
+            _ => Depth::Unknown
+        };
+
+        let successors = match instruction {
+            Instruction::Jump(address) => vec![(*address, next_depth)],
+            Instruction::JumpIfFalse(address) => vec![(*address, next_depth), (pc + 1, next_depth)],
+            Instruction::Return | Instruction::ReturnN(_) | Instruction::EndFunction | Instruction::TailCall(_) | Instruction::Halt | Instruction::Panic | Instruction::Throw => vec![],
+
+            // The handler starts with the operands of the try region and the exception
+            Instruction::TryBegin(address) => {
+                let handler_depth = match depth {
+                    Depth::Known(depth) => Depth::Known(depth + 1),
+                    Depth::Unknown => Depth::Unknown
+                };
+                vec![(*address, handler_depth), (pc + 1, next_depth)]
+            },
+            _ => vec![(pc + 1, next_depth)]
+        };
+
+        for (successor, successor_depth) in successors {
+            if successor >= function.instructions.len() {
+                return Err(error(VmErrorKind::InvalidProgramCounter, pc));
+            }
+            pending.push((successor, successor_depth));
+        }
+    }
+
+    Ok(())
+}
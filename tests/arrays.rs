@@ -29,7 +29,7 @@ fn test_create_array() {
     match result {
         Variant::Array(array) => {
             assert_eq!(array.borrow().len(), 3);
-            assert_eq!(array.borrow().get(0), Some(&Variant::Integer(1)));
+            assert_eq!(array.borrow().first(), Some(&Variant::Integer(1)));
             assert_eq!(array.borrow().get(1), Some(&Variant::Integer(2)));
             assert_eq!(array.borrow().get(2), Some(&Variant::Integer(3)));
         }
@@ -0,0 +1,247 @@
+use bytevm::prelude::*;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+fn run(source: &str) -> Variant {
+    let mut vm = Vm::default();
+    vm.load_program(Program::from_assembly(source).unwrap());
+    vm.run(None, None).unwrap().result.unwrap()
+}
+
+fn assembly_error(source: &str) -> AssemblyError {
+    Program::from_assembly(source).unwrap_err()
+}
+
+#[test]
+fn test_assemble_fib() {
+    assert_eq!(run(include_str!("fixtures/fib.bvasm")), Variant::Integer(5));
+}
+
+#[test]
+fn test_assemble_closures() {
+    let result = run(include_str!("fixtures/counter.bvasm"));
+    assert_eq!(result, Variant::Array(Rc::new(RefCell::new(vec![Variant::Integer(2), Variant::Integer(1)]))));
+}
+
+#[test]
+fn test_assembly_matches_builder() {
+    let assembled = Program::from_assembly(include_str!("fixtures/fib.bvasm")).unwrap();
+
+    let mut program = Program::builder();
+    program.add_function(FunctionBuilder::default()
+        .name("main")
+        .arity(0)
+        .body(BlockEncoder::default()
+            .declare_local("n")
+            .push_integer(5)
+            .set_local("n")
+            .get_local("n")
+            .call_function_by_name("fib")
+            .return_value()
+        )
+        .build()
+    );
+    program.add_function(FunctionBuilder::default()
+        .name("fib")
+        .arity(1)
+        .body(BlockEncoder::default()
+            .declare_local("n")
+            .get_local("n")
+            .push_integer(1)
+            .less_than_or_equal()
+            .jump_if_false("recurse")
+            .get_local("n")
+            .return_value()
+            .add_label("recurse")
+            .get_local("n")
+            .push_integer(1)
+            .sub()
+            .call_function_by_name("fib")
+            .get_local("n")
+            .push_integer(2)
+            .sub()
+            .call_function_by_name("fib")
+            .add()
+            .return_value()
+        )
+        .build()
+    );
+
+    assert_eq!(assembled, program.build());
+}
+
+#[test]
+fn test_assemble_constants() {
+    let program = Program::from_assembly(r#"
+        .function main 0
+            push null
+            push true
+            push -3
+            push 2.5
+            push "a \"quoted\"\n"
+            push @print
+            push #4
+            push [1, [2], {}]
+            push {"key": [true, null]}
+            return_n 9
+        .end
+    "#).unwrap();
+
+    let constants = program.functions[0].instructions.iter()
+        .filter_map(|instruction| match instruction {
+            Instruction::Push(value) => Some(value.clone()),
+            _ => None
+        })
+        .collect::<Vec<_>>();
+
+    let array = |items: Vec<Variant>| Variant::Array(Rc::new(RefCell::new(items)));
+    let dictionary = |items: Vec<(Variant, Variant)>| Variant::Dictionary(Rc::new(RefCell::new(items.into_iter().collect::<HashMap<_, _>>())));
+
+    assert_eq!(constants, vec![
+        Variant::Null,
+        Variant::Boolean(true),
+        Variant::Integer(-3),
+        Variant::Float(2.5),
+        Variant::String("a \"quoted\"\n".to_string()),
+        Variant::SymbolReference("print".to_string()),
+        Variant::Index(4),
+        array(vec![Variant::Integer(1), array(vec![Variant::Integer(2)]), dictionary(vec![])]),
+        dictionary(vec![(Variant::String("key".to_string()), array(vec![Variant::Boolean(true), Variant::Null]))])
+    ]);
+}
+
+#[test]
+fn test_assemble_every_instruction() {
+    let program = Program::from_assembly(r#"
+        .native print_line 1
+        .global total 0
+
+        .function main 0
+            .local a
+            set_local a
+            get_local a
+            set_global total
+            get_global #0
+            create_array 2
+            get_array_item
+            set_array_item
+            get_array_length
+            create_dictionary 1
+            get_dictionary_item
+            set_dictionary_item
+            get_dictionary_keys
+            call helper
+            call_with_argument_count helper 2
+            get_argument_count
+            call_indirect 1
+            make_closure #1 a
+            push 1
+            pop
+            add
+            sub
+            mul
+            div
+            mod
+            pow
+            equal
+            less_than
+            less_equal
+            greater_than
+            greater_equal
+            not_equal
+            or
+            and
+            not
+            negate
+        loop:
+            jump loop
+            jump_if_false loop
+            make_coroutine 0
+            resume
+            yield
+            is_coroutine_finished
+            try_begin handler
+            try_end
+        handler:
+            throw
+            print
+            call print_line
+            end_function
+            return_n 0
+            halt
+            panic
+            tail_call helper
+        .end
+
+        .function helper 0..2+
+            .returns 1
+            .upvalue x
+            get_upvalue x
+            set_upvalue x
+            return
+        .end
+    "#).unwrap();
+
+    let main = &program.functions[0];
+    assert_eq!(main.instructions.len(), 51);
+    assert_eq!(main.instructions[3], Instruction::GetGlobal(GlobalTarget::Slot(0)));
+    assert_eq!(main.instructions[16], Instruction::MakeClosure(CallTarget::Index(1), vec![0]));
+    assert_eq!(main.instructions[35], Instruction::Jump(35));
+    assert_eq!(main.instructions[41], Instruction::TryBegin(43));
+
+    let helper = &program.functions[1];
+    assert_eq!((helper.arity, helper.optional_count, helper.variadic, helper.return_count), (0, 2, true, Some(1)));
+    assert_eq!(helper.instructions, vec![Instruction::GetUpvalue(0), Instruction::SetUpvalue(0), Instruction::Return]);
+}
+
+#[test]
+fn test_unknown_opcode() {
+    let error = assembly_error(".function main 0\n    push 1\n    frobnicate\n.end\n");
+    assert_eq!(error.kind, AssemblyErrorKind::UnknownOpcode { name: "frobnicate".to_string() });
+    assert_eq!((error.line, error.column), (3, 5));
+    assert_eq!(error.to_string(), "3:5: Unknown opcode: frobnicate");
+}
+
+#[test]
+fn test_undeclared_local() {
+    let error = assembly_error(".function main 0\n    .local a\n    get_local b\n.end\n");
+    assert_eq!(error.kind, AssemblyErrorKind::UndeclaredLocal { name: "b".to_string() });
+    assert_eq!((error.line, error.column), (3, 15));
+}
+
+#[test]
+fn test_missing_label() {
+    let error = assembly_error(".function main 0\n    push true\n    jump_if_false done\n    halt\n.end\n");
+    assert_eq!(error.kind, AssemblyErrorKind::MissingLabel { name: "done".to_string() });
+    assert_eq!((error.line, error.column), (3, 19));
+}
+
+#[test]
+fn test_syntax_errors() {
+    let error = assembly_error("push 1");
+    assert_eq!((error.kind, error.line, error.column), (AssemblyErrorKind::OutsideFunction, 1, 1));
+
+    let error = assembly_error(".function main 0\n    push \"open\n.end");
+    assert_eq!((error.kind, error.line, error.column), (AssemblyErrorKind::UnterminatedString, 2, 10));
+
+    let error = assembly_error(".function main 0\n    push 1 2\n.end");
+    assert_eq!((error.kind, error.line, error.column), (AssemblyErrorKind::UnexpectedOperand { found: "2".to_string() }, 2, 12));
+
+    let error = assembly_error(".function main 0\n    create_array\n.end");
+    assert_eq!((error.kind, error.line, error.column), (AssemblyErrorKind::MissingOperand { expected: "array size" }, 2, 17));
+
+    let error = assembly_error(".function main 0\n    halt\n");
+    assert_eq!((error.kind, error.line, error.column), (AssemblyErrorKind::UnterminatedFunction { name: "main".to_string() }, 1, 1));
+
+    let error = assembly_error(".function main 0\n.end\n.function main 0\n.end");
+    assert_eq!((error.kind, error.line, error.column), (AssemblyErrorKind::DuplicateFunction { name: "main".to_string() }, 3, 11));
+}
+
+#[test]
+fn test_build_errors_are_reported() {
+    let error = assembly_error(".export missing\n.function main 0\n    halt\n.end\n");
+    assert_eq!(error.kind, AssemblyErrorKind::Build {
+        error: VmError::new(VmErrorKind::UnresolvedSymbol { name: "missing".to_string() })
+    });
+}
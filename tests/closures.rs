@@ -0,0 +1,158 @@
+use bytevm::prelude::*;
+
+#[test]
+fn test_closure_keeps_state_after_frame_returns() {
+    let mut program = Program::builder();
+
+    // Returns a closure that increments and returns its own counter
+    program.add_function(FunctionBuilder::default()
+        .name("make_counter")
+        .arity(0)
+        .body(
+            BlockEncoder::default()
+                .declare_local("count")
+                .push_integer(0)
+                .set_local("count")
+                .make_closure("next", &["count"])
+                .return_value()
+        )
+        .build()
+    );
+
+    program.add_function(FunctionBuilder::default()
+        .name("next")
+        .arity(0)
+        .body(
+            BlockEncoder::default()
+                .declare_upvalue("count")
+                .get_upvalue("count")
+                .push_integer(1)
+                .add()
+                .set_upvalue("count")
+                .get_upvalue("count")
+                .return_value()
+        )
+        .build()
+    );
+
+    program.add_function(FunctionBuilder::default()
+        .name("main")
+        .arity(0)
+        .body(
+            BlockEncoder::default()
+                .declare_local("a")
+                .declare_local("b")
+                .call_function_by_name("make_counter")
+                .set_local("a")
+                .call_function_by_name("make_counter")
+                .set_local("b")
+                .get_local("a")
+                .call_indirect(0)
+                .pop()
+                .get_local("a")
+                .call_indirect(0)
+                .pop()
+                .get_local("b")
+                .call_indirect(0)
+                .pop()
+                .get_local("a")
+                .call_indirect(0)
+                .return_value()
+        )
+        .build()
+    );
+
+    let mut vm = Vm::default();
+    vm.load_program(program.build());
+    let result = vm.run(None, None).unwrap().result.unwrap();
+
+    assert_eq!(result, Variant::Integer(3));
+}
+
+#[test]
+fn test_captured_local_is_shared_with_frame() {
+    let mut program = Program::builder();
+    program.add_function(FunctionBuilder::default()
+        .name("main")
+        .arity(0)
+        .body(
+            BlockEncoder::default()
+                .declare_local("x")
+                .declare_local("set")
+                .declare_local("get")
+                .push_integer(1)
+                .set_local("x")
+                .make_closure("set_x", &["x"])
+                .set_local("set")
+                .make_closure("get_x", &["x"])
+                .set_local("get")
+                // The closure writes the frame's variable
+                .push_integer(42)
+                .get_local("set")
+                .call_indirect(1)
+                .pop()
+                // The frame writes the variable seen by the other closure
+                .get_local("x")
+                .push_integer(1)
+                .add()
+                .set_local("x")
+                .get_local("get")
+                .call_indirect(0)
+                .return_value()
+        )
+        .build()
+    );
+    program.add_function(FunctionBuilder::default()
+        .name("set_x")
+        .arity(1)
+        .body(
+            BlockEncoder::default()
+                .declare_local("value")
+                .declare_upvalue("x")
+                .get_local("value")
+                .set_upvalue("x")
+                .push_null()
+                .return_value()
+        )
+        .build()
+    );
+    program.add_function(FunctionBuilder::default()
+        .name("get_x")
+        .arity(0)
+        .body(
+            BlockEncoder::default()
+                .declare_upvalue("x")
+                .get_upvalue("x")
+                .return_value()
+        )
+        .build()
+    );
+
+    let mut vm = Vm::default();
+    vm.load_program(program.build());
+    let result = vm.run(None, None).unwrap().result.unwrap();
+
+    assert_eq!(result, Variant::Integer(43));
+}
+
+#[test]
+fn test_upvalue_outside_closure() {
+    let mut program = Program::builder();
+    program.add_function(FunctionBuilder::default()
+        .name("main")
+        .arity(0)
+        .body(
+            BlockEncoder::default()
+                .declare_upvalue("x")
+                .get_upvalue("x")
+                .return_value()
+        )
+        .build()
+    );
+
+    let mut vm = Vm::default();
+    vm.load_program(program.build());
+    let error = vm.run(None, None).unwrap_err();
+
+    assert_eq!(error.kind, VmErrorKind::InvalidUpvalue { index: 0 });
+}
@@ -0,0 +1,13 @@
+use bytevm::prelude::*;
+
+/// Builds a program whose only function is a main function without arguments.
+pub fn main_program(body: &mut BlockEncoder) -> Program {
+    let mut program = Program::builder();
+    program.add_function(FunctionBuilder::default()
+        .name("main")
+        .arity(0)
+        .body(body)
+        .build()
+    );
+    program.build()
+}
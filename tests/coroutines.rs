@@ -0,0 +1,390 @@
+use bytevm::prelude::*;
+
+mod common;
+
+#[test]
+fn test_generator_yields_until_finished() {
+    let mut program = Program::builder();
+    program.verify_stack_depth(true);
+    program.add_function(FunctionBuilder::default()
+        .name("main")
+        .arity(0)
+        .body(
+            BlockEncoder::default()
+                .declare_local("generator")
+                .declare_local("total")
+                .declare_local("value")
+                .push_integer(3)
+                .push_function_reference("counter")
+                .make_coroutine(1)
+                .set_local("generator")
+                .push_integer(0)
+                .set_local("total")
+                .add_label("loop")
+                .get_local("generator")
+                .resume()
+                .set_local("value")
+                .get_local("generator")
+                .is_coroutine_finished()
+                .not()
+                .jump_if_false("done")
+                .get_local("total")
+                .get_local("value")
+                .add()
+                .set_local("total")
+                .jump("loop")
+                .add_label("done")
+                .get_local("total")
+                .return_value()
+        )
+        .build()
+    );
+
+    // Yields the numbers from 1 to the limit and returns null
+    program.add_function(FunctionBuilder::default()
+        .name("counter")
+        .arity(1)
+        .body(
+            BlockEncoder::default()
+                .declare_local("limit")
+                .declare_local("i")
+                .push_integer(0)
+                .set_local("i")
+                .add_label("loop")
+                .get_local("i")
+                .get_local("limit")
+                .less_than()
+                .jump_if_false("done")
+                .get_local("i")
+                .push_integer(1)
+                .add()
+                .set_local("i")
+                .get_local("i")
+                .yield_value()
+                .jump("loop")
+                .add_label("done")
+                .push_null()
+                .return_value()
+        )
+        .build()
+    );
+
+    let mut vm = Vm::default();
+    vm.load_program(program.build());
+    let result = vm.run(None, None).unwrap();
+
+    assert_eq!(result.result, Some(Variant::Integer(6)));
+}
+
+#[test]
+fn test_coroutine_status_from_host() {
+    let mut program = Program::builder();
+    program.verify_stack_depth(true);
+    program.add_function(FunctionBuilder::default()
+        .name("main")
+        .arity(0)
+        .body(
+            BlockEncoder::default()
+                .declare_local("generator")
+                .push_integer(2)
+                .push_function_reference("counter")
+                .make_coroutine(1)
+                .set_local("generator")
+                .get_local("generator")
+                .resume()
+                .pop()
+                .get_local("generator")
+                .return_value()
+        )
+        .build()
+    );
+
+    // Yields the numbers from 1 to the limit and returns null
+    program.add_function(FunctionBuilder::default()
+        .name("counter")
+        .arity(1)
+        .body(
+            BlockEncoder::default()
+                .declare_local("limit")
+                .declare_local("i")
+                .push_integer(0)
+                .set_local("i")
+                .add_label("loop")
+                .get_local("i")
+                .get_local("limit")
+                .less_than()
+                .jump_if_false("done")
+                .get_local("i")
+                .push_integer(1)
+                .add()
+                .set_local("i")
+                .get_local("i")
+                .yield_value()
+                .jump("loop")
+                .add_label("done")
+                .push_null()
+                .return_value()
+        )
+        .build()
+    );
+
+    let mut vm = Vm::default();
+    vm.load_program(program.build());
+    let result = vm.run(None, None).unwrap();
+
+    let Some(Variant::Coroutine(coroutine)) = result.result else {
+        panic!("Expected coroutine");
+    };
+    assert_eq!(coroutine.borrow().status(), CoroutineStatus::Suspended);
+}
+
+#[test]
+fn test_resume_finished_coroutine() {
+    let mut program = Program::builder();
+    program.verify_stack_depth(true);
+    program.add_function(FunctionBuilder::default()
+        .name("main")
+        .arity(0)
+        .body(
+            BlockEncoder::default()
+                .declare_local("generator")
+                .push_integer(0)
+                .push_function_reference("counter")
+                .make_coroutine(1)
+                .set_local("generator")
+                .get_local("generator")
+                .resume()
+                .pop()
+                .get_local("generator")
+                .resume()
+                .return_value()
+        )
+        .build()
+    );
+
+    // Yields the numbers from 1 to the limit and returns null
+    program.add_function(FunctionBuilder::default()
+        .name("counter")
+        .arity(1)
+        .body(
+            BlockEncoder::default()
+                .declare_local("limit")
+                .declare_local("i")
+                .push_integer(0)
+                .set_local("i")
+                .add_label("loop")
+                .get_local("i")
+                .get_local("limit")
+                .less_than()
+                .jump_if_false("done")
+                .get_local("i")
+                .push_integer(1)
+                .add()
+                .set_local("i")
+                .get_local("i")
+                .yield_value()
+                .jump("loop")
+                .add_label("done")
+                .push_null()
+                .return_value()
+        )
+        .build()
+    );
+
+    let mut vm = Vm::default();
+    vm.load_program(program.build());
+    let error = vm.run(None, None).unwrap_err();
+
+    assert_eq!(error.kind, VmErrorKind::CoroutineFinished);
+    assert_eq!(error.location().unwrap().pc, 8);
+}
+
+#[test]
+fn test_yield_outside_coroutine() {
+    let mut vm = Vm::default();
+    vm.load_program(common::main_program(
+        BlockEncoder::default()
+            .push_integer(1)
+            .yield_value()
+    ));
+    let error = vm.run(None, None).unwrap_err();
+
+    assert_eq!(error.kind, VmErrorKind::YieldOutsideCoroutine);
+}
+
+#[test]
+fn test_catch_error_thrown_in_coroutine() {
+    let mut program = Program::builder();
+    program.add_function(FunctionBuilder::default()
+        .name("main")
+        .arity(0)
+        .body(
+            BlockEncoder::default()
+                .push_function_reference("failing")
+                .make_coroutine(0)
+                .try_begin("catch")
+                .resume()
+                .try_end()
+                .return_value()
+                .add_label("catch")
+                .return_value()
+        )
+        .build()
+    );
+    program.add_function(FunctionBuilder::default()
+        .name("failing")
+        .arity(0)
+        .body(
+            BlockEncoder::default()
+                .push_string(String::from("broken"))
+                .throw()
+        )
+        .build()
+    );
+
+    let mut vm = Vm::default();
+    vm.load_program(program.build());
+    let result = vm.run(None, None).unwrap();
+
+    assert_eq!(result.result, Some(Variant::String(String::from("broken"))));
+}
+
+#[test]
+fn test_resume_exceeds_call_depth() {
+    let mut vm = Vm::default();
+    vm.set_limits(VmLimits {
+        max_call_depth: Some(16),
+        ..Default::default()
+    });
+    vm.load_program(common::main_program(
+        BlockEncoder::default()
+            .push_function_reference("main")
+            .make_coroutine(0)
+            .resume()
+            .return_value()
+    ));
+    let error = vm.run(None, None).unwrap_err();
+
+    assert_eq!(error.kind, VmErrorKind::CallDepthExceeded { limit: 16 });
+}
+
+#[test]
+fn test_unbounded_resume_recursion_is_error() {
+    let mut vm = Vm::default();
+    vm.load_program(common::main_program(
+        BlockEncoder::default()
+            .push_function_reference("main")
+            .make_coroutine(0)
+            .resume()
+            .return_value()
+    ));
+    let error = vm.run(None, None).unwrap_err();
+
+    assert!(matches!(error.kind, VmErrorKind::CallDepthExceeded { .. }));
+}
+
+#[test]
+fn test_fuel_exhausted_inside_coroutine_suspends() {
+    let mut program = Program::builder();
+    program.add_function(FunctionBuilder::default()
+        .name("main")
+        .arity(0)
+        .body(
+            BlockEncoder::default()
+                .push_integer(1_000)
+                .push_function_reference("count")
+                .make_coroutine(1)
+                .resume()
+                .return_value()
+        )
+        .build()
+    );
+
+    // Counts up to the limit and returns it
+    program.add_function(FunctionBuilder::default()
+        .name("count")
+        .arity(1)
+        .body(
+            BlockEncoder::default()
+                .declare_local("limit")
+                .declare_local("i")
+                .push_integer(0)
+                .set_local("i")
+                .add_label("loop")
+                .get_local("i")
+                .get_local("limit")
+                .less_than()
+                .jump_if_false("done")
+                .get_local("i")
+                .push_integer(1)
+                .add()
+                .set_local("i")
+                .jump("loop")
+                .add_label("done")
+                .get_local("i")
+                .return_value()
+        )
+        .build()
+    );
+
+    let mut vm = Vm::default();
+    vm.load_program(program.build());
+
+    let options = RunOptions {
+        fuel: Some(100),
+        ..Default::default()
+    };
+
+    let mut result = vm.run_with_options(None, None, options.clone()).unwrap();
+    let mut resumes = 0;
+    while let Some(execution) = result.suspended.take() {
+        resumes += 1;
+        result = vm.resume(execution, options.clone()).unwrap();
+    }
+
+    assert!(resumes > 10);
+    assert_eq!(result.result, Some(Variant::Integer(1_000)));
+}
+
+#[test]
+fn test_deadline_inside_coroutine() {
+    let mut program = Program::builder();
+    program.add_function(FunctionBuilder::default()
+        .name("main")
+        .arity(0)
+        .body(
+            BlockEncoder::default()
+                .try_begin("catch")
+                .push_function_reference("spin")
+                .make_coroutine(0)
+                .resume()
+                .return_value()
+                .add_label("catch")
+                .return_value()
+        )
+        .build()
+    );
+    program.add_function(FunctionBuilder::default()
+        .name("spin")
+        .arity(0)
+        .body(
+            BlockEncoder::default()
+                .add_label("start")
+                .jump("start")
+        )
+        .build()
+    );
+
+    let mut vm = Vm::default();
+    vm.load_program(program.build());
+
+    let options = RunOptions {
+        deadline: Some(std::time::Duration::from_millis(20)),
+        ..Default::default()
+    };
+
+    // The timeout is not a coroutine error that the handler could catch
+    let error = vm.run_with_options(None, None, options).unwrap_err();
+    assert_eq!(error.kind, VmErrorKind::Timeout);
+    assert_eq!(error.location().unwrap().function, "main");
+}
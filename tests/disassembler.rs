@@ -0,0 +1,179 @@
+use bytevm::prelude::*;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+fn round_trip(program: &Program) -> Program {
+    let source = program.to_string();
+    Program::from_assembly(&source).unwrap_or_else(|error| panic!("{}\n{}", error, source))
+}
+
+#[test]
+fn test_disassemble_function() {
+    let program = Program::from_assembly(include_str!("fixtures/fib.bvasm")).unwrap();
+
+    assert_eq!(program.to_string().lines().skip(9).collect::<Vec<_>>(), vec![
+        "",
+        "; function #1: fib, arity 1, 1 locals",
+        ".function fib 1",
+        "    .local arg_0",
+        "    get_local arg_0                      ; 0",
+        "    push 1                               ; 1",
+        "    less_equal                           ; 2",
+        "    jump_if_false L0                     ; 3",
+        "    get_local arg_0                      ; 4",
+        "    return                               ; 5",
+        "L0:",
+        "    get_local arg_0                      ; 6",
+        "    push 1                               ; 7",
+        "    sub                                  ; 8",
+        "    call fib                             ; 9",
+        "    get_local arg_0                      ; 10",
+        "    push 2                               ; 11",
+        "    sub                                  ; 12",
+        "    call fib                             ; 13",
+        "    add                                  ; 14",
+        "    return                               ; 15",
+        ".end",
+    ]);
+}
+
+#[test]
+fn test_function_without_program_shows_indices() {
+    let program = Program::from_assembly(include_str!("fixtures/fib.bvasm")).unwrap();
+    let source = program.functions[1].to_string();
+
+    assert!(source.starts_with("; function: fib, arity 1, 1 locals\n"));
+    assert!(source.contains("    call #1 "));
+}
+
+#[test]
+fn test_round_trip_fixtures() {
+    for source in [include_str!("fixtures/fib.bvasm"), include_str!("fixtures/counter.bvasm")] {
+        let program = Program::from_assembly(source).unwrap();
+        assert_eq!(round_trip(&program), program);
+    }
+}
+
+#[test]
+fn test_round_trip_builder_program() {
+    let mut program = Program::builder();
+    program.add_symbol("print_line".to_string(), SymbolEntry::NativeFunction { arity: 1 });
+    program.declare_global("greeting", Some(Variant::String("tab\there \"quoted\"\n".to_string())));
+    program.declare_global("counter", None);
+
+    program.add_function(FunctionBuilder::default()
+        .name("main")
+        .arity(0)
+        .body(BlockEncoder::default()
+            .declare_local("total")
+            .push_float(2.0)
+            .set_local("total")
+            .try_begin("handler")
+            .get_global("greeting")
+            .call_function_by_name("print_line")
+            .push_symbol("helper")
+            .call_indirect(0)
+            .pop()
+            .try_end()
+            .jump("done")
+            .add_label("handler")
+            .pop()
+            .add_label("done")
+            .push_integer(1)
+            .push_integer(2)
+            .call_function_with_argument_count("helper", 2)
+            .get_local("total")
+            .add()
+            .set_global("counter")
+            .make_closure("helper", &["total"])
+            .return_value()
+        )
+        .build()
+    );
+
+    program.add_function(FunctionBuilder::default()
+        .name("helper")
+        .min_arity(0)
+        .max_arity(1)
+        .rest_parameter()
+        .returns(1)
+        .body(BlockEncoder::default()
+            .declare_upvalue("captured")
+            .get_argument_count()
+            .push_float(f64::NEG_INFINITY)
+            .pop()
+            .return_value()
+        )
+        .build()
+    );
+
+    let program = program.build();
+    assert_eq!(round_trip(&program), program);
+}
+
+#[test]
+fn test_disassemble_constants() {
+    let array = Variant::Array(Rc::new(RefCell::new(vec![Variant::Integer(1), Variant::Float(0.5), Variant::Null])));
+    let dictionary = Variant::Dictionary(Rc::new(RefCell::new([
+        (Variant::String("b".to_string()), Variant::Boolean(false)),
+        (Variant::String("a".to_string()), array.clone())
+    ].into_iter().collect::<HashMap<_, _>>())));
+
+    let mut program = Program::builder();
+    program.add_function(FunctionBuilder::default()
+        .name("main")
+        .arity(0)
+        .body(BlockEncoder::default()
+            .add_instruction(Instruction::Push(dictionary))
+            .add_instruction(Instruction::Push(Variant::Index(3)))
+            .push_symbol("main")
+            .push_float(f64::NAN)
+            .return_values(4)
+        )
+        .build()
+    );
+    let program = program.build();
+    let source = program.to_string();
+
+    assert!(source.contains(r#"push {"a": [1, 0.5, null], "b": false}"#));
+    assert!(source.contains("push #3 "));
+    assert!(source.contains("push @main "));
+    assert!(source.contains("push NaN "));
+
+    // NaN is never equal to itself, so the round trip is compared through the printed source
+    assert_eq!(round_trip(&program).to_string(), source);
+}
+
+#[test]
+fn test_round_trip_module() {
+    let mut program = Program::builder();
+    program.module("geometry").export("area").import("math.square");
+    program.add_function(FunctionBuilder::default()
+        .name("area")
+        .arity(1)
+        .body(BlockEncoder::default()
+            .declare_local("side")
+            .get_local("side")
+            .call_function_by_name("square")
+            .call_function_by_name("round")
+            .return_value()
+        )
+        .build()
+    );
+    program.add_function(FunctionBuilder::default()
+        .name("round")
+        .arity(1)
+        .body(BlockEncoder::default()
+            .declare_local("value")
+            .get_local("value")
+            .return_value()
+        )
+        .build()
+    );
+
+    let program = program.build();
+    let source = program.to_string();
+    assert!(source.starts_with(".module geometry\n.export area\n.import math.square\n"));
+    assert_eq!(round_trip(&program), program);
+}
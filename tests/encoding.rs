@@ -0,0 +1,219 @@
+use bytevm::prelude::*;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+// Uses every instruction and kind of constant once
+fn complete_program() -> Program {
+    let array = Variant::Array(Rc::new(RefCell::new(vec![Variant::Integer(1), Variant::String(String::from("two"))])));
+    let dictionary = Variant::Dictionary(Rc::new(RefCell::new(HashMap::from([
+        (Variant::String(String::from("key")), Variant::Float(1.5))
+    ]))));
+
+    let instructions = vec![
+        Instruction::SetLocal(0),
+        Instruction::GetLocal(1),
+        Instruction::SetGlobal(GlobalTarget::Name(String::from("counter"))),
+        Instruction::GetGlobal(GlobalTarget::Slot(0)),
+        Instruction::CreateArray(2),
+        Instruction::GetArrayItem,
+        Instruction::SetArrayItem,
+        Instruction::GetArrayLength,
+        Instruction::CreateDictionary(1),
+        Instruction::GetDictionaryItem,
+        Instruction::SetDictionaryItem,
+        Instruction::GetDictionaryKeys,
+        Instruction::FunctionCall(CallTarget::Name(String::from("math::sqrt"))),
+        Instruction::TailCall(CallTarget::Index(0)),
+        Instruction::CallWithArgumentCount(CallTarget::Index(0), 3),
+        Instruction::GetArgumentCount,
+        Instruction::CallIndirect(2),
+        Instruction::MakeClosure(CallTarget::Index(0), vec![0, 1]),
+        Instruction::GetUpvalue(0),
+        Instruction::SetUpvalue(1),
+        Instruction::Return,
+        Instruction::ReturnN(2),
+        Instruction::EndFunction,
+        Instruction::Push(Variant::Null),
+        Instruction::Push(Variant::Integer(-42)),
+        Instruction::Push(Variant::Float(3.25)),
+        Instruction::Push(Variant::String(String::from("hello"))),
+        Instruction::Push(Variant::Boolean(true)),
+        Instruction::Push(Variant::SymbolReference(String::from("main"))),
+        Instruction::Push(Variant::Index(7)),
+        Instruction::Push(array),
+        Instruction::Push(dictionary),
+        Instruction::Push(Variant::Integer(-42)),
+        Instruction::Pop,
+        Instruction::Add,
+        Instruction::Sub,
+        Instruction::Mul,
+        Instruction::Div,
+        Instruction::Mod,
+        Instruction::Pow,
+        Instruction::Equal,
+        Instruction::LessThan,
+        Instruction::LessEqual,
+        Instruction::GreaterThan,
+        Instruction::GreaterEqual,
+        Instruction::NotEqual,
+        Instruction::Or,
+        Instruction::And,
+        Instruction::Not,
+        Instruction::Negate,
+        Instruction::Jump(0),
+        Instruction::JumpIfFalse(1),
+        Instruction::MakeCoroutine(1),
+        Instruction::Resume,
+        Instruction::Yield,
+        Instruction::IsCoroutineFinished,
+        Instruction::TryBegin(3),
+        Instruction::TryEnd,
+        Instruction::Throw,
+        Instruction::Print,
+        Instruction::Halt,
+        Instruction::Panic,
+    ];
+
+    Program {
+        symbol_table: HashMap::from([
+            (String::from("main"), SymbolEntry::UserDefinedFunction { index: 0 }),
+            (String::from("print_line"), SymbolEntry::NativeFunction { arity: 1 }),
+        ]),
+        functions: vec![Function {
+            name: String::from("main"),
+            arity: 1,
+            optional_count: 1,
+            variadic: true,
+            local_count: 4,
+            return_count: Some(2),
+            instructions
+        }],
+        globals: vec![Global { name: String::from("counter"), initial_value: Variant::Integer(10) }],
+        name: Some(String::from("app")),
+        exports: vec![String::from("main")],
+        imports: vec![Import { module: String::from("math"), name: String::from("sqrt") }],
+    }
+}
+
+#[test]
+fn test_round_trip() {
+    let program = complete_program();
+
+    let bytes = program.to_bytes().unwrap();
+
+    assert_eq!(&bytes[0..4], b"BVM\0");
+    assert_eq!(Program::from_bytes(&bytes).unwrap(), program);
+    assert_eq!(program.to_bytes().unwrap(), bytes);
+}
+
+#[test]
+fn test_run_decoded_program() {
+    let mut program = Program::builder();
+    program.add_function(FunctionBuilder::default()
+        .name("main")
+        .arity(0)
+        .body(
+            BlockEncoder::default()
+                .push_integer(6)
+                .call_function_by_name("double")
+                .return_value()
+        )
+        .build()
+    );
+    program.add_function(FunctionBuilder::default()
+        .name("double")
+        .arity(1)
+        .body(
+            BlockEncoder::default()
+                .declare_local("value")
+                .get_local("value")
+                .push_integer(2)
+                .mul()
+                .return_value()
+        )
+        .build()
+    );
+
+    let bytes = program.build().to_bytes().unwrap();
+    let mut vm = Vm::default();
+    vm.load_program(Program::from_bytes(&bytes).unwrap());
+    let result = vm.run(None, None).unwrap();
+
+    assert_eq!(result.result, Some(Variant::Integer(12)));
+}
+
+#[test]
+fn test_invalid_magic() {
+    assert_eq!(Program::from_bytes(b"ELF\0\x01\x00"), Err(DecodeError::InvalidMagic));
+    assert_eq!(Program::from_bytes(b""), Err(DecodeError::InvalidMagic));
+}
+
+#[test]
+fn test_newer_version_is_rejected() {
+    let mut bytes = complete_program().to_bytes().unwrap();
+    bytes[4..6].copy_from_slice(&(Program::FORMAT_VERSION + 1).to_le_bytes());
+
+    assert_eq!(Program::from_bytes(&bytes), Err(DecodeError::UnsupportedVersion {
+        version: Program::FORMAT_VERSION + 1,
+        supported: Program::FORMAT_VERSION
+    }));
+}
+
+#[test]
+fn test_truncated_bytes_are_rejected() {
+    let bytes = complete_program().to_bytes().unwrap();
+
+    for length in 0..bytes.len() {
+        assert!(Program::from_bytes(&bytes[..length]).is_err(), "Decoded a program from {} bytes", length);
+    }
+}
+
+#[test]
+fn test_trailing_bytes_are_rejected() {
+    let mut bytes = complete_program().to_bytes().unwrap();
+    let length = bytes.len();
+    bytes.push(0);
+
+    assert_eq!(Program::from_bytes(&bytes), Err(DecodeError::TrailingBytes { offset: length }));
+}
+
+#[test]
+fn test_invalid_instruction() {
+    let mut program = complete_program();
+    program.functions[0].instructions = vec![Instruction::Halt];
+    let mut bytes = program.to_bytes().unwrap();
+    let length = bytes.len();
+    bytes[length - 1] = 200;
+
+    assert_eq!(Program::from_bytes(&bytes), Err(DecodeError::InvalidTag { kind: "instruction", tag: 200, offset: length - 1 }));
+}
+
+#[test]
+fn test_huge_local_count_is_rejected() {
+    let bytes = include_bytes!("fixtures/huge_locals.bvm");
+
+    assert_eq!(Program::from_bytes(bytes), Err(DecodeError::InvalidLocalCount { local_count: 1 << 50, offset: 26 }));
+}
+
+#[test]
+fn test_local_count_below_parameters_is_rejected() {
+    let mut program = complete_program();
+    program.functions[0].local_count = 2;
+    let bytes = program.to_bytes().unwrap();
+
+    assert!(matches!(Program::from_bytes(&bytes), Err(DecodeError::InvalidLocalCount { local_count: 2, .. })));
+}
+
+#[test]
+fn test_runtime_constant_is_not_encoded() {
+    let mut program = complete_program();
+    program.functions[0].instructions = vec![Instruction::Push(Variant::Closure(Rc::new(Closure {
+        function_index: 0,
+        upvalues: Vec::new()
+    })))];
+
+    let error = program.to_bytes().unwrap_err();
+
+    assert_eq!(error.kind, VmErrorKind::UnsupportedConstant { type_name: "closure" });
+}
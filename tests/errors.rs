@@ -0,0 +1,54 @@
+use bytevm::prelude::*;
+
+#[test]
+fn test_error_backtrace() {
+
+    let mut program = Program::builder();
+
+    program.add_function(FunctionBuilder::default()
+        .name("main")
+        .arity(0)
+        .body(
+            BlockEncoder::default()
+                .push_integer(10)
+                .call_function_by_name("lookup")
+                .return_value()
+        )
+        .build()
+    );
+
+    program.add_function(FunctionBuilder::default()
+        .name("lookup")
+        .arity(1)
+        .body(
+            BlockEncoder::default()
+                .declare_local("key")
+                .create_dictionary(0)
+                .get_local("key")
+                .get_dictionary_item()
+                .return_value()
+        )
+        .build()
+    );
+
+    let mut vm = Vm::default();
+    vm.load_program(program.build());
+    let error = vm.run(None, None).unwrap_err();
+
+    assert_eq!(error.kind, VmErrorKind::KeyNotFound { key: Variant::Integer(10) });
+    assert_eq!(error.backtrace, vec![
+        StackTraceEntry { function: String::from("lookup"), function_index: 1, pc: 2 },
+        StackTraceEntry { function: String::from("main"), function_index: 0, pc: 1 },
+    ]);
+    assert_eq!(error.to_string(), "Dictionary key not found: 10\n    at lookup (pc 2)\n    at main (pc 1)");
+}
+
+#[test]
+fn test_entry_point_not_found() {
+
+    let mut vm = Vm::default();
+    let error = vm.run(Some(String::from("missing")), None).unwrap_err();
+
+    assert_eq!(error.kind, VmErrorKind::FunctionNotFound { name: String::from("missing") });
+    assert_eq!(error.location(), None);
+}
@@ -0,0 +1,191 @@
+use bytevm::prelude::*;
+
+#[test]
+fn test_catch_thrown_value() {
+    let mut program = Program::builder();
+    program.verify_stack_depth(true);
+    program.add_function(FunctionBuilder::default()
+        .name("main")
+        .arity(0)
+        .body(
+            BlockEncoder::default()
+                .try_begin("catch")
+                .push_string(String::from("boom"))
+                .throw()
+                .try_end()
+                .push_string(String::from("not thrown"))
+                .return_value()
+                .add_label("catch")
+                .return_value()
+        )
+        .build()
+    );
+
+    let mut vm = Vm::default();
+    vm.load_program(program.build());
+    let result = vm.run(None, None).unwrap();
+
+    assert_eq!(result.result, Some(Variant::String(String::from("boom"))));
+}
+
+#[test]
+fn test_unwind_frames_to_handler() {
+    let mut program = Program::builder();
+    program.verify_stack_depth(true);
+    program.add_function(FunctionBuilder::default()
+        .name("main")
+        .arity(0)
+        .body(
+            BlockEncoder::default()
+                .push_integer(100)
+                .try_begin("catch")
+                .push_integer(1)
+                .call_function_by_name("middle")
+                .try_end()
+                .add()
+                .return_value()
+                .add_label("catch")
+                .add()
+                .return_value()
+        )
+        .build()
+    );
+    program.add_function(FunctionBuilder::default()
+        .name("middle")
+        .arity(1)
+        .body(
+            BlockEncoder::default()
+                .declare_local("value")
+                .push_integer(2)
+                .push_integer(3)
+                .call_function_by_name("thrower")
+                .return_value()
+        )
+        .build()
+    );
+    program.add_function(FunctionBuilder::default()
+        .name("thrower")
+        .arity(1)
+        .body(
+            BlockEncoder::default()
+                .declare_local("value")
+                .push_integer(42)
+                .throw()
+        )
+        .build()
+    );
+
+    let mut vm = Vm::default();
+    vm.load_program(program.build());
+    let result = vm.run(None, None).unwrap();
+
+    // The operands pushed inside the try region and by the unwound functions are gone
+    assert_eq!(result.result, Some(Variant::Integer(142)));
+}
+
+#[test]
+fn test_catch_runtime_error() {
+    let mut program = Program::builder();
+    program.add_function(FunctionBuilder::default()
+        .name("main")
+        .arity(0)
+        .body(
+            BlockEncoder::default()
+                .try_begin("catch")
+                .create_dictionary(0)
+                .push_integer(10)
+                .get_dictionary_item()
+                .try_end()
+                .return_value()
+                .add_label("catch")
+                .return_value()
+        )
+        .build()
+    );
+
+    let mut vm = Vm::default();
+    vm.load_program(program.build());
+    let result = vm.run(None, None).unwrap();
+
+    assert_eq!(result.result, Some(Variant::String(String::from("Dictionary key not found: 10"))));
+}
+
+#[test]
+fn test_uncaught_throw_carries_value() {
+    let mut program = Program::builder();
+    program.add_function(FunctionBuilder::default()
+        .name("main")
+        .arity(0)
+        .body(
+            BlockEncoder::default()
+                .call_function_by_name("guarded")
+                .pop()
+                .push_integer(7)
+                .throw()
+        )
+        .build()
+    );
+
+    // Returning without ending the try region removes the handler
+    program.add_function(FunctionBuilder::default()
+        .name("guarded")
+        .arity(0)
+        .body(
+            BlockEncoder::default()
+                .try_begin("catch")
+                .push_integer(1)
+                .return_value()
+                .add_label("catch")
+                .return_value()
+        )
+        .build()
+    );
+
+    let mut vm = Vm::default();
+    vm.load_program(program.build());
+    let error = vm.run(None, None).unwrap_err();
+
+    assert_eq!(error.kind, VmErrorKind::Thrown { value: Variant::Integer(7) });
+    assert_eq!(error.location().unwrap().pc, 3);
+}
+
+#[test]
+fn test_panic_is_not_caught() {
+    let mut program = Program::builder();
+    program.add_function(FunctionBuilder::default()
+        .name("main")
+        .arity(0)
+        .body(
+            BlockEncoder::default()
+                .try_begin("catch")
+                .push_string(String::from("fatal"))
+                .panic()
+                .add_label("catch")
+                .return_value()
+        )
+        .build()
+    );
+
+    let mut vm = Vm::default();
+    vm.load_program(program.build());
+    let error = vm.run(None, None).unwrap_err();
+
+    assert_eq!(error.kind, VmErrorKind::Panic { value: Variant::String(String::from("fatal")) });
+}
+
+#[test]
+fn test_try_end_without_handler() {
+    let mut program = Program::builder();
+    program.add_function(FunctionBuilder::default()
+        .name("main")
+        .arity(0)
+        .body(BlockEncoder::default().try_end())
+        .build()
+    );
+
+    let mut vm = Vm::default();
+    vm.load_program(program.build());
+    let error = vm.run(None, None).unwrap_err();
+
+    assert_eq!(error.kind, VmErrorKind::HandlerNotFound);
+}
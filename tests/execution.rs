@@ -0,0 +1,206 @@
+use bytevm::prelude::*;
+use std::time::Duration;
+
+mod common;
+
+#[test]
+fn test_suspend_and_resume_with_fuel() {
+
+    let mut vm = Vm::default();
+    vm.load_program(common::main_program(
+        BlockEncoder::default()
+            .declare_local("i")
+            .push_integer(0)
+            .set_local("i")
+            .add_label("start")
+            .get_local("i")
+            .push_integer(1_000)
+            .less_than()
+            .jump_if_false("end")
+            .get_local("i")
+            .push_integer(1)
+            .add()
+            .set_local("i")
+            .jump("start")
+            .add_label("end")
+            .get_local("i")
+            .return_value()
+    ));
+
+    let options = RunOptions {
+        fuel: Some(100),
+        ..Default::default()
+    };
+
+    let mut result = vm.run_with_options(None, None, options.clone()).unwrap();
+    let mut resumes = 0;
+    while let Some(execution) = result.suspended.take() {
+        resumes += 1;
+        result = vm.resume(execution, options.clone()).unwrap();
+    }
+
+    assert!(resumes > 10);
+    assert!(!result.is_suspended());
+    assert_eq!(result.result, Some(Variant::Integer(1_000)));
+}
+
+#[test]
+fn test_resume_on_another_vm_is_error() {
+
+    let mut vm = Vm::default();
+    vm.load_program(common::main_program(
+        BlockEncoder::default()
+            .declare_local("i")
+            .push_integer(0)
+            .set_local("i")
+            .add_label("start")
+            .get_local("i")
+            .push_integer(1_000)
+            .less_than()
+            .jump_if_false("end")
+            .get_local("i")
+            .push_integer(1)
+            .add()
+            .set_local("i")
+            .jump("start")
+            .add_label("end")
+            .get_local("i")
+            .return_value()
+    ));
+
+    let options = RunOptions {
+        fuel: Some(100),
+        ..Default::default()
+    };
+    let execution = vm.run_with_options(None, None, options.clone()).unwrap().suspended.unwrap();
+
+    // The other Vm has no functions for the execution to continue in
+    let mut other = Vm::default();
+    let error = other.resume(execution, options).unwrap_err();
+    assert_eq!(error.kind, VmErrorKind::InvalidFunctionIndex { index: 0 });
+}
+
+#[test]
+fn test_fuel_exhausted_inside_native_callback() {
+
+    let mut program = Program::builder();
+    program.add_function(FunctionBuilder::default()
+        .name("main")
+        .arity(0)
+        .body(
+            BlockEncoder::default()
+                .call_function_by_name("invoke")
+                .return_value()
+        )
+        .build()
+    );
+    program.add_function(FunctionBuilder::default()
+        .name("spin")
+        .arity(0)
+        .body(
+            BlockEncoder::default()
+                .add_label("start")
+                .jump("start")
+        )
+        .build()
+    );
+
+    let mut vm = Vm::default();
+    vm.load_program(program.build());
+    vm.register_native_function(String::from("invoke"), 0, |context, _| context.call("spin", vec![]));
+
+    // The native function's frame can't be suspended, so the run stops instead
+    let error = vm.run_with_options(None, None, RunOptions { fuel: Some(1_000), ..Default::default() }).unwrap_err();
+    assert_eq!(error.kind, VmErrorKind::OutOfFuel);
+    assert_eq!(error.location().unwrap().function, "main");
+}
+
+#[test]
+fn test_deadline_stops_execution() {
+
+    let mut vm = Vm::default();
+    vm.load_program(common::main_program(
+        BlockEncoder::default()
+            .add_label("start")
+            .jump("start")
+    ));
+
+    let options = RunOptions {
+        deadline: Some(Duration::from_millis(20)),
+        ..Default::default()
+    };
+
+    let error = vm.run_with_options(None, None, options).unwrap_err();
+    assert_eq!(error.kind, VmErrorKind::Timeout);
+    assert_eq!(error.location().unwrap().function, "main");
+    assert_eq!(error.location().unwrap().pc, 0);
+}
+
+#[test]
+fn test_cancel_from_another_thread() {
+
+    let mut vm = Vm::default();
+    vm.load_program(common::main_program(
+        BlockEncoder::default()
+            .add_label("start")
+            .jump("start")
+    ));
+
+    let cancellation = CancellationHandle::new();
+    let handle = cancellation.clone();
+    let canceller = std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(20));
+        handle.cancel();
+    });
+
+    let options = RunOptions {
+        cancellation: Some(cancellation.clone()),
+        ..Default::default()
+    };
+
+    let error = vm.run_with_options(None, None, options).unwrap_err();
+    canceller.join().unwrap();
+
+    assert!(cancellation.is_cancelled());
+    assert_eq!(error.kind, VmErrorKind::Cancelled);
+    assert_eq!(error.location().unwrap().function, "main");
+}
+
+#[test]
+fn test_deadline_expires_inside_native_callback() {
+
+    let mut program = Program::builder();
+    program.add_function(FunctionBuilder::default()
+        .name("main")
+        .arity(0)
+        .body(
+            BlockEncoder::default()
+                .call_function_by_name("invoke")
+                .return_value()
+        )
+        .build()
+    );
+    program.add_function(FunctionBuilder::default()
+        .name("spin")
+        .arity(0)
+        .body(
+            BlockEncoder::default()
+                .add_label("start")
+                .jump("start")
+        )
+        .build()
+    );
+
+    let mut vm = Vm::default();
+    vm.load_program(program.build());
+    vm.register_native_function(String::from("invoke"), 0, |context, _| context.call("spin", vec![]));
+
+    let options = RunOptions {
+        deadline: Some(Duration::from_millis(20)),
+        ..Default::default()
+    };
+
+    let error = vm.run_with_options(None, None, options).unwrap_err();
+    assert_eq!(error.kind, VmErrorKind::Timeout);
+    assert_eq!(error.location().unwrap().function, "main");
+}
@@ -1,4 +1,6 @@
 use bytevm::prelude::*;
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
 
 #[test]
 fn test_user_defined_function() {
@@ -86,12 +88,797 @@ fn test_builtin_function() {
 
     let mut vm = Vm::default();
     vm.load_program(program.build());
-    vm.register_native_function(String::from("native_add"), 2,|args: Vec<Variant>| {
+    vm.register_native_function(String::from("native_add"), 2,|_, args: Vec<Variant>| {
         let a = args[0].clone();
         let b = args[1].clone();
-        Some(a + b)
+        Ok(Some((a + b)?))
     });
 
     let result = vm.run(None, None).unwrap().result.unwrap();
     assert_eq!(result, Variant::Integer(3));
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_entry_point_parameters() {
+
+    let mut program = Program::builder();
+
+    program.add_function(FunctionBuilder::default()
+        .name("add")
+        .arity(2)
+        .body(
+            BlockEncoder::default()
+                .declare_local("a")
+                .declare_local("b")
+                .get_local("a")
+                .get_local("b")
+                .add()
+                .return_value()
+        )
+        .build()
+    );
+
+    let mut vm = Vm::default();
+    vm.load_program(program.build());
+
+    let result = vm.run(Some(String::from("add")), Some(vec![Variant::Integer(1), Variant::Integer(2)])).unwrap().result.unwrap();
+    assert_eq!(result, Variant::Integer(3));
+
+    let result = vm.run(Some(String::from("add")), Some(vec![Variant::Integer(5), Variant::Integer(7)])).unwrap().result.unwrap();
+    assert_eq!(result, Variant::Integer(12));
+
+    let result = vm.run(Some(String::from("add")), Some(vec![Variant::Integer(1)]));
+    assert!(result.is_err());
+}
+
+#[test]
+fn test_entry_point_without_parameters() {
+
+    let mut program = Program::builder();
+
+    program.add_function(FunctionBuilder::default()
+        .name("main")
+        .arity(1)
+        .body(
+            BlockEncoder::default()
+                .declare_local("value")
+                .get_local("value")
+                .return_value()
+        )
+        .build()
+    );
+
+    let mut vm = Vm::default();
+    vm.load_program(program.build());
+
+    // Without parameters the arity is not checked and the arguments are null
+    let result = vm.run(None, None).unwrap().result;
+    assert_eq!(result, Some(Variant::Null));
+}
+
+#[test]
+fn test_host_call() {
+
+    let mut program = Program::builder();
+
+    program.add_function(FunctionBuilder::default()
+        .name("square")
+        .arity(1)
+        .body(
+            BlockEncoder::default()
+                .declare_local("x")
+                .get_local("x")
+                .get_local("x")
+                .mul()
+                .return_value()
+        )
+        .build()
+    );
+
+    let mut vm = Vm::default();
+    vm.load_program(program.build());
+
+    // Call by name
+    let result = vm.call("square", vec![Variant::Integer(3)]).unwrap();
+    assert_eq!(result, Some(Variant::Integer(9)));
+
+    // Call by resolved function index
+    let index = vm.resolve_function("square").unwrap();
+    for i in 0..100 {
+        let result = vm.call(index, vec![Variant::Integer(i)]).unwrap();
+        assert_eq!(result, Some(Variant::Integer(i * i)));
+    }
+
+    assert!(vm.call("missing", vec![]).is_err());
+    assert!(vm.call(index, vec![]).is_err());
+}
+
+#[test]
+fn test_native_function_with_state() {
+
+    let mut program = Program::builder();
+    program.add_function(FunctionBuilder::default()
+        .name("main")
+        .arity(0)
+        .body(
+            BlockEncoder::default()
+                .call_function_by_name("next")
+                .call_function_by_name("next")
+                .add()
+                .return_value()
+        )
+        .build()
+    );
+
+    let mut vm = Vm::default();
+    vm.load_program(program.build());
+
+    let counter = Cell::new(0);
+    vm.register_native_function(String::from("next"), 0, move |_, _| {
+        counter.set(counter.get() + 1);
+        Ok(Some(Variant::Integer(counter.get())))
+    });
+
+    let result = vm.run(None, None).unwrap().result.unwrap();
+    assert_eq!(result, Variant::Integer(3));
+
+    // Clones share the native function and the state it captured
+    let mut clone = vm.clone();
+    assert_eq!(clone, vm);
+
+    let result = clone.run(None, None).unwrap().result.unwrap();
+    assert_eq!(result, Variant::Integer(7));
+}
+
+#[test]
+fn test_native_function_error() {
+
+    let mut program = Program::builder();
+    program.add_function(FunctionBuilder::default()
+        .name("main")
+        .arity(0)
+        .body(
+            BlockEncoder::default()
+                .call_function_by_name("fail")
+                .return_value()
+        )
+        .build()
+    );
+
+    let mut vm = Vm::default();
+    vm.load_program(program.build());
+    vm.register_native_function(String::from("fail"), 0, |_, _| {
+        Err(VmErrorKind::RuntimeError { message: String::from("database unavailable") }.into())
+    });
+
+    let error = vm.run(None, None).unwrap_err();
+    assert_eq!(error.kind, VmErrorKind::NativeFunctionError {
+        name: String::from("fail"),
+        error: Box::new(VmErrorKind::RuntimeError { message: String::from("database unavailable") }.into())
+    });
+    assert_eq!(error.location().unwrap().function, "main");
+    assert_eq!(error.location().unwrap().pc, 0);
+}
+
+#[test]
+fn test_native_function_calls_bytecode() {
+
+    let mut program = Program::builder();
+    program.add_function(FunctionBuilder::default()
+        .name("main")
+        .arity(0)
+        .body(
+            BlockEncoder::default()
+                .push_integer(1)
+                .push_integer(2)
+                .push_integer(3)
+                .create_array(3)
+                .push_function_reference("double")
+                .call_function_by_name("map")
+                .return_value()
+        )
+        .build()
+    );
+
+    program.add_function(FunctionBuilder::default()
+        .name("double")
+        .arity(1)
+        .body(
+            BlockEncoder::default()
+                .declare_local("x")
+                .get_local("x")
+                .push_integer(2)
+                .mul()
+                .return_value()
+        )
+        .build()
+    );
+
+    let mut vm = Vm::default();
+    vm.load_program(program.build());
+    vm.register_native_function(String::from("map"), 2, |context, args| {
+        let (Variant::Array(array), Variant::SymbolReference(callback)) = (&args[0], &args[1]) else {
+            return Err(VmErrorKind::RuntimeError { message: String::from("map expects an array and a function") }.into());
+        };
+        let mut mapped = Vec::new();
+        for item in array.borrow().iter() {
+            mapped.push(context.call(callback.as_str(), vec![item.clone()])?.unwrap_or(Variant::Null));
+        }
+        Ok(Some(Variant::Array(Rc::new(RefCell::new(mapped)))))
+    });
+
+    let result = vm.run(None, None).unwrap().result.unwrap();
+    assert_eq!(result, Variant::Array(Rc::new(RefCell::new(vec![
+        Variant::Integer(2),
+        Variant::Integer(4),
+        Variant::Integer(6),
+    ]))));
+}
+
+#[test]
+fn test_native_function_reentered_from_callback() {
+
+    let mut program = Program::builder();
+    program.add_function(FunctionBuilder::default()
+        .name("main")
+        .arity(0)
+        .body(
+            BlockEncoder::default()
+                .push_integer(3)
+                .push_function_reference("double_twice")
+                .call_function_by_name("apply")
+                .return_value()
+        )
+        .build()
+    );
+
+    // Calls apply again while the outer call of apply is still running
+    program.add_function(FunctionBuilder::default()
+        .name("double_twice")
+        .arity(1)
+        .body(
+            BlockEncoder::default()
+                .declare_local("x")
+                .get_local("x")
+                .get_local("x")
+                .add()
+                .push_function_reference("double")
+                .call_function_by_name("apply")
+                .return_value()
+        )
+        .build()
+    );
+
+    program.add_function(FunctionBuilder::default()
+        .name("double")
+        .arity(1)
+        .body(
+            BlockEncoder::default()
+                .declare_local("x")
+                .get_local("x")
+                .push_integer(2)
+                .mul()
+                .return_value()
+        )
+        .build()
+    );
+
+    let mut vm = Vm::default();
+    vm.load_program(program.build());
+    vm.register_native_function(String::from("apply"), 2, |context, args| {
+        let Variant::SymbolReference(callback) = &args[1] else {
+            return Err(VmErrorKind::RuntimeError { message: String::from("apply expects a function") }.into());
+        };
+        context.call(callback.as_str(), vec![args[0].clone()])
+    });
+
+    let result = vm.run(None, None).unwrap().result.unwrap();
+    assert_eq!(result, Variant::Integer(12));
+}
+
+#[test]
+fn test_typed_native_function() {
+
+    let mut program = Program::builder();
+    program.add_function(FunctionBuilder::default()
+        .name("main")
+        .arity(0)
+        .body(
+            BlockEncoder::default()
+                .push_integer(15)
+                .push_integer(0)
+                .push_integer(10)
+                .call_function_by_name("clamp")
+                .return_value()
+        )
+        .build()
+    );
+
+    program.add_function(FunctionBuilder::default()
+        .name("bad")
+        .arity(0)
+        .body(
+            BlockEncoder::default()
+                .push_integer(15)
+                .push_string(String::from("zero"))
+                .push_integer(10)
+                .call_function_by_name("clamp")
+                .return_value()
+        )
+        .build()
+    );
+
+    let mut vm = Vm::default();
+    vm.load_program(program.build());
+    vm.register_fn("clamp", |x: i64, lo: i64, hi: i64| -> i64 { x.clamp(lo, hi) });
+
+    let result = vm.run(None, None).unwrap().result.unwrap();
+    assert_eq!(result, Variant::Integer(10));
+
+    let error = vm.run(Some(String::from("bad")), None).unwrap_err();
+    assert_eq!(error.kind, VmErrorKind::NativeFunctionError {
+        name: String::from("clamp"),
+        error: Box::new(VmErrorKind::ArgumentTypeError { index: 1, expected: "integer", actual: "string" }.into())
+    });
+    assert_eq!(error.location().unwrap().function, "bad");
+    assert_eq!(error.location().unwrap().pc, 3);
+}
+
+#[test]
+fn test_typed_native_function_returns_size_as_integer() {
+
+    let mut program = Program::builder();
+    program.add_function(FunctionBuilder::default()
+        .name("main")
+        .arity(0)
+        .body(
+            BlockEncoder::default()
+                .push_string(String::from("four"))
+                .call_function_by_name("len")
+                .push_integer(1)
+                .add()
+                .return_value()
+        )
+        .build()
+    );
+
+    let mut vm = Vm::default();
+    vm.load_program(program.build());
+    vm.register_fn("len", |s: String| s.len());
+
+    let result = vm.run(None, None).unwrap().result.unwrap();
+    assert_eq!(result, Variant::Integer(5));
+}
+
+#[test]
+fn test_call_indirect_user_defined_function() {
+    let mut program = Program::builder();
+
+    // Calls the function reference in the first argument with the second argument
+    program.add_function(FunctionBuilder::default()
+        .name("apply")
+        .arity(2)
+        .body(
+            BlockEncoder::default()
+                .declare_local("f")
+                .declare_local("x")
+                .get_local("x")
+                .get_local("f")
+                .call_indirect(1)
+                .return_value()
+        )
+        .build()
+    );
+
+    program.add_function(FunctionBuilder::default()
+        .name("main")
+        .arity(0)
+        .body(
+            BlockEncoder::default()
+                .push_function_reference("double")
+                .push_integer(20)
+                .call_function_by_name("apply")
+                .return_value()
+        )
+        .build()
+    );
+
+    program.add_function(FunctionBuilder::default()
+        .name("double")
+        .arity(1)
+        .body(
+            BlockEncoder::default()
+                .declare_local("x")
+                .get_local("x")
+                .push_integer(2)
+                .mul()
+                .return_value()
+        )
+        .build()
+    );
+
+    let mut vm = Vm::default();
+    vm.load_program(program.build());
+    let result = vm.run(None, None).unwrap().result.unwrap();
+
+    assert_eq!(result, Variant::Integer(40));
+}
+
+#[test]
+fn test_call_indirect_native_function() {
+    let mut program = Program::builder();
+
+    // Calls the function reference in the first argument with the second argument
+    program.add_function(FunctionBuilder::default()
+        .name("apply")
+        .arity(2)
+        .body(
+            BlockEncoder::default()
+                .declare_local("f")
+                .declare_local("x")
+                .get_local("x")
+                .get_local("f")
+                .call_indirect(1)
+                .return_value()
+        )
+        .build()
+    );
+
+    program.add_function(FunctionBuilder::default()
+        .name("main")
+        .arity(0)
+        .body(
+            BlockEncoder::default()
+                .push_function_reference("increment")
+                .push_integer(20)
+                .call_function_by_name("apply")
+                .return_value()
+        )
+        .build()
+    );
+
+    let mut vm = Vm::default();
+    vm.load_program(program.build());
+    vm.register_fn("increment", |x: i64| x + 1);
+    let result = vm.run(None, None).unwrap().result.unwrap();
+
+    assert_eq!(result, Variant::Integer(21));
+}
+
+#[test]
+fn test_call_indirect_arity_mismatch() {
+    let mut program = Program::builder();
+
+    // Calls the function reference in the first argument with the second argument
+    program.add_function(FunctionBuilder::default()
+        .name("apply")
+        .arity(2)
+        .body(
+            BlockEncoder::default()
+                .declare_local("f")
+                .declare_local("x")
+                .get_local("x")
+                .get_local("f")
+                .call_indirect(2)
+                .return_value()
+        )
+        .build()
+    );
+
+    program.add_function(FunctionBuilder::default()
+        .name("main")
+        .arity(0)
+        .body(
+            BlockEncoder::default()
+                .push_function_reference("double")
+                .push_integer(20)
+                .call_function_by_name("apply")
+                .return_value()
+        )
+        .build()
+    );
+
+    program.add_function(FunctionBuilder::default()
+        .name("double")
+        .arity(1)
+        .body(
+            BlockEncoder::default()
+                .declare_local("x")
+                .get_local("x")
+                .push_integer(2)
+                .mul()
+                .return_value()
+        )
+        .build()
+    );
+
+    let mut vm = Vm::default();
+    vm.load_program(program.build());
+    let error = vm.run(None, None).unwrap_err();
+
+    assert_eq!(error.kind, VmErrorKind::ArityMismatch { function: String::from("double"), expected: 1, actual: 2 });
+    assert_eq!(error.location().unwrap().function, "apply");
+    assert_eq!(error.location().unwrap().pc, 2);
+}
+
+#[test]
+fn test_call_indirect_unknown_function() {
+    let mut program = Program::builder();
+
+    // Calls the function reference in the first argument with the second argument
+    program.add_function(FunctionBuilder::default()
+        .name("apply")
+        .arity(2)
+        .body(
+            BlockEncoder::default()
+                .declare_local("f")
+                .declare_local("x")
+                .get_local("x")
+                .get_local("f")
+                .call_indirect(1)
+                .return_value()
+        )
+        .build()
+    );
+
+    program.add_function(FunctionBuilder::default()
+        .name("main")
+        .arity(0)
+        .body(
+            BlockEncoder::default()
+                .push_function_reference("missing")
+                .push_integer(20)
+                .call_function_by_name("apply")
+                .return_value()
+        )
+        .build()
+    );
+
+    let mut vm = Vm::default();
+    vm.load_program(program.build());
+    let error = vm.run(None, None).unwrap_err();
+
+    assert_eq!(error.kind, VmErrorKind::FunctionNotFound { name: String::from("missing") });
+}
+
+#[test]
+fn test_rest_parameter_collects_extra_arguments() {
+
+    let mut program = Program::builder();
+
+    program.add_function(FunctionBuilder::default()
+        .name("main")
+        .arity(0)
+        .body(
+            BlockEncoder::default()
+                .push_string(String::from("values"))
+                .push_integer(1)
+                .push_integer(2)
+                .push_integer(3)
+                .call_function_with_argument_count("count_rest", 4)
+                .push_string(String::from("none"))
+                .call_function_with_argument_count("count_rest", 1)
+                .add()
+                .return_value()
+        )
+        .build()
+    );
+
+    // Returns the number of arguments after the first one
+    program.add_function(FunctionBuilder::default()
+        .name("count_rest")
+        .arity(1)
+        .rest_parameter()
+        .body(
+            BlockEncoder::default()
+                .declare_local("name")
+                .declare_local("rest")
+                .get_local("rest")
+                .get_array_length()
+                .return_value()
+        )
+        .build()
+    );
+
+    let mut vm = Vm::default();
+    vm.load_program(program.build());
+    let result = vm.run(None, None).unwrap().result.unwrap();
+
+    assert_eq!(result, Variant::Integer(3));
+}
+
+#[test]
+fn test_default_arguments_from_argument_count() {
+
+    let mut program = Program::builder();
+
+    program.add_function(FunctionBuilder::default()
+        .name("main")
+        .arity(0)
+        .body(
+            BlockEncoder::default()
+                .push_integer(1)
+                .call_function_by_name("add_step")
+                .push_integer(2)
+                .push_integer(5)
+                .call_function_with_argument_count("add_step", 2)
+                .add()
+                .return_value()
+        )
+        .build()
+    );
+
+    // Adds the step to the value, the step defaults to 10
+    program.add_function(FunctionBuilder::default()
+        .name("add_step")
+        .min_arity(1)
+        .max_arity(2)
+        .body(
+            BlockEncoder::default()
+                .declare_local("value")
+                .declare_local("step")
+                .get_argument_count()
+                .push_integer(2)
+                .less_than()
+                .jump_if_false("add")
+                .push_integer(10)
+                .set_local("step")
+                .add_label("add")
+                .get_local("value")
+                .get_local("step")
+                .add()
+                .return_value()
+        )
+        .build()
+    );
+
+    let mut vm = Vm::default();
+    vm.load_program(program.build());
+    let result = vm.run(None, None).unwrap().result.unwrap();
+    assert_eq!(result, Variant::Integer(18));
+
+    assert_eq!(vm.call("add_step", vec![Variant::Integer(1)]).unwrap(), Some(Variant::Integer(11)));
+    assert_eq!(vm.call("add_step", vec![Variant::Integer(1), Variant::Integer(1)]).unwrap(), Some(Variant::Integer(2)));
+
+    let error = vm.call("add_step", vec![Variant::Integer(1), Variant::Integer(1), Variant::Integer(1)]).unwrap_err();
+    assert_eq!(error.kind, VmErrorKind::ArityMismatch { function: String::from("add_step"), expected: 2, actual: 3 });
+}
+
+#[test]
+fn test_too_many_arguments_without_rest_parameter() {
+
+    let mut program = Program::builder();
+
+    program.add_function(FunctionBuilder::default()
+        .name("main")
+        .arity(0)
+        .body(
+            BlockEncoder::default()
+                .push_integer(1)
+                .push_integer(2)
+                .call_function_with_argument_count("identity", 2)
+                .return_value()
+        )
+        .build()
+    );
+
+    program.add_function(FunctionBuilder::default()
+        .name("identity")
+        .arity(1)
+        .body(
+            BlockEncoder::default()
+                .declare_local("value")
+                .get_local("value")
+                .return_value()
+        )
+        .build()
+    );
+
+    let mut vm = Vm::default();
+    vm.load_program(program.build());
+    let error = vm.run(None, None).unwrap_err();
+
+    assert_eq!(error.kind, VmErrorKind::ArityMismatch { function: String::from("identity"), expected: 1, actual: 2 });
+    assert_eq!(error.location().unwrap().pc, 2);
+}
+
+#[test]
+fn test_multiple_return_values_in_caller() {
+
+    let mut program = Program::builder();
+
+    program.add_function(FunctionBuilder::default()
+        .name("main")
+        .arity(0)
+        .body(
+            BlockEncoder::default()
+                .declare_local("quotient")
+                .declare_local("remainder")
+                .push_integer(17)
+                .push_integer(5)
+                .call_function_by_name("divmod")
+                .set_local("remainder")
+                .set_local("quotient")
+                .get_local("quotient")
+                .push_integer(10)
+                .mul()
+                .get_local("remainder")
+                .add()
+                .return_value()
+        )
+        .build()
+    );
+
+    program.add_function(FunctionBuilder::default()
+        .name("divmod")
+        .arity(2)
+        .returns(2)
+        .body(
+            BlockEncoder::default()
+                .declare_local("a")
+                .declare_local("b")
+                .get_local("a")
+                .get_local("b")
+                .div()
+                .get_local("a")
+                .get_local("b")
+                .modulus()
+                .return_values(2)
+        )
+        .build()
+    );
+
+    let mut vm = Vm::default();
+    vm.load_program(program.build());
+    let result = vm.run(None, None).unwrap().result.unwrap();
+
+    assert_eq!(result, Variant::Integer(32));
+}
+
+#[test]
+fn test_multiple_return_values_to_host() {
+
+    let mut program = Program::builder();
+
+    program.add_function(FunctionBuilder::default()
+        .name("main")
+        .arity(0)
+        .body(
+            BlockEncoder::default()
+                .push_integer(17)
+                .push_integer(5)
+                .call_function_by_name("divmod")
+                .return_values(2)
+        )
+        .build()
+    );
+
+    program.add_function(FunctionBuilder::default()
+        .name("divmod")
+        .arity(2)
+        .returns(2)
+        .body(
+            BlockEncoder::default()
+                .declare_local("a")
+                .declare_local("b")
+                .get_local("a")
+                .get_local("b")
+                .div()
+                .get_local("a")
+                .get_local("b")
+                .modulus()
+                .return_values(2)
+        )
+        .build()
+    );
+
+    let mut vm = Vm::default();
+    vm.load_program(program.build());
+    let result = vm.run(None, None).unwrap();
+
+    assert_eq!(result.results, vec![Variant::Integer(3), Variant::Integer(2)]);
+    assert_eq!(result.result, Some(Variant::Integer(3)));
+
+    let result = vm.run(Some(String::from("divmod")), Some(vec![Variant::Integer(9), Variant::Integer(4)])).unwrap();
+    assert_eq!(result.results, vec![Variant::Integer(2), Variant::Integer(1)]);
+}
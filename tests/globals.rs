@@ -0,0 +1,106 @@
+use bytevm::prelude::*;
+
+#[test]
+fn test_globals_are_shared_between_functions() {
+    let mut program = Program::builder();
+    program.declare_global("count", Some(Variant::Integer(10)));
+    program.add_function(FunctionBuilder::default()
+        .name("increment")
+        .arity(0)
+        .body(
+            BlockEncoder::default()
+                .get_global("count")
+                .push_integer(1)
+                .add()
+                .set_global("count")
+                .end_function()
+        )
+        .build()
+    );
+    program.add_function(FunctionBuilder::default()
+        .name("main")
+        .arity(0)
+        .body(
+            BlockEncoder::default()
+                .call_function_by_name("increment")
+                .call_function_by_name("increment")
+                .get_global("count")
+                .return_value()
+        )
+        .build()
+    );
+
+    let mut vm = Vm::default();
+    vm.load_program(program.build());
+
+    let result = vm.run(None, None).unwrap();
+    assert_eq!(result.result, Some(Variant::Integer(12)));
+}
+
+#[test]
+fn test_globals_persist_between_runs() {
+    let mut program = Program::builder();
+    program.declare_global("count", Some(Variant::Integer(10)));
+    program.add_function(FunctionBuilder::default()
+        .name("increment")
+        .arity(0)
+        .body(
+            BlockEncoder::default()
+                .get_global("count")
+                .push_integer(1)
+                .add()
+                .set_global("count")
+                .end_function()
+        )
+        .build()
+    );
+    program.add_function(FunctionBuilder::default()
+        .name("main")
+        .arity(0)
+        .body(
+            BlockEncoder::default()
+                .call_function_by_name("increment")
+                .call_function_by_name("increment")
+                .get_global("count")
+                .return_value()
+        )
+        .build()
+    );
+
+    let mut vm = Vm::default();
+    vm.load_program(program.build());
+
+    assert_eq!(vm.get_global("count"), Some(&Variant::Integer(10)));
+
+    vm.run(None, None).unwrap();
+    assert_eq!(vm.get_global("count"), Some(&Variant::Integer(12)));
+
+    vm.set_global("count", 100i64).unwrap();
+    let result = vm.run(None, None).unwrap();
+    assert_eq!(result.result, Some(Variant::Integer(102)));
+}
+
+#[test]
+fn test_undeclared_global() {
+    let mut program = Program::builder();
+    program.add_function(FunctionBuilder::default()
+        .name("main")
+        .arity(0)
+        .body(
+            BlockEncoder::default()
+                .get_global("missing")
+                .return_value()
+        )
+        .build()
+    );
+
+    let mut vm = Vm::default();
+    vm.load_program(program.build());
+
+    let error = vm.run(None, None).unwrap_err();
+    assert_eq!(error.kind, VmErrorKind::GlobalNotFound { name: String::from("missing") });
+    assert_eq!(error.location().unwrap().pc, 0);
+
+    assert_eq!(vm.get_global("missing"), None);
+    assert_eq!(vm.set_global("missing", 1i64).unwrap_err().kind, VmErrorKind::GlobalNotFound { name: String::from("missing") });
+}
@@ -0,0 +1,203 @@
+use bytevm::prelude::*;
+
+mod common;
+
+#[test]
+fn test_infinite_recursion_exceeds_call_depth() {
+    let mut program = Program::builder();
+    program.add_function(FunctionBuilder::default()
+        .name("main")
+        .arity(0)
+        .body(
+            BlockEncoder::default()
+                .call_function_by_name("main")
+                .return_value()
+        )
+        .build()
+    );
+
+    let mut vm = Vm::default();
+    vm.set_limits(VmLimits {
+        max_call_depth: Some(64),
+        ..Default::default()
+    });
+    vm.load_program(program.build());
+    let error = vm.run(None, None).unwrap_err();
+
+    assert_eq!(error.kind, VmErrorKind::CallDepthExceeded { limit: 64 });
+    assert_eq!(error.backtrace.len(), 64);
+}
+
+#[test]
+fn test_call_depth_includes_native_callbacks() {
+    let mut program = Program::builder();
+    program.add_function(FunctionBuilder::default()
+        .name("main")
+        .arity(0)
+        .body(
+            BlockEncoder::default()
+                .call_function_by_name("invoke")
+                .return_value()
+        )
+        .build()
+    );
+    program.add_function(FunctionBuilder::default()
+        .name("recurse")
+        .arity(0)
+        .body(
+            BlockEncoder::default()
+                .call_function_by_name("recurse")
+                .return_value()
+        )
+        .build()
+    );
+
+    let mut vm = Vm::default();
+    vm.set_limits(VmLimits {
+        max_call_depth: Some(16),
+        ..Default::default()
+    });
+    vm.load_program(program.build());
+    vm.register_native_function(String::from("invoke"), 0, |context, _| context.call("recurse", vec![]));
+
+    // Limits stop the whole run and are reported where the native function was called
+    let error = vm.run(None, None).unwrap_err();
+    assert_eq!(error.kind, VmErrorKind::CallDepthExceeded { limit: 16 });
+    assert_eq!(error.location().unwrap().function, "main");
+}
+
+#[test]
+fn test_unbounded_pushes_overflow_stack() {
+    let mut vm = Vm::default();
+    vm.set_limits(VmLimits {
+        max_stack_size: Some(100),
+        ..Default::default()
+    });
+    vm.load_program(common::main_program(
+        BlockEncoder::default()
+            .add_label("start")
+            .push_integer(1)
+            .jump("start")
+    ));
+    let error = vm.run(None, None).unwrap_err();
+
+    assert_eq!(error.kind, VmErrorKind::StackOverflow { limit: 100 });
+    assert_eq!(error.location().unwrap().pc, 0);
+}
+
+#[test]
+fn test_entry_function_locals_overflow_stack() {
+    let mut vm = Vm::default();
+    vm.set_limits(VmLimits {
+        max_stack_size: Some(2),
+        ..Default::default()
+    });
+    vm.load_program(common::main_program(
+        BlockEncoder::default()
+            .declare_local("a")
+            .declare_local("b")
+            .declare_local("c")
+            .push_null()
+            .return_value()
+    ));
+    let error = vm.run(None, None).unwrap_err();
+
+    // The locals are checked before the function starts, so there is no location
+    assert_eq!(error.kind, VmErrorKind::StackOverflow { limit: 2 });
+    assert!(error.location().is_none());
+}
+
+#[test]
+fn test_create_array_exceeds_collection_length() {
+    let mut vm = Vm::default();
+    vm.set_limits(VmLimits {
+        max_collection_length: Some(2),
+        ..Default::default()
+    });
+    vm.load_program(common::main_program(
+        BlockEncoder::default()
+            .push_integer(1)
+            .push_integer(2)
+            .push_integer(3)
+            .create_array(3)
+            .return_value()
+    ));
+    let error = vm.run(None, None).unwrap_err();
+
+    assert_eq!(error.kind, VmErrorKind::CollectionTooLarge { length: 3, limit: 2 });
+    assert_eq!(error.location().unwrap().pc, 3);
+}
+
+#[test]
+fn test_dictionary_insert_exceeds_collection_length() {
+    let mut vm = Vm::default();
+    vm.set_limits(VmLimits {
+        max_collection_length: Some(1),
+        ..Default::default()
+    });
+    vm.load_program(common::main_program(
+        BlockEncoder::default()
+            .declare_local("table")
+            .create_dictionary(0)
+            .set_local("table")
+            .get_local("table")
+            .push_integer(1)
+            .push_integer(1)
+            .set_dictionary_item()
+            .get_local("table")
+            .push_integer(2)
+            .push_integer(2)
+            .set_dictionary_item()
+            .get_local("table")
+            .return_value()
+    ));
+    let error = vm.run(None, None).unwrap_err();
+
+    assert_eq!(error.kind, VmErrorKind::CollectionTooLarge { length: 2, limit: 1 });
+}
+
+#[test]
+fn test_string_concatenation_exceeds_heap_limit() {
+    let mut vm = Vm::default();
+    vm.set_limits(VmLimits {
+        max_heap_bytes: Some(1 << 20),
+        ..Default::default()
+    });
+    vm.load_program(common::main_program(
+        BlockEncoder::default()
+            .declare_local("text")
+            .push_string(String::from("x"))
+            .set_local("text")
+            .add_label("start")
+            .get_local("text")
+            .get_local("text")
+            .add()
+            .set_local("text")
+            .jump("start")
+    ));
+    let error = vm.run(None, None).unwrap_err();
+
+    assert_eq!(error.kind, VmErrorKind::HeapLimitExceeded { limit: 1 << 20 });
+}
+
+#[test]
+fn test_program_within_limits_runs() {
+    let mut vm = Vm::default();
+    vm.set_limits(VmLimits {
+        max_call_depth: Some(1),
+        max_stack_size: Some(2),
+        max_collection_length: Some(2),
+        max_heap_bytes: Some(1024),
+    });
+    vm.load_program(common::main_program(
+        BlockEncoder::default()
+            .push_integer(1)
+            .push_integer(2)
+            .create_array(2)
+            .get_array_length()
+            .return_value()
+    ));
+    let result = vm.run(None, None).unwrap();
+
+    assert_eq!(result.result, Some(Variant::Integer(2)));
+}
@@ -0,0 +1,292 @@
+use bytevm::prelude::*;
+
+#[test]
+fn test_link_programs() {
+    // Library with a helper that is called by index from square
+    let mut library = Program::builder();
+    library.add_function(FunctionBuilder::default()
+        .name("square")
+        .arity(1)
+        .body(
+            BlockEncoder::default()
+                .declare_local("value")
+                .get_local("value")
+                .get_local("value")
+                .call_function_by_name("multiply")
+                .return_value()
+        )
+        .build()
+    );
+    library.add_function(FunctionBuilder::default()
+        .name("multiply")
+        .arity(2)
+        .body(
+            BlockEncoder::default()
+                .declare_local("a")
+                .declare_local("b")
+                .get_local("a")
+                .get_local("b")
+                .mul()
+                .return_value()
+        )
+        .build()
+    );
+
+    // Application that calls its own function by index and the library's square by name
+    let mut application = Program::builder();
+    application.declare_global("offset", Some(Variant::Integer(1)));
+    application.add_function(FunctionBuilder::default()
+        .name("main")
+        .arity(0)
+        .body(
+            BlockEncoder::default()
+                .push_integer(3)
+                .call_function_by_name("increment")
+                .call_function_by_name("square")
+                .return_value()
+        )
+        .build()
+    );
+    application.add_function(FunctionBuilder::default()
+        .name("increment")
+        .arity(1)
+        .body(
+            BlockEncoder::default()
+                .declare_local("value")
+                .get_local("value")
+                .get_global("offset")
+                .add()
+                .return_value()
+        )
+        .build()
+    );
+
+    let mut linker = Linker::default();
+    linker.add_program(library.build()).unwrap();
+    linker.add_program(application.build()).unwrap();
+    let program = linker.link().unwrap();
+
+    assert_eq!(program.functions[2].instructions[1], Instruction::FunctionCall(CallTarget::Index(3)));
+    assert_eq!(program.functions[2].instructions[2], Instruction::FunctionCall(CallTarget::Index(0)));
+
+    let mut vm = Vm::default();
+    vm.load_program(program);
+    let result = vm.run(Some(String::from("main")), None).unwrap();
+
+    assert_eq!(result.result, Some(Variant::Integer(16)));
+}
+
+#[test]
+fn test_load_programs_relocates_indices() {
+    // Library with a helper that is called by index from square
+    let mut library = Program::builder();
+    library.add_function(FunctionBuilder::default()
+        .name("square")
+        .arity(1)
+        .body(
+            BlockEncoder::default()
+                .declare_local("value")
+                .get_local("value")
+                .get_local("value")
+                .call_function_by_name("multiply")
+                .return_value()
+        )
+        .build()
+    );
+    library.add_function(FunctionBuilder::default()
+        .name("multiply")
+        .arity(2)
+        .body(
+            BlockEncoder::default()
+                .declare_local("a")
+                .declare_local("b")
+                .get_local("a")
+                .get_local("b")
+                .mul()
+                .return_value()
+        )
+        .build()
+    );
+
+    // Application that calls its own function by index and the library's square by name
+    let mut application = Program::builder();
+    application.declare_global("offset", Some(Variant::Integer(1)));
+    application.add_function(FunctionBuilder::default()
+        .name("main")
+        .arity(0)
+        .body(
+            BlockEncoder::default()
+                .push_integer(3)
+                .call_function_by_name("increment")
+                .call_function_by_name("square")
+                .return_value()
+        )
+        .build()
+    );
+    application.add_function(FunctionBuilder::default()
+        .name("increment")
+        .arity(1)
+        .body(
+            BlockEncoder::default()
+                .declare_local("value")
+                .get_local("value")
+                .get_global("offset")
+                .add()
+                .return_value()
+        )
+        .build()
+    );
+
+    let mut first = Program::builder();
+    first.declare_global("unused", None);
+    first.add_function(FunctionBuilder::default()
+        .name("first")
+        .arity(0)
+        .body(BlockEncoder::default().push_integer(0).return_value())
+        .build()
+    );
+
+    let mut vm = Vm::default();
+    vm.load_program(first.build());
+    vm.load_program(library.build());
+    vm.load_program(application.build());
+
+    let result = vm.run(Some(String::from("main")), None).unwrap();
+
+    assert_eq!(result.result, Some(Variant::Integer(16)));
+}
+
+#[test]
+fn test_load_program_with_out_of_range_index() {
+    let mut first = Program::builder();
+    first.add_function(FunctionBuilder::default()
+        .name("first")
+        .arity(0)
+        .body(BlockEncoder::default().push_integer(0).return_value())
+        .build()
+    );
+
+    let mut program = Program::builder();
+    program.add_function(FunctionBuilder::default()
+        .name("main")
+        .arity(0)
+        .body(BlockEncoder::default().end_function())
+        .build()
+    );
+    let mut program = program.build();
+    program.functions[0].instructions.insert(0, Instruction::FunctionCall(CallTarget::Index(usize::MAX)));
+
+    // Relocating the index must not overflow, the runtime rejects it instead
+    let mut vm = Vm::default();
+    vm.load_program(first.build());
+    vm.load_program(program);
+    let error = vm.run(None, None).unwrap_err();
+
+    assert_eq!(error.kind, VmErrorKind::InvalidFunctionIndex { index: usize::MAX });
+}
+
+#[test]
+fn test_duplicate_symbol() {
+    // Application that calls its own function by index and the library's square by name
+    let mut application = Program::builder();
+    application.declare_global("offset", Some(Variant::Integer(1)));
+    application.add_function(FunctionBuilder::default()
+        .name("main")
+        .arity(0)
+        .body(
+            BlockEncoder::default()
+                .push_integer(3)
+                .call_function_by_name("increment")
+                .call_function_by_name("square")
+                .return_value()
+        )
+        .build()
+    );
+    application.add_function(FunctionBuilder::default()
+        .name("increment")
+        .arity(1)
+        .body(
+            BlockEncoder::default()
+                .declare_local("value")
+                .get_local("value")
+                .get_global("offset")
+                .add()
+                .return_value()
+        )
+        .build()
+    );
+
+    let application = application.build();
+    let mut linker = Linker::default();
+    linker.add_program(application.clone()).unwrap();
+    let error = linker.add_program(application).unwrap_err();
+
+    assert!(matches!(error.kind, VmErrorKind::DuplicateSymbol { .. }));
+}
+
+#[test]
+fn test_unresolved_symbol() {
+    // Application that calls its own function by index and the library's square by name
+    let mut application = Program::builder();
+    application.declare_global("offset", Some(Variant::Integer(1)));
+    application.add_function(FunctionBuilder::default()
+        .name("main")
+        .arity(0)
+        .body(
+            BlockEncoder::default()
+                .push_integer(3)
+                .call_function_by_name("increment")
+                .call_function_by_name("square")
+                .return_value()
+        )
+        .build()
+    );
+    application.add_function(FunctionBuilder::default()
+        .name("increment")
+        .arity(1)
+        .body(
+            BlockEncoder::default()
+                .declare_local("value")
+                .get_local("value")
+                .get_global("offset")
+                .add()
+                .return_value()
+        )
+        .build()
+    );
+
+    let mut linker = Linker::default();
+    linker.add_program(application.build()).unwrap();
+    let error = linker.link().unwrap_err();
+
+    assert_eq!(error.kind, VmErrorKind::UnresolvedSymbol { name: String::from("square") });
+    assert_eq!(error.location(), Some(&StackTraceEntry { function: String::from("main"), function_index: 0, pc: 2 }));
+}
+
+#[test]
+fn test_declared_native_function_is_resolved() {
+    let mut program = Program::builder();
+    program.add_function(FunctionBuilder::default()
+        .name("main")
+        .arity(0)
+        .body(
+            BlockEncoder::default()
+                .push_integer(20)
+                .call_function_by_name("negate")
+                .return_value()
+        )
+        .build()
+    );
+
+    let mut linker = Linker::default();
+    linker.add_program(program.build()).unwrap();
+    linker.declare_native_function("negate", 1).unwrap();
+    let program = linker.link().unwrap();
+
+    let mut vm = Vm::default();
+    vm.load_program(program);
+    vm.register_fn("negate", |value: i64| -value);
+    let result = vm.run(None, None).unwrap();
+
+    assert_eq!(result.result, Some(Variant::Integer(-20)));
+}
@@ -0,0 +1,259 @@
+use bytevm::prelude::*;
+use std::collections::HashMap;
+
+fn program(functions: Vec<Function>) -> Program {
+    let mut symbol_table = HashMap::new();
+    for (index, function) in functions.iter().enumerate() {
+        symbol_table.insert(function.name.clone(), SymbolEntry::UserDefinedFunction { index });
+    }
+    Program {
+        symbol_table,
+        functions,
+        ..Default::default()
+    }
+}
+
+fn main_function(local_count: usize, instructions: Vec<Instruction>) -> Function {
+    Function {
+        name: String::from("main"),
+        arity: 0,
+        local_count,
+        instructions,
+        ..Default::default()
+    }
+}
+
+fn run(program: Program) -> Result<VmExecutionResult, VmError> {
+    let mut vm = Vm::default();
+    vm.load_program(program);
+    vm.run(None, None)
+}
+
+#[test]
+fn test_stack_underflow() {
+    let result = run(program(vec![main_function(0, vec![
+        Instruction::Push(Variant::Integer(1)),
+        Instruction::Add,
+        Instruction::Return
+    ])]));
+
+    let error = result.unwrap_err();
+    assert_eq!(error.kind, VmErrorKind::StackUnderflow);
+    assert_eq!(error.location().unwrap().function, "main");
+    assert_eq!(error.location().unwrap().pc, 1);
+}
+
+#[test]
+fn test_set_invalid_local() {
+    let result = run(program(vec![main_function(1, vec![
+        Instruction::Push(Variant::Integer(1)),
+        Instruction::SetLocal(5),
+        Instruction::Halt
+    ])]));
+
+    let error = result.unwrap_err();
+    assert_eq!(error.kind, VmErrorKind::InvalidLocal { index: 5 });
+    assert_eq!(error.location().unwrap().function, "main");
+    assert_eq!(error.location().unwrap().pc, 1);
+}
+
+#[test]
+fn test_set_array_item_out_of_bounds() {
+    let result = run(program(vec![main_function(0, vec![
+        Instruction::Push(Variant::Integer(1)),
+        Instruction::CreateArray(1),
+        Instruction::Push(Variant::Index(3)),
+        Instruction::Push(Variant::Integer(2)),
+        Instruction::SetArrayItem,
+        Instruction::Return
+    ])]));
+
+    let error = result.unwrap_err();
+    assert_eq!(error.kind, VmErrorKind::IndexOutOfBounds { index: 3, length: 1 });
+    assert_eq!(error.location().unwrap().function, "main");
+    assert_eq!(error.location().unwrap().pc, 4);
+}
+
+#[test]
+fn test_function_call_without_arguments() {
+    let result = run(program(vec![
+        main_function(0, vec![
+            Instruction::FunctionCall(CallTarget::Index(1)),
+            Instruction::Return
+        ]),
+        Function {
+            name: String::from("add"),
+            arity: 2,
+            local_count: 2,
+            instructions: vec![Instruction::GetLocal(0), Instruction::GetLocal(1), Instruction::Add, Instruction::Return],
+            ..Default::default()
+        }
+    ]));
+
+    let error = result.unwrap_err();
+    assert_eq!(error.kind, VmErrorKind::ArityMismatch { function: String::from("add"), expected: 2, actual: 0 });
+    assert_eq!(error.location().unwrap().function, "main");
+    assert_eq!(error.location().unwrap().pc, 0);
+}
+
+#[test]
+fn test_function_call_does_not_take_caller_locals() {
+    let result = run(program(vec![
+        main_function(2, vec![
+            Instruction::Push(Variant::Integer(1)),
+            Instruction::FunctionCall(CallTarget::Index(1)),
+            Instruction::Return
+        ]),
+        Function {
+            name: String::from("add"),
+            arity: 2,
+            local_count: 2,
+            instructions: vec![Instruction::GetLocal(0), Instruction::GetLocal(1), Instruction::Add, Instruction::Return],
+            ..Default::default()
+        }
+    ]));
+
+    let error = result.unwrap_err();
+    assert_eq!(error.kind, VmErrorKind::ArityMismatch { function: String::from("add"), expected: 2, actual: 1 });
+    assert_eq!(error.location().unwrap().pc, 1);
+}
+
+#[test]
+fn test_return_count_larger_than_stack() {
+    let result = run(program(vec![
+        main_function(0, vec![
+            Instruction::FunctionCall(CallTarget::Index(1)),
+            Instruction::Return
+        ]),
+        Function {
+            name: String::from("many"),
+            local_count: 1,
+            instructions: vec![Instruction::ReturnN(usize::MAX)],
+            ..Default::default()
+        }
+    ]));
+
+    let error = result.unwrap_err();
+    assert_eq!(error.kind, VmErrorKind::StackUnderflow);
+    assert_eq!(error.location().unwrap().function, "many");
+}
+
+#[test]
+fn test_callee_local_count_larger_than_stack() {
+    let calls = vec![
+        vec![Instruction::FunctionCall(CallTarget::Index(1)), Instruction::Return],
+        vec![Instruction::TailCall(CallTarget::Index(1))],
+        vec![Instruction::MakeCoroutine(0), Instruction::Return],
+    ];
+
+    for instructions in calls {
+        let result = run(program(vec![
+            main_function(0, vec![Instruction::Push(Variant::SymbolReference(String::from("huge")))].into_iter().chain(instructions).collect()),
+            Function {
+                name: String::from("huge"),
+                local_count: usize::MAX,
+                instructions: vec![Instruction::Push(Variant::Null), Instruction::Return],
+                ..Default::default()
+            }
+        ]));
+
+        let error = result.unwrap_err();
+        assert_eq!(error.kind, VmErrorKind::StackOverflow { limit: usize::MAX });
+        assert_eq!(error.location().unwrap().function, "main");
+    }
+}
+
+#[test]
+fn test_entry_function_local_count_larger_than_stack() {
+    let result = run(program(vec![main_function(usize::MAX, vec![Instruction::Halt])]));
+
+    let error = result.unwrap_err();
+    assert_eq!(error.kind, VmErrorKind::StackOverflow { limit: usize::MAX });
+}
+
+#[test]
+fn test_parameter_counts_do_not_overflow() {
+    let mut vm = Vm::default();
+    vm.load_program(program(vec![
+        Function {
+            name: String::from("optional"),
+            arity: 1,
+            optional_count: usize::MAX,
+            local_count: 1,
+            instructions: vec![Instruction::GetLocal(0), Instruction::Return],
+            ..Default::default()
+        },
+        Function {
+            name: String::from("rest"),
+            arity: 1,
+            optional_count: usize::MAX,
+            variadic: true,
+            local_count: 2,
+            instructions: vec![Instruction::GetLocal(0), Instruction::Return],
+            ..Default::default()
+        },
+        Function {
+            name: String::from("many"),
+            arity: usize::MAX,
+            optional_count: 1,
+            ..Default::default()
+        }
+    ]));
+
+    assert_eq!(vm.call("optional", vec![Variant::Integer(1), Variant::Integer(2)]), Ok(Some(Variant::Integer(1))));
+    assert_eq!(vm.call("rest", vec![Variant::Integer(3)]), Ok(Some(Variant::Integer(3))));
+
+    let error = vm.call("many", vec![]).unwrap_err();
+    assert_eq!(error.kind, VmErrorKind::ArityMismatch { function: String::from("many"), expected: usize::MAX, actual: 0 });
+}
+
+#[test]
+fn test_malformed_programs_do_not_panic() {
+    let programs = vec![
+        vec![Instruction::Pop],
+        vec![Instruction::GetLocal(0)],
+        vec![Instruction::SetLocal(0)],
+        vec![Instruction::GetGlobal(GlobalTarget::Slot(0))],
+        vec![Instruction::Push(Variant::Null), Instruction::SetGlobal(GlobalTarget::Name(String::from("missing")))],
+        vec![Instruction::Jump(100)],
+        vec![Instruction::JumpIfFalse(0)],
+        vec![Instruction::Pop, Instruction::Return],
+        vec![Instruction::Return],
+        vec![Instruction::ReturnN(usize::MAX)],
+        vec![Instruction::Panic],
+        vec![Instruction::Print],
+        vec![Instruction::Not],
+        vec![Instruction::Negate],
+        vec![Instruction::Push(Variant::Null), Instruction::Negate],
+        vec![Instruction::Push(Variant::Integer(2)), Instruction::Push(Variant::Integer(-1)), Instruction::Pow],
+        vec![Instruction::Push(Variant::Integer(i64::MAX)), Instruction::Push(Variant::Integer(1)), Instruction::Add],
+        vec![Instruction::CreateArray(usize::MAX)],
+        vec![Instruction::CreateDictionary(usize::MAX)],
+        vec![Instruction::GetArrayItem],
+        vec![Instruction::SetArrayItem],
+        vec![Instruction::GetArrayLength],
+        vec![Instruction::GetDictionaryItem],
+        vec![Instruction::SetDictionaryItem],
+        vec![Instruction::GetDictionaryKeys],
+        vec![Instruction::CreateDictionary(0), Instruction::SetLocal(0), Instruction::GetLocal(0), Instruction::GetLocal(0), Instruction::Push(Variant::Null), Instruction::SetDictionaryItem],
+        vec![Instruction::FunctionCall(CallTarget::Index(42))],
+        vec![Instruction::Push(Variant::Integer(1)), Instruction::CallIndirect(0)],
+        vec![Instruction::CallIndirect(0)],
+        vec![Instruction::FunctionCall(CallTarget::Name(String::from("missing")))],
+        vec![Instruction::TailCall(CallTarget::Index(42))],
+        vec![Instruction::Throw],
+        vec![Instruction::Resume],
+        vec![Instruction::Push(Variant::Null), Instruction::Resume],
+        vec![Instruction::Push(Variant::Null), Instruction::Yield],
+        vec![Instruction::MakeCoroutine(0)],
+        vec![Instruction::Push(Variant::SymbolReference(String::from("main"))), Instruction::MakeCoroutine(3)],
+        vec![Instruction::TryEnd],
+        vec![Instruction::TryBegin(100), Instruction::Push(Variant::Null), Instruction::Throw],
+        vec![],
+    ];
+
+    for instructions in programs {
+        let result = run(program(vec![main_function(1, instructions.clone())]));
+        assert!(result.is_err(), "Expected error for {:?}", instructions);
+    }
+}
@@ -313,4 +313,56 @@ fn test_not_equal() {
     let result = vm.run(None, None).unwrap().result.unwrap();
 
     assert_eq!(result, Variant::Boolean(true));
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_division_by_zero_is_error() {
+    let mut program = Program::builder();
+    program.add_function(FunctionBuilder::default()
+        .name("main")
+        .arity(0)
+        .body(
+            BlockEncoder::default()
+                .push_integer(1)
+                .push_integer(0)
+                .div()
+                .return_value()
+        )
+        .build()
+    );
+
+    let mut vm = Vm::default();
+    vm.load_program(program.build());
+    let result = vm.run(None, None);
+
+    let error = result.unwrap_err();
+    assert_eq!(error.kind, VmErrorKind::DivisionByZero);
+    assert_eq!(error.location().unwrap().function, "main");
+    assert_eq!(error.location().unwrap().pc, 2);
+}
+
+#[test]
+fn test_invalid_operands_is_error() {
+    let mut program = Program::builder();
+    program.add_function(FunctionBuilder::default()
+        .name("main")
+        .arity(0)
+        .body(
+            BlockEncoder::default()
+                .push_integer(1)
+                .push_string(String::from("a"))
+                .mul()
+                .return_value()
+        )
+        .build()
+    );
+
+    let mut vm = Vm::default();
+    vm.load_program(program.build());
+    let result = vm.run(None, None);
+
+    let error = result.unwrap_err();
+    assert_eq!(error.kind, VmErrorKind::OperatorTypeError { operator: "*", lhs: "integer", rhs: "string" });
+    assert_eq!(error.location().unwrap().function, "main");
+    assert_eq!(error.location().unwrap().pc, 2);
+}
@@ -0,0 +1,431 @@
+use bytevm::prelude::*;
+
+#[test]
+fn test_imported_and_qualified_calls() {
+    let mut app = Program::builder();
+    app.module("app").export("main").import("math.square");
+    app.add_function(FunctionBuilder::default()
+        .name("main")
+        .arity(0)
+        .body(
+            BlockEncoder::default()
+                .push_integer(3)
+                .call_function_by_name("square")
+                .push_integer(4)
+                .call_function_by_name("math::square")
+                .add()
+                .return_value()
+        )
+        .build()
+    );
+
+    let mut math = Program::builder();
+    math.module("math").export("square");
+    math.add_function(FunctionBuilder::default()
+        .name("square")
+        .arity(1)
+        .body(
+            BlockEncoder::default()
+                .declare_local("value")
+                .get_local("value")
+                .get_local("value")
+                .call_function_by_name("multiply")
+                .return_value()
+        )
+        .build()
+    );
+    math.add_function(FunctionBuilder::default()
+        .name("multiply")
+        .arity(2)
+        .body(
+            BlockEncoder::default()
+                .declare_local("a")
+                .declare_local("b")
+                .get_local("a")
+                .get_local("b")
+                .mul()
+                .return_value()
+        )
+        .build()
+    );
+
+    let mut vm = Vm::default();
+    vm.load_program(app.build());
+    vm.load_program(math.build());
+    let result = vm.run(Some(String::from("app::main")), None).unwrap();
+
+    assert_eq!(result.result, Some(Variant::Integer(25)));
+}
+
+#[test]
+fn test_private_function_is_not_an_entry_point() {
+    let mut math = Program::builder();
+    math.module("math").export("square");
+    math.add_function(FunctionBuilder::default()
+        .name("square")
+        .arity(1)
+        .body(
+            BlockEncoder::default()
+                .declare_local("value")
+                .get_local("value")
+                .get_local("value")
+                .call_function_by_name("multiply")
+                .return_value()
+        )
+        .build()
+    );
+    math.add_function(FunctionBuilder::default()
+        .name("multiply")
+        .arity(2)
+        .body(
+            BlockEncoder::default()
+                .declare_local("a")
+                .declare_local("b")
+                .get_local("a")
+                .get_local("b")
+                .mul()
+                .return_value()
+        )
+        .build()
+    );
+
+    let mut vm = Vm::default();
+    vm.load_program(math.build());
+
+    for name in ["multiply", "math::multiply", "square"] {
+        let error = vm.run(Some(String::from(name)), Some(vec![Variant::Integer(1), Variant::Integer(2)])).unwrap_err();
+        assert_eq!(error.kind, VmErrorKind::FunctionNotFound { name: String::from(name) });
+    }
+}
+
+#[test]
+fn test_private_function_is_hidden_from_other_modules() {
+    let mut math = Program::builder();
+    math.module("math").export("square");
+    math.add_function(FunctionBuilder::default()
+        .name("square")
+        .arity(1)
+        .body(
+            BlockEncoder::default()
+                .declare_local("value")
+                .get_local("value")
+                .get_local("value")
+                .call_function_by_name("multiply")
+                .return_value()
+        )
+        .build()
+    );
+    math.add_function(FunctionBuilder::default()
+        .name("multiply")
+        .arity(2)
+        .body(
+            BlockEncoder::default()
+                .declare_local("a")
+                .declare_local("b")
+                .get_local("a")
+                .get_local("b")
+                .mul()
+                .return_value()
+        )
+        .build()
+    );
+
+    let mut app = Program::builder();
+    app.module("app").export("main").import("math.square");
+    app.add_function(FunctionBuilder::default()
+        .name("main")
+        .arity(0)
+        .body(
+            BlockEncoder::default()
+                .push_integer(3)
+                .push_integer(4)
+                .call_function_by_name("math::multiply")
+                .return_value()
+        )
+        .build()
+    );
+
+    let mut vm = Vm::default();
+    vm.load_program(math.build());
+    vm.load_program(app.build());
+    let error = vm.run(Some(String::from("app::main")), None).unwrap_err();
+
+    assert_eq!(error.kind, VmErrorKind::FunctionNotFound { name: String::from("math::multiply") });
+    assert_eq!(error.location().unwrap().function, "app::main");
+}
+
+#[test]
+fn test_private_name_is_rejected_from_other_modules() {
+    let mut math = Program::builder();
+    math.module("math").export("square");
+    math.add_function(FunctionBuilder::default()
+        .name("square")
+        .arity(1)
+        .body(
+            BlockEncoder::default()
+                .declare_local("value")
+                .get_local("value")
+                .get_local("value")
+                .call_function_by_name("multiply")
+                .return_value()
+        )
+        .build()
+    );
+    math.add_function(FunctionBuilder::default()
+        .name("multiply")
+        .arity(2)
+        .body(
+            BlockEncoder::default()
+                .declare_local("a")
+                .declare_local("b")
+                .get_local("a")
+                .get_local("b")
+                .mul()
+                .return_value()
+        )
+        .build()
+    );
+
+    // The hidden name of the private function, called by name, through a reference and as a coroutine
+    let bodies = vec![
+        BlockEncoder::default()
+            .push_integer(3)
+            .push_integer(4)
+            .call_function_by_name("math::<multiply>")
+            .return_value()
+            .clone(),
+        BlockEncoder::default()
+            .push_integer(3)
+            .push_integer(4)
+            .push_symbol("math::<multiply>")
+            .call_indirect(2)
+            .return_value()
+            .clone(),
+        BlockEncoder::default()
+            .push_integer(3)
+            .push_integer(4)
+            .push_symbol("math::<multiply>")
+            .make_coroutine(2)
+            .return_value()
+            .clone(),
+    ];
+
+    for mut body in bodies {
+        let mut app = Program::builder();
+        app.module("app").export("main");
+        app.add_function(FunctionBuilder::default()
+            .name("main")
+            .arity(0)
+            .body(&mut body)
+            .build()
+        );
+
+        let mut vm = Vm::default();
+        vm.load_program(math.clone().build());
+        vm.load_program(app.build());
+        let error = vm.run(Some(String::from("app::main")), None).unwrap_err();
+
+        assert_eq!(error.kind, VmErrorKind::FunctionNotFound { name: String::from("math::<multiply>") });
+        assert_eq!(error.location().unwrap().function, "app::main");
+    }
+}
+
+#[test]
+fn test_private_function_reference() {
+    let mut math = Program::builder();
+    math.module("math").export("twice");
+
+    // Calls the private helper through a reference and as a coroutine
+    math.add_function(FunctionBuilder::default()
+        .name("twice")
+        .arity(1)
+        .body(
+            BlockEncoder::default()
+                .declare_local("value")
+                .get_local("value")
+                .push_function_reference("double")
+                .call_indirect(1)
+                .push_function_reference("double")
+                .make_coroutine(1)
+                .resume()
+                .return_value()
+        )
+        .build()
+    );
+    math.add_function(FunctionBuilder::default()
+        .name("double")
+        .arity(1)
+        .body(
+            BlockEncoder::default()
+                .declare_local("value")
+                .get_local("value")
+                .push_integer(2)
+                .mul()
+                .return_value()
+        )
+        .build()
+    );
+
+    let mut vm = Vm::default();
+    vm.load_program(math.build());
+    let result = vm.run(Some(String::from("math::twice")), Some(vec![Variant::Integer(5)])).unwrap();
+
+    assert_eq!(result.result, Some(Variant::Integer(20)));
+    assert_eq!(vm.resolve_function("math::double"), None);
+}
+
+#[test]
+fn test_link_modules() {
+    let mut math = Program::builder();
+    math.module("math").export("square");
+    math.add_function(FunctionBuilder::default()
+        .name("square")
+        .arity(1)
+        .body(
+            BlockEncoder::default()
+                .declare_local("value")
+                .get_local("value")
+                .get_local("value")
+                .call_function_by_name("multiply")
+                .return_value()
+        )
+        .build()
+    );
+    math.add_function(FunctionBuilder::default()
+        .name("multiply")
+        .arity(2)
+        .body(
+            BlockEncoder::default()
+                .declare_local("a")
+                .declare_local("b")
+                .get_local("a")
+                .get_local("b")
+                .mul()
+                .return_value()
+        )
+        .build()
+    );
+
+    let mut app = Program::builder();
+    app.module("app").export("main").import("math.square");
+    app.add_function(FunctionBuilder::default()
+        .name("main")
+        .arity(0)
+        .body(
+            BlockEncoder::default()
+                .push_integer(5)
+                .call_function_by_name("square")
+                .return_value()
+        )
+        .build()
+    );
+
+    let mut linker = Linker::default();
+    linker.add_program(math.build()).unwrap();
+    linker.add_program(app.build()).unwrap();
+    let program = linker.link().unwrap();
+
+    let mut vm = Vm::default();
+    vm.load_program(program);
+    let result = vm.run(Some(String::from("app::main")), None).unwrap();
+
+    assert_eq!(result.result, Some(Variant::Integer(25)));
+}
+
+#[test]
+fn test_link_reports_import_of_private_function() {
+    let mut math = Program::builder();
+    math.module("math").export("square");
+    math.add_function(FunctionBuilder::default()
+        .name("square")
+        .arity(1)
+        .body(
+            BlockEncoder::default()
+                .declare_local("value")
+                .get_local("value")
+                .get_local("value")
+                .call_function_by_name("multiply")
+                .return_value()
+        )
+        .build()
+    );
+    math.add_function(FunctionBuilder::default()
+        .name("multiply")
+        .arity(2)
+        .body(
+            BlockEncoder::default()
+                .declare_local("a")
+                .declare_local("b")
+                .get_local("a")
+                .get_local("b")
+                .mul()
+                .return_value()
+        )
+        .build()
+    );
+
+    let mut program = Program::builder();
+    program.import("math.multiply");
+    program.add_function(FunctionBuilder::default()
+        .name("main")
+        .arity(0)
+        .body(
+            BlockEncoder::default()
+                .push_integer(1)
+                .push_integer(2)
+                .call_function_by_name("multiply")
+                .return_value()
+        )
+        .build()
+    );
+
+    let mut linker = Linker::default();
+    linker.add_program(math.build()).unwrap();
+    linker.add_program(program.build()).unwrap();
+    let error = linker.link().unwrap_err();
+
+    assert_eq!(error.kind, VmErrorKind::UnresolvedSymbol { name: String::from("math::multiply") });
+}
+
+#[test]
+fn test_export_unknown_function() {
+    let mut program = Program::builder();
+    program.module("math").export("sqrt");
+
+    let error = program.try_build().unwrap_err();
+
+    assert_eq!(error.kind, VmErrorKind::UnresolvedSymbol { name: String::from("sqrt") });
+}
+
+#[test]
+fn test_import_conflicts_with_function() {
+    let mut program = Program::builder();
+    program.import("math.square");
+    program.add_function(FunctionBuilder::default()
+        .name("square")
+        .arity(0)
+        .body(BlockEncoder::default().push_integer(0).return_value())
+        .build()
+    );
+
+    let error = program.try_build().unwrap_err();
+
+    assert_eq!(error.kind, VmErrorKind::DuplicateSymbol { name: String::from("square") });
+}
+
+#[test]
+fn test_invalid_import_path() {
+    let mut program = Program::builder();
+    program.import("sqrt");
+
+    let error = program.try_build().unwrap_err();
+
+    assert_eq!(error.kind, VmErrorKind::UnresolvedSymbol { name: String::from("sqrt") });
+}
+
+#[test]
+fn test_parse_import() {
+    assert_eq!(Import::parse("math.sqrt"), Some(Import { module: String::from("math"), name: String::from("sqrt") }));
+    assert_eq!(Import::parse("sqrt"), None);
+    assert_eq!(Import::parse("math."), None);
+}
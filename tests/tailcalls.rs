@@ -0,0 +1,215 @@
+use bytevm::prelude::*;
+
+#[test]
+fn test_tail_call_reuses_frame() {
+    let mut program = Program::builder();
+
+    // Sums the numbers from n down to 1 with an accumulator, calling itself in tail position
+    program.add_function(FunctionBuilder::default()
+        .name("sum")
+        .arity(2)
+        .body(
+            BlockEncoder::default()
+                .declare_local("n")
+                .declare_local("total")
+                .get_local("n")
+                .push_integer(0)
+                .greater_than()
+                .jump_if_false("done")
+                .get_local("n")
+                .push_integer(1)
+                .sub()
+                .get_local("total")
+                .get_local("n")
+                .add()
+                .tail_call_function_by_name("sum")
+                .add_label("done")
+                .get_local("total")
+                .return_value()
+        )
+        .build()
+    );
+
+    let mut vm = Vm::default();
+    vm.set_limits(VmLimits {
+        max_call_depth: Some(16),
+        ..Default::default()
+    });
+    vm.load_program(program.build());
+    let result = vm.run(Some(String::from("sum")), Some(vec![Variant::Integer(100_000), Variant::Integer(0)])).unwrap();
+
+    assert_eq!(result.result, Some(Variant::Integer(5_000_050_000)));
+}
+
+#[test]
+fn test_plain_recursion_exceeds_call_depth() {
+    let mut program = Program::builder();
+
+    // Sums the numbers from n down to 1 with an accumulator, calling itself in tail position
+    program.add_function(FunctionBuilder::default()
+        .name("sum")
+        .arity(2)
+        .body(
+            BlockEncoder::default()
+                .declare_local("n")
+                .declare_local("total")
+                .get_local("n")
+                .push_integer(0)
+                .greater_than()
+                .jump_if_false("done")
+                .get_local("n")
+                .push_integer(1)
+                .sub()
+                .get_local("total")
+                .get_local("n")
+                .add()
+                .call_function_by_name("sum")
+                .return_value()
+                .add_label("done")
+                .get_local("total")
+                .return_value()
+        )
+        .build()
+    );
+
+    let mut vm = Vm::default();
+    vm.set_limits(VmLimits {
+        max_call_depth: Some(16),
+        ..Default::default()
+    });
+    vm.load_program(program.build());
+    let error = vm.run(Some(String::from("sum")), Some(vec![Variant::Integer(100_000), Variant::Integer(0)])).unwrap_err();
+
+    assert_eq!(error.kind, VmErrorKind::CallDepthExceeded { limit: 16 });
+}
+
+#[test]
+fn test_optimized_recursion_reuses_frame() {
+    let mut program = Program::builder();
+    program.optimize_tail_calls(true);
+
+    // Sums the numbers from n down to 1 with an accumulator, calling itself in tail position
+    program.add_function(FunctionBuilder::default()
+        .name("sum")
+        .arity(2)
+        .body(
+            BlockEncoder::default()
+                .declare_local("n")
+                .declare_local("total")
+                .get_local("n")
+                .push_integer(0)
+                .greater_than()
+                .jump_if_false("done")
+                .get_local("n")
+                .push_integer(1)
+                .sub()
+                .get_local("total")
+                .get_local("n")
+                .add()
+                .call_function_by_name("sum")
+                .return_value()
+                .add_label("done")
+                .get_local("total")
+                .return_value()
+        )
+        .build()
+    );
+
+    let mut vm = Vm::default();
+    vm.set_limits(VmLimits {
+        max_call_depth: Some(16),
+        ..Default::default()
+    });
+    vm.load_program(program.build());
+    let result = vm.run(Some(String::from("sum")), Some(vec![Variant::Integer(100_000), Variant::Integer(0)])).unwrap();
+
+    assert_eq!(result.result, Some(Variant::Integer(5_000_050_000)));
+}
+
+#[test]
+fn test_tail_call_native_function() {
+    let mut program = Program::builder();
+    program.add_function(FunctionBuilder::default()
+        .name("main")
+        .arity(0)
+        .body(
+            BlockEncoder::default()
+                .call_function_by_name("twice")
+                .push_integer(1)
+                .add()
+                .return_value()
+        )
+        .build()
+    );
+    program.add_function(FunctionBuilder::default()
+        .name("twice")
+        .arity(0)
+        .body(
+            BlockEncoder::default()
+                .declare_local("unused")
+                .push_integer(21)
+                .tail_call_function_by_name("double")
+        )
+        .build()
+    );
+
+    let mut vm = Vm::default();
+    vm.load_program(program.build());
+    vm.register_native_function(String::from("double"), 1, |_, args| {
+        match &args[0] {
+            Variant::Integer(value) => Ok(Some(Variant::Integer(value * 2))),
+            _ => Ok(None)
+        }
+    });
+
+    let result = vm.run(None, None).unwrap();
+
+    assert_eq!(result.result, Some(Variant::Integer(43)));
+}
+
+#[test]
+fn test_optimized_call_inside_try_is_caught() {
+    let mut program = Program::builder();
+    program.optimize_tail_calls(true);
+    program.add_function(FunctionBuilder::default()
+        .name("main")
+        .arity(0)
+        .body(
+            BlockEncoder::default()
+                .try_begin("catch")
+                .push_integer(0)
+                .call_function_by_name("boom")
+                .return_value()
+                .add_label("catch")
+                .return_value()
+        )
+        .build()
+    );
+    program.add_function(FunctionBuilder::default()
+        .name("boom")
+        .arity(1)
+        .body(
+            BlockEncoder::default()
+                .declare_local("value")
+                .get_local("value")
+                .jump_if_false("throw")
+                .get_local("value")
+                .return_value()
+                .add_label("throw")
+                .push_integer(7)
+                .throw()
+        )
+        .build()
+    );
+
+    let program = program.build();
+
+    // The handler belongs to main's frame, so the call must keep it
+    assert_eq!(program.functions[0].instructions[2], Instruction::FunctionCall(CallTarget::Index(1)));
+
+    let mut vm = Vm::default();
+    vm.load_program(program);
+    let result = vm.run(None, None).unwrap();
+
+    assert_eq!(result.result, Some(Variant::Integer(7)));
+}
@@ -0,0 +1,286 @@
+use bytevm::prelude::*;
+
+#[test]
+fn test_valid_program_passes_verification() {
+    let mut program = Program::builder();
+    program.verify_stack_depth(true);
+    program.add_function(FunctionBuilder::default()
+        .name("main")
+        .arity(0)
+        .body(
+            BlockEncoder::default()
+                .declare_local("i")
+                .push_integer(0)
+                .set_local("i")
+                .add_label("start")
+                .get_local("i")
+                .push_integer(10)
+                .less_than()
+                .jump_if_false("end")
+                .push_integer(100)
+                .get_local("i")
+                .push_integer(1)
+                .call_function_by_name("add")
+                .set_local("i")
+                .pop()
+                .jump("start")
+                .add_label("end")
+                .get_local("i")
+                .call_function_by_name("native")
+                .return_value()
+        )
+        .build()
+    );
+    program.add_function(FunctionBuilder::default()
+        .name("add")
+        .arity(2)
+        .body(
+            BlockEncoder::default()
+                .declare_local("a")
+                .declare_local("b")
+                .get_local("a")
+                .get_local("b")
+                .add()
+                .return_value()
+        )
+        .build()
+    );
+
+    let program = program.try_build().unwrap();
+
+    let mut vm = Vm::default();
+    vm.load_program(program);
+    vm.register_fn("native", |x: i64| x);
+    let result = vm.run(None, None).unwrap().result.unwrap();
+
+    assert_eq!(result, Variant::Integer(10));
+}
+
+#[test]
+fn test_call_with_missing_argument() {
+    let mut program = Program::builder();
+    program.verify_stack_depth(true);
+    program.add_function(FunctionBuilder::default()
+        .name("main")
+        .arity(0)
+        .body(
+            BlockEncoder::default()
+                .push_integer(1)
+                .call_function_by_name("add")
+                .return_value()
+        )
+        .build()
+    );
+    program.add_function(FunctionBuilder::default()
+        .name("add")
+        .arity(2)
+        .body(
+            BlockEncoder::default()
+                .declare_local("a")
+                .declare_local("b")
+                .get_local("a")
+                .get_local("b")
+                .add()
+                .return_value()
+        )
+        .build()
+    );
+
+    let error = program.try_build().unwrap_err();
+
+    assert_eq!(error.kind, VmErrorKind::ArityMismatch { function: String::from("add"), expected: 2, actual: 1 });
+    assert_eq!(error.location().unwrap().function, "main");
+    assert_eq!(error.location().unwrap().pc, 1);
+}
+
+#[test]
+fn test_locals_are_not_operands() {
+    let mut program = Program::builder();
+    program.verify_stack_depth(true);
+    program.add_function(FunctionBuilder::default()
+        .name("main")
+        .arity(0)
+        .body(
+            BlockEncoder::default()
+                .declare_local("a")
+                .declare_local("b")
+                .call_function_by_name("add")
+                .return_value()
+        )
+        .build()
+    );
+    program.add_function(FunctionBuilder::default()
+        .name("add")
+        .arity(2)
+        .body(
+            BlockEncoder::default()
+                .declare_local("a")
+                .declare_local("b")
+                .get_local("a")
+                .get_local("b")
+                .add()
+                .return_value()
+        )
+        .build()
+    );
+
+    let error = program.try_build().unwrap_err();
+
+    assert_eq!(error.kind, VmErrorKind::ArityMismatch { function: String::from("add"), expected: 2, actual: 0 });
+}
+
+#[test]
+fn test_branches_with_different_depths() {
+    let mut program = Program::builder();
+    program.verify_stack_depth(true);
+    program.add_function(FunctionBuilder::default()
+        .name("main")
+        .arity(0)
+        .body(
+            BlockEncoder::default()
+                .push_boolean(true)
+                .jump_if_false("end")
+                .push_integer(1)
+                .add_label("end")
+                .push_integer(2)
+                .return_value()
+        )
+        .build()
+    );
+
+    let error = program.try_build().unwrap_err();
+
+    assert_eq!(error.kind, VmErrorKind::InconsistentStackDepth { expected: 1, actual: 0 });
+    assert_eq!(error.location().unwrap().pc, 3);
+}
+
+#[test]
+fn test_operator_underflow() {
+    let mut program = Program::builder();
+    program.verify_stack_depth(true);
+    program.add_function(FunctionBuilder::default()
+        .name("main")
+        .arity(0)
+        .body(
+            BlockEncoder::default()
+                .push_integer(1)
+                .add()
+                .return_value()
+        )
+        .build()
+    );
+
+    let error = program.try_build().unwrap_err();
+
+    assert_eq!(error.kind, VmErrorKind::StackUnderflow);
+    assert_eq!(error.location().unwrap().pc, 1);
+}
+
+#[test]
+fn test_verification_is_disabled_by_default() {
+    let mut program = Program::builder();
+    program.add_function(FunctionBuilder::default()
+        .name("main")
+        .arity(0)
+        .body(BlockEncoder::default().add().return_value())
+        .build()
+    );
+
+    assert!(program.try_build().is_ok());
+}
+
+#[test]
+fn test_declared_return_count() {
+    let mut program = Program::builder();
+    program.verify_stack_depth(true);
+    program.add_function(FunctionBuilder::default()
+        .name("main")
+        .arity(0)
+        .returns(2)
+        .body(
+            BlockEncoder::default()
+                .push_integer(1)
+                .push_integer(2)
+                .return_value()
+        )
+        .build()
+    );
+
+    let error = program.try_build().unwrap_err();
+    assert_eq!(error.kind, VmErrorKind::ReturnCountMismatch { function: String::from("main"), expected: 2, actual: 1 });
+    assert_eq!(error.location().unwrap().pc, 2);
+}
+
+#[test]
+fn test_multiple_results_count_as_operands() {
+    let mut program = Program::builder();
+    program.verify_stack_depth(true);
+    program.add_function(FunctionBuilder::default()
+        .name("main")
+        .arity(0)
+        .body(
+            BlockEncoder::default()
+                .call_function_by_name("pair")
+                .call_function_by_name("add")
+                .return_value()
+        )
+        .build()
+    );
+    program.add_function(FunctionBuilder::default()
+        .name("pair")
+        .arity(0)
+        .body(
+            BlockEncoder::default()
+                .push_integer(1)
+                .push_integer(2)
+                .return_values(2)
+        )
+        .build()
+    );
+    program.add_function(FunctionBuilder::default()
+        .name("add")
+        .arity(2)
+        .body(
+            BlockEncoder::default()
+                .declare_local("a")
+                .declare_local("b")
+                .get_local("a")
+                .get_local("b")
+                .add()
+                .return_value()
+        )
+        .build()
+    );
+
+    assert!(program.try_build().is_ok());
+}
+
+#[test]
+fn test_result_count_larger_than_stack() {
+    let mut program = Program::builder();
+    program.verify_stack_depth(true);
+    program.add_function(FunctionBuilder::default()
+        .name("main")
+        .arity(0)
+        .body(
+            BlockEncoder::default()
+                .push_integer(1)
+                .call_function_by_name("many")
+                .return_value()
+        )
+        .build()
+    );
+    program.add_function(FunctionBuilder::default()
+        .name("many")
+        .arity(0)
+        .body(
+            BlockEncoder::default()
+                .return_values(usize::MAX)
+        )
+        .build()
+    );
+
+    let error = program.try_build().unwrap_err();
+    assert_eq!(error.kind, VmErrorKind::StackUnderflow);
+    assert_eq!(error.location().unwrap().function, "many");
+}